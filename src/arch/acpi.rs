@@ -11,6 +11,7 @@ use limine::request::RsdpRequest;
 use x86_64::{PhysAddr, VirtAddr};
 
 use crate::memory::{convert_physical_to_virtual, convert_virtual_to_physical};
+use crate::InitError;
 
 pub static ACPI: OnceCell<Acpi> = OnceCell::uninit();
 
@@ -45,36 +46,51 @@ pub struct Acpi<'a> {
     pub mcfg_info: Vec<McfgEntry>,
 }
 
-pub fn init() {
-    let rsdp_response = RSDP_REQUEST.get_response().unwrap();
+pub fn init() -> Result<(), InitError> {
+    let rsdp_response = RSDP_REQUEST.get_response().ok_or(InitError::NoRsdp)?;
 
     let acpi_tables = unsafe {
         let rsdp_addr = VirtAddr::new(rsdp_response.address() as u64);
         let tables = AcpiTables::from_rsdp(
             AcpiMemHandler,
             convert_virtual_to_physical(rsdp_addr).as_u64() as usize,
-        );
-        Box::leak(Box::new(tables.unwrap()))
+        )
+        .map_err(|err| {
+            log::error!("Failed to parse ACPI tables: {:?}", err);
+            InitError::NoAcpi
+        })?;
+        Box::leak(Box::new(tables))
     };
 
     log::info!("Find ACPI tables successfully!");
 
-    let platform_info = acpi_tables
-        .platform_info()
-        .expect("Failed to get platform info!");
+    let platform_info = acpi_tables.platform_info().map_err(|err| {
+        log::error!("Failed to get platform info: {:?}", err);
+        InitError::NoApic
+    })?;
 
     let apic_info = match platform_info.interrupt_model {
-        InterruptModel::Unknown => panic!("No APIC support, cannot continue!"),
         InterruptModel::Apic(apic) => apic,
-        _ => panic!("ACPI does not have interrupt model info!"),
+        _ => return Err(InitError::NoApic),
     };
 
-    let hpet_info = HpetInfo::new(acpi_tables).expect("Failed to get HPET info!");
+    for source_override in apic_info.interrupt_source_overrides.iter() {
+        log::debug!(
+            "Interrupt source override: IRQ {} -> GSI {}, {:?}, {:?}",
+            source_override.isa_source,
+            source_override.global_system_interrupt,
+            source_override.polarity,
+            source_override.trigger_mode
+        );
+    }
+
+    let hpet_info = HpetInfo::new(acpi_tables).map_err(|_| InitError::NoHpet)?;
 
+    // Without an MCFG the PCI driver finds no segment, which isn't fatal.
     let mut mcfg_info = Vec::new();
-    let mcfg = acpi_tables.find_table::<Mcfg>().expect("Cannot get MCFG");
-    for entry in mcfg.entries() {
-        mcfg_info.push(*entry);
+    match acpi_tables.find_table::<Mcfg>() {
+        Ok(mcfg) => mcfg_info.extend(mcfg.entries().iter().copied()),
+        Err(err) => log::warn!("Cannot get MCFG: {:?}", err),
     }
 
     ACPI.init_once(|| Acpi {
@@ -82,4 +98,5 @@ pub fn init() {
         hpet_info,
         mcfg_info,
     });
+    Ok(())
 }
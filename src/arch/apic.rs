@@ -1,7 +1,11 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
 use conquer_once::spin::OnceCell;
 use spin::Mutex;
-use x2apic::ioapic::{IoApic, IrqMode, RedirectionTableEntry};
-use x2apic::lapic::{LocalApic, LocalApicBuilder, TimerMode};
+use acpi::platform::interrupt::{Polarity, TriggerMode};
+use x2apic::ioapic::{IoApic, IrqFlags, IrqMode, RedirectionTableEntry};
+use x2apic::lapic::{ErrorFlags, LocalApic, LocalApicBuilder, TimerMode};
+use x86_64::registers::model_specific::Msr;
 use x86_64::VirtAddr;
 use x86_64::{instructions::port::Port, PhysAddr};
 
@@ -9,16 +13,53 @@ use super::interrupts::InterruptIndex;
 use crate::drivers::hpet::HPET;
 use crate::memory::convert_physical_to_virtual;
 
-const TIMER_FREQUENCY_HZ: u32 = 200;
-const TIMER_CALIBRATION_ITERATION: u32 = 100;
+/// The default frequency of the LAPIC timer interrupt, and so of `arch::ticks`.
+pub const DEFAULT_TIMER_FREQUENCY_HZ: u32 = 200;
+/// The highest frequency accepted by `set_tick_hz`.
+pub const MAX_TIMER_FREQUENCY_HZ: u32 = 10_000;
+const TIMER_CALIBRATION_ITERATION: usize = 100;
+const TIMER_CALIBRATION_ATTEMPTS: u32 = 3;
+/// The plausible LAPIC timer rates, 1MHz to 10GHz.
+const MIN_LAPIC_TICKS_PER_MS: u32 = 1_000;
+const MAX_LAPIC_TICKS_PER_MS: u32 = 10_000_000;
+/// Assumed when the calibration fails, high enough that the timer rather ticks too slowly.
+const FALLBACK_LAPIC_TICKS_PER_MS: u32 = 1_000_000;
 const IOAPIC_INTERRUPT_INDEX_OFFSET: u8 = 32;
 
-pub static IOAPIC: OnceCell<Mutex<IoApic>> = OnceCell::uninit();
+const IA32_APIC_BASE: u32 = 0x1b;
+const X2APIC_ENABLE: u64 = 1 << 10;
+const X2APIC_ISR_BASE: u32 = 0x810;
+const XAPIC_ISR_OFFSET: u64 = 0x100;
+
+/// The requested timer frequency, read by `calibrate_timer`.
+static TICK_HZ: AtomicU32 = AtomicU32::new(DEFAULT_TIMER_FREQUENCY_HZ);
+/// The LAPIC ticks per millisecond measured by the last calibration, 0 before it.
+static LAPIC_TICKS_PER_MS: AtomicU32 = AtomicU32::new(0);
+/// The initial count programmed by the last calibration, 0 before it.
+static TIMER_INITIAL_COUNT: AtomicU32 = AtomicU32::new(0);
+
+pub static IOAPICS: OnceCell<Vec<IoApicInfo>> = OnceCell::uninit();
+
+/// An IOAPIC and the range of GSIs it handles.
+pub struct IoApicInfo {
+    pub ioapic: Mutex<IoApic>,
+    pub gsi_base: u32,
+    pub entry_count: u32,
+}
+
+impl IoApicInfo {
+    /// Returns whether the GSI is handled by this IOAPIC.
+    pub fn contains(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entry_count).contains(&gsi)
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum IrqVector {
     Keyboard = 1,
+    Serial = 4,
+    Rtc = 8,
     Mouse = 12,
 }
 
@@ -44,8 +85,8 @@ fn get_lapic_addr() -> VirtAddr {
     virtual_address
 }
 
-/// Gets the local APIC.
-pub fn get_lapic() -> LocalApic {
+/// Builds the local APIC, fails if the CPU has no APIC.
+pub fn build_lapic() -> Result<LocalApic, &'static str> {
     LocalApicBuilder::new()
         .timer_vector(InterruptIndex::Timer as usize)
         .timer_mode(TimerMode::OneShot)
@@ -54,23 +95,32 @@ pub fn get_lapic() -> LocalApic {
         .spurious_vector(InterruptIndex::ApicSpurious as usize)
         .set_xapic_base(get_lapic_addr().as_u64())
         .build()
-        .unwrap_or_else(|err| panic!("Failed to build local APIC: {:#?}", err))
+}
+
+/// Gets the local APIC.
+pub fn get_lapic() -> LocalApic {
+    build_lapic().unwrap_or_else(|err| panic!("Failed to build local APIC: {:#?}", err))
+}
+
+/// Returns whether the interrupt vector is in service on the local APIC of the current CPU.
+/// A vector raised with the `int` instruction is never in service.
+pub fn is_in_service(vector: u8) -> bool {
+    let index = vector as u32 / 32;
+    let bit = vector as u32 % 32;
+    let isr = unsafe {
+        if Msr::new(IA32_APIC_BASE).read() & X2APIC_ENABLE != 0 {
+            Msr::new(X2APIC_ISR_BASE + index).read() as u32
+        } else {
+            let address = get_lapic_addr() + XAPIC_ISR_OFFSET + index as u64 * 0x10;
+            core::ptr::read_volatile(address.as_ptr::<u32>())
+        }
+    };
+    isr & (1 << bit) != 0
 }
 
 /// Returns the local APIC ID of the current CPU.
 pub fn get_lapic_id() -> u32 {
-    unsafe {
-        LocalApicBuilder::new()
-            .timer_vector(InterruptIndex::Timer as usize)
-            .timer_mode(TimerMode::OneShot)
-            .timer_initial(0)
-            .error_vector(InterruptIndex::ApicError as usize)
-            .spurious_vector(InterruptIndex::ApicSpurious as usize)
-            .set_xapic_base(get_lapic_addr().as_u64())
-            .build()
-            .unwrap_or_else(|err| panic!("Failed to build local APIC: {:#?}", err))
-            .id()
-    }
+    unsafe { get_lapic().id() }
 }
 
 unsafe fn disable_pic() {
@@ -80,42 +130,286 @@ unsafe fn disable_pic() {
 
 unsafe fn init_ioapic() {
     let acpi = super::acpi::ACPI.try_get().unwrap();
-    let physical_address = PhysAddr::new(acpi.apic_info.io_apics[0].address as u64);
-    let virtual_address = convert_physical_to_virtual(physical_address);
+    let mut ioapics = Vec::new();
+
+    for ioapic_info in acpi.apic_info.io_apics.iter() {
+        let physical_address = PhysAddr::new(ioapic_info.address as u64);
+        let virtual_address = convert_physical_to_virtual(physical_address);
+
+        let mut ioapic = IoApic::new(virtual_address.as_u64());
+        ioapic.init(IOAPIC_INTERRUPT_INDEX_OFFSET);
+        let entry_count = ioapic.max_table_entry() as u32 + 1;
 
-    let mut ioapic = IoApic::new(virtual_address.as_u64());
-    ioapic.init(IOAPIC_INTERRUPT_INDEX_OFFSET);
-    IOAPIC.init_once(|| Mutex::new(ioapic));
+        log::debug!(
+            "IOAPIC {}: GSI {}..{}",
+            ioapic_info.id,
+            ioapic_info.global_system_interrupt_base,
+            ioapic_info.global_system_interrupt_base + entry_count
+        );
+
+        ioapics.push(IoApicInfo {
+            ioapic: Mutex::new(ioapic),
+            gsi_base: ioapic_info.global_system_interrupt_base,
+            entry_count,
+        });
+    }
+
+    IOAPICS.init_once(|| ioapics);
 
     ioapic_add_entry(IrqVector::Keyboard, InterruptIndex::Keyboard);
-    ioapic_add_entry(IrqVector::Mouse, InterruptIndex::Mouse);
 }
 
-unsafe fn ioapic_add_entry(irq: IrqVector, vector: InterruptIndex) {
-    let lapic = get_lapic();
-    let mut ioapic = IOAPIC.try_get().unwrap().lock();
+fn ioapic_add_entry(irq: IrqVector, vector: InterruptIndex) {
+    route_irq(irq as u8, vector as u8, get_lapic_id() as u8);
+}
+
+/// Routes the ISA IRQ `irq` to `vector` on the local APIC `dest_lapic`.
+/// The IRQ is translated to its GSI, polarity and trigger mode with the interrupt source overrides from the MADT.
+pub fn route_irq(irq: u8, vector: u8, dest_lapic: u8) {
+    let (gsi, flags) = irq_to_gsi(irq);
+    set_gsi_entry(gsi, vector, dest_lapic, flags);
+}
+
+/// Routes the GSI `gsi` to `vector` on the local APIC `dest_lapic`.
+pub fn route_gsi(gsi: u32, vector: u8, dest_lapic: u8) {
+    set_gsi_entry(gsi, vector, dest_lapic, IrqFlags::empty());
+}
+
+fn set_gsi_entry(gsi: u32, vector: u8, dest_lapic: u8, flags: IrqFlags) {
+    let ioapics = IOAPICS.try_get().unwrap();
+    let Some(ioapic_info) = ioapics.iter().find(|ioapic_info| ioapic_info.contains(gsi)) else {
+        log::error!("No IOAPIC handles GSI {}!", gsi);
+        return;
+    };
+    let index = (gsi - ioapic_info.gsi_base) as u8;
+
     let mut entry = RedirectionTableEntry::default();
     entry.set_mode(IrqMode::Fixed);
-    entry.set_dest(lapic.id() as u8);
-    entry.set_vector(vector as u8);
-    ioapic.set_table_entry(irq as u8, entry);
-    ioapic.enable_irq(irq as u8);
+    entry.set_flags(flags);
+    entry.set_dest(dest_lapic);
+    entry.set_vector(vector);
+
+    let mut ioapic = ioapic_info.ioapic.lock();
+    unsafe {
+        ioapic.set_table_entry(index, entry);
+        ioapic.enable_irq(index);
+    }
+}
+
+/// A redirection table entry of an IOAPIC, read back for diagnostics.
+#[derive(Debug, Clone, Copy)]
+pub struct RedirectionEntryInfo {
+    pub ioapic_id: u8,
+    pub gsi: u32,
+    pub vector: u8,
+    pub dest: u8,
+    /// The polarity, trigger mode, mask and delivery status bits.
+    pub flags: IrqFlags,
+}
+
+impl RedirectionEntryInfo {
+    pub fn masked(&self) -> bool {
+        self.flags.contains(IrqFlags::MASKED)
+    }
+}
+
+/// The state of the local APIC of the current CPU, read back for diagnostics.
+#[derive(Debug, Clone, Copy)]
+pub struct LapicState {
+    pub id: u32,
+    pub version: u8,
+    pub x2apic: bool,
+    pub max_lvt_entry: u8,
+    /// The initial count programmed by the last calibration, 0 before it.
+    pub timer_initial: u32,
+    pub timer_current: u32,
+    pub error_flags: ErrorFlags,
+}
+
+/// Reads every redirection table entry of every IOAPIC, the masked ones included.
+pub fn dump_ioapic() -> Vec<RedirectionEntryInfo> {
+    let mut entries = Vec::new();
+    let Some(ioapics) = IOAPICS.get() else {
+        return entries;
+    };
+
+    for ioapic_info in ioapics {
+        let mut ioapic = ioapic_info.ioapic.lock();
+        let ioapic_id = unsafe { ioapic.id() };
+        for index in 0..ioapic_info.entry_count {
+            let entry = unsafe { ioapic.table_entry(index as u8) };
+            entries.push(RedirectionEntryInfo {
+                ioapic_id,
+                gsi: ioapic_info.gsi_base + index,
+                vector: entry.vector(),
+                dest: entry.dest(),
+                flags: entry.flags(),
+            });
+        }
+    }
+    entries
+}
+
+/// Reads the identification, timer and error state of the local APIC of the current CPU.
+pub fn dump_lapic() -> LapicState {
+    let lapic = get_lapic();
+    unsafe {
+        LapicState {
+            id: lapic.id(),
+            version: lapic.version(),
+            x2apic: Msr::new(IA32_APIC_BASE).read() & X2APIC_ENABLE != 0,
+            max_lvt_entry: lapic.max_lvt_entry(),
+            timer_initial: TIMER_INITIAL_COUNT.load(Ordering::SeqCst),
+            timer_current: lapic.timer_current(),
+            error_flags: lapic.error_flags(),
+        }
+    }
+}
+
+/// Returns the GSI that the ISA IRQ `irq` is connected to, and the flags for its polarity and trigger mode.
+/// ISA IRQs without an override are active high and edge triggered.
+fn irq_to_gsi(irq: u8) -> (u32, IrqFlags) {
+    let acpi = super::acpi::ACPI.try_get().unwrap();
+    let source_override = acpi
+        .apic_info
+        .interrupt_source_overrides
+        .iter()
+        .find(|source_override| source_override.isa_source == irq);
+
+    match source_override {
+        Some(source_override) => {
+            let mut flags = IrqFlags::empty();
+            if source_override.polarity == Polarity::ActiveLow {
+                flags |= IrqFlags::LOW_ACTIVE;
+            }
+            if source_override.trigger_mode == TriggerMode::Level {
+                flags |= IrqFlags::LEVEL_TRIGGERED;
+            }
+            (source_override.global_system_interrupt, flags)
+        }
+        None => (irq as u32, IrqFlags::empty()),
+    }
 }
 
 pub unsafe fn calibrate_timer(lapic: &mut LocalApic) {
-    let mut lapic_total_ticks = 0;
     let hpet_clock_speed = HPET.clock_speed() as u64;
     let hpet_tick_per_ms = 1_000_000_000_000 / hpet_clock_speed;
+    let mut samples = [0; TIMER_CALIBRATION_ITERATION];
+
+    let mut average_clock_per_ms = None;
+    for attempt in 1..=TIMER_CALIBRATION_ATTEMPTS {
+        for sample in samples.iter_mut() {
+            let next_ms = HPET.get_monotonic_counter() + hpet_tick_per_ms;
+            lapic.set_timer_initial(u32::MAX);
+            while HPET.get_monotonic_counter() < next_ms {}
+            *sample = u32::MAX - lapic.timer_current();
+        }
 
-    for _ in 0..TIMER_CALIBRATION_ITERATION {
-        let next_ms = HPET.get_counter() + hpet_tick_per_ms;
-        lapic.set_timer_initial(u32::MAX);
-        while HPET.get_counter() < next_ms {}
-        lapic_total_ticks += u32::MAX - lapic.timer_current();
+        average_clock_per_ms = calibration_average(&mut samples);
+        match average_clock_per_ms {
+            Some(ticks_per_ms) => {
+                log::debug!("LAPIC timer: {} ticks per ms", ticks_per_ms);
+                break;
+            }
+            None => log::warn!(
+                "LAPIC timer calibration attempt {} gave implausible readings",
+                attempt
+            ),
+        }
     }
 
-    let average_clock_per_ms = lapic_total_ticks / TIMER_CALIBRATION_ITERATION;
+    let average_clock_per_ms = average_clock_per_ms.unwrap_or_else(|| {
+        log::warn!(
+            "LAPIC timer calibration failed, assuming {} ticks per ms",
+            FALLBACK_LAPIC_TICKS_PER_MS
+        );
+        FALLBACK_LAPIC_TICKS_PER_MS
+    });
+    LAPIC_TICKS_PER_MS.store(average_clock_per_ms, Ordering::SeqCst);
 
     lapic.set_timer_mode(TimerMode::Periodic);
-    lapic.set_timer_initial(average_clock_per_ms * 1000 / TIMER_FREQUENCY_HZ);
+    program_timer(lapic, average_clock_per_ms, TICK_HZ.load(Ordering::SeqCst));
+}
+
+/// Averages the LAPIC ticks measured over each HPET millisecond, `None` if the readings are implausible.
+///
+/// Samples further than a quarter from the median are dropped as outliers, e.g. a window where
+/// the VM was paused. The readings are rejected if more than half are outliers or if the
+/// average is outside `MIN_LAPIC_TICKS_PER_MS..=MAX_LAPIC_TICKS_PER_MS`.
+pub fn calibration_average(samples: &mut [u32]) -> Option<u32> {
+    if samples.is_empty() {
+        return None;
+    }
+    samples.sort_unstable();
+    let median = samples[samples.len() / 2] as u64;
+    let tolerance = median / 4;
+
+    let (mut total, mut count) = (0u64, 0u64);
+    for &sample in samples.iter() {
+        if (sample as u64).abs_diff(median) <= tolerance {
+            total += sample as u64;
+            count += 1;
+        }
+    }
+    if count * 2 < samples.len() as u64 {
+        return None;
+    }
+
+    let average = (total / count) as u32;
+    (MIN_LAPIC_TICKS_PER_MS..=MAX_LAPIC_TICKS_PER_MS)
+        .contains(&average)
+        .then_some(average)
+}
+
+/// Sets the frequency of the timer interrupt, in Hz.
+///
+/// Call it before `init_framework` so every CPU calibrates with it. After the calibration the
+/// timer is only reprogrammed on a single CPU, with the APs started the change is refused,
+/// since `tick_hz` and the tick counts assume one rate on every CPU.
+pub fn set_tick_hz(hz: u32) -> Result<(), &'static str> {
+    if hz == 0 || hz > MAX_TIMER_FREQUENCY_HZ {
+        return Err("Timer frequency out of range!");
+    }
+    let ticks_per_ms = LAPIC_TICKS_PER_MS.load(Ordering::SeqCst);
+    if ticks_per_ms != 0 && super::smp::CPUS.read().len() > 1 {
+        return Err("The timer frequency can't change once the APs are running!");
+    }
+    TICK_HZ.store(hz, Ordering::SeqCst);
+
+    if ticks_per_ms != 0 {
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            program_timer(&mut get_lapic(), ticks_per_ms, hz);
+        });
+    }
+    Ok(())
+}
+
+/// Unmasks the timer interrupt of the current CPU.
+/// `init_framework` and the APs leave it masked after the calibration, `start_schedule` calls this.
+pub fn enable_timer() {
+    unsafe { get_lapic().enable_timer() };
+}
+
+/// Returns the effective frequency of the timer interrupt, in Hz.
+///
+/// Before calibration this is the requested frequency, after it the rate the
+/// programmed initial count really gives, which differs from it by the rounding.
+pub fn tick_hz() -> u32 {
+    let initial_count = TIMER_INITIAL_COUNT.load(Ordering::SeqCst);
+    if initial_count == 0 {
+        return TICK_HZ.load(Ordering::SeqCst);
+    }
+    let ticks_per_ms = LAPIC_TICKS_PER_MS.load(Ordering::SeqCst) as u64;
+    (ticks_per_ms * 1000 / initial_count as u64) as u32
+}
+
+/// Returns the timer initial count giving `hz` interrupts per second, at least 1.
+pub fn timer_initial_count(ticks_per_ms: u32, hz: u32) -> u32 {
+    (ticks_per_ms as u64 * 1000 / hz as u64).clamp(1, u32::MAX as u64) as u32
+}
+
+unsafe fn program_timer(lapic: &mut LocalApic, ticks_per_ms: u32, hz: u32) {
+    let initial_count = timer_initial_count(ticks_per_ms, hz);
+    TIMER_INITIAL_COUNT.store(initial_count, Ordering::SeqCst);
+    lapic.set_timer_initial(initial_count);
 }
@@ -1,15 +1,24 @@
+use core::arch::asm;
+use core::mem::size_of;
 use spin::Lazy;
 use x86_64::instructions::segmentation::{Segment, CS, SS};
-use x86_64::instructions::tables::load_tss;
+use x86_64::instructions::tables::{load_tss, sgdt};
+use x86_64::structures::DescriptorTablePointer;
 use x86_64::structures::gdt::GlobalDescriptorTable;
 use x86_64::structures::gdt::{Descriptor, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use super::smp::{CpuCounters, PerCpu};
+use super::watchdog::WatchdogState;
+
 pub const DOUBLE_FAULT_IST_INDEX: usize = 0;
 const FAULT_STACK_SIZE: usize = 256;
 
 pub struct CpuInfo {
+    pub per_cpu: PerCpu,
+    pub watchdog: WatchdogState,
+    pub counters: CpuCounters,
     gdt: GlobalDescriptorTable,
     tss: TaskStateSegment,
     selectors: Option<Selectors>,
@@ -17,8 +26,11 @@ pub struct CpuInfo {
 }
 
 impl CpuInfo {
-    pub fn new() -> Self {
+    pub fn new(lapic_id: u32) -> Self {
         Self {
+            per_cpu: PerCpu::new(lapic_id),
+            watchdog: WatchdogState::default(),
+            counters: CpuCounters::default(),
             gdt: GlobalDescriptorTable::new(),
             tss: TaskStateSegment::new(),
             selectors: None,
@@ -26,7 +38,11 @@ impl CpuInfo {
         }
     }
 
+    /// Sets up the descriptor tables, once the `CpuInfo` is leaked at its final address.
     pub fn init(&mut self) {
+        let cpu_info: *const CpuInfo = self;
+        self.per_cpu.set_cpu_info(cpu_info);
+
         let (mut gdt, mut selectors) = COMMON_GDT.clone();
 
         self.tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] = {
@@ -54,11 +70,57 @@ impl CpuInfo {
         }
     }
 
+    /// Checks that the GDT, the code segment and the TSS of this CPU are the loaded ones.
+    /// Logs what is wrong, since a bad descriptor table usually ends in a silent triple fault.
+    pub fn check_loaded(&self) -> bool {
+        let selectors = self.selectors.as_ref().unwrap();
+        let entries = self.gdt.entries();
+        let expected = DescriptorTablePointer {
+            limit: (entries.len() * size_of::<u64>() - 1) as u16,
+            base: VirtAddr::from_ptr(entries.as_ptr()),
+        };
+
+        let mut loaded = check_table_pointer("GDT", &sgdt(), &expected);
+        if CS::get_reg() != selectors.code_selector {
+            log::error!("Loaded CS {:?} is not the kernel code segment", CS::get_reg());
+            loaded = false;
+        }
+
+        let task_register: u16;
+        unsafe { asm!("str {0:x}", out(reg) task_register, options(nomem, nostack, preserves_flags)) };
+        if task_register != selectors.tss_selector.unwrap().0 {
+            log::error!("Loaded task register {:#x} is not the TSS of this CPU", task_register);
+            loaded = false;
+        }
+
+        loaded
+    }
+
     pub fn set_ring0_rsp(&mut self, rsp: VirtAddr) {
         self.tss.privilege_stack_table[0] = rsp;
     }
 }
 
+/// Checks that the loaded descriptor table pointer is the expected one, logging the difference.
+pub fn check_table_pointer(
+    name: &str,
+    loaded: &DescriptorTablePointer,
+    expected: &DescriptorTablePointer,
+) -> bool {
+    let (loaded_base, expected_base) = (loaded.base, expected.base);
+    let (loaded_limit, expected_limit) = (loaded.limit, expected.limit);
+
+    if loaded_base != expected_base {
+        log::error!("Loaded {} base {:#x} is not {:#x}", name, loaded_base, expected_base);
+        return false;
+    }
+    if loaded_limit != expected_limit {
+        log::error!("Loaded {} limit {:#x} is not {:#x}", name, loaded_limit, expected_limit);
+        return false;
+    }
+    true
+}
+
 static COMMON_GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
     let mut gdt = GlobalDescriptorTable::new();
 
@@ -0,0 +1,59 @@
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Lazy;
+
+/// The MWAIT hint for C1, deeper C-states may stop the LAPIC timer on CPUs without ARAT.
+const MWAIT_HINT_C1: u32 = 0;
+
+/// Bumped whenever the scheduler queues a thread, idle CPUs monitor its cache line.
+/// The ready queue is shared by all CPUs, so every idle CPU is woken and the first one takes the thread.
+#[repr(align(64))]
+struct WakeupCounter(AtomicU64);
+
+static WAKEUP: WakeupCounter = WakeupCounter(AtomicU64::new(0));
+
+/// Whether the CPU supports MONITOR/MWAIT, CPUID leaf 1 ECX bit 3.
+/// `__cpuid` is only safe on newer compilers.
+#[allow(unused_unsafe)]
+pub static MWAIT_SUPPORTED: Lazy<bool> = Lazy::new(|| unsafe { __cpuid(1).ecx & (1 << 3) != 0 });
+
+/// Wakes the idle CPUs, called by the scheduler when a thread becomes ready.
+#[inline]
+pub fn wake_idle_cpus() {
+    WAKEUP.0.fetch_add(1, Ordering::Release);
+}
+
+/// Sleeps until an interrupt, or until a thread is queued when MWAIT is supported.
+/// Returns whether a thread was queued while sleeping. Interrupts must be enabled.
+pub fn wait_for_work() -> bool {
+    if !*MWAIT_SUPPORTED {
+        x86_64::instructions::hlt();
+        return false;
+    }
+
+    let seen = WAKEUP.0.load(Ordering::Acquire);
+    unsafe {
+        asm!("monitor", in("rax") &WAKEUP.0, in("ecx") 0, in("edx") 0, options(nostack, preserves_flags));
+    }
+    // A thread queued before the monitor was armed wouldn't wake us, so check again.
+    if WAKEUP.0.load(Ordering::Acquire) == seen {
+        unsafe {
+            asm!("mwait", in("eax") MWAIT_HINT_C1, in("ecx") 0, options(nostack, preserves_flags));
+        }
+    }
+    WAKEUP.0.load(Ordering::Acquire) != seen
+}
+
+/// The loop of an idle CPU, it schedules as soon as a thread is queued instead of waiting for the next tick.
+pub fn idle_loop() -> ! {
+    loop {
+        let counters = &super::smp::current_cpu().counters;
+        counters.set_idle(true);
+        let woken = wait_for_work();
+        counters.set_idle(false);
+        if woken {
+            crate::task::schedule();
+        }
+    }
+}
@@ -1,18 +1,28 @@
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU64, Ordering};
 use spin::Lazy;
 use spin::Mutex;
 use x86_64::instructions::port::PortReadOnly;
+use x86_64::instructions::tables::sidt;
 use x86_64::registers::control::Cr2;
+use x86_64::structures::DescriptorTablePointer;
 use x86_64::structures::idt::InterruptDescriptorTable;
 use x86_64::structures::idt::InterruptStackFrame;
 use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::PrivilegeLevel;
 use x86_64::VirtAddr;
 
-use super::gdt::DOUBLE_FAULT_IST_INDEX;
+use super::gdt::{check_table_pointer, DOUBLE_FAULT_IST_INDEX};
 use crate::arch::apic::get_lapic_id;
+use crate::arch::smp::{current_cpu, current_lapic_id, KernelGs, BSP_LAPIC_ID};
 use crate::task::scheduler::SCHEDULER;
+use crate::START_SCHEDULE;
 
 const INTERRUPT_INDEX_OFFSET: u8 = 32;
 
+/// The count of LAPIC timer interrupts on the BSP, see `arch::ticks`.
+pub static TICKS: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
@@ -21,11 +31,16 @@ pub enum InterruptIndex {
     ApicSpurious,
     Keyboard,
     Mouse,
+    Serial,
+    Rtc,
+    Watchdog,
 }
 
 macro_rules! interrupt_handler {
     ($k: expr) => {{
         extern "x86-interrupt" fn default(frame: InterruptStackFrame) {
+            let _gs = KernelGs::enter(&frame);
+            current_cpu().counters.count_interrupt();
             IRQ_HANDLER.lock()($k as usize, frame);
         }
         default
@@ -50,6 +65,7 @@ macro_rules! interrupt_handler10 {
 pub static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     let mut idt = InterruptDescriptorTable::new();
 
+    idt.non_maskable_interrupt.set_handler_fn(non_maskable_interrupt);
     idt.breakpoint.set_handler_fn(breakpoint);
     idt.segment_not_present.set_handler_fn(segment_not_present);
     idt.invalid_opcode.set_handler_fn(invalid_opcode);
@@ -85,6 +101,9 @@ pub static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     idt[InterruptIndex::ApicSpurious as u8].set_handler_fn(spurious_interrupt);
     idt[InterruptIndex::Keyboard as u8].set_handler_fn(keyboard_interrupt);
     idt[InterruptIndex::Mouse as u8].set_handler_fn(mouse_interrupt);
+    idt[InterruptIndex::Serial as u8].set_handler_fn(serial_interrupt);
+    idt[InterruptIndex::Rtc as u8].set_handler_fn(rtc_interrupt);
+    idt[InterruptIndex::Watchdog as u8].set_handler_fn(watchdog_interrupt);
 
     unsafe {
         idt.double_fault
@@ -95,10 +114,33 @@ pub static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     return idt;
 });
 
+/// Checks that the loaded IDT is `IDT` with its full limit.
+pub fn check_idt_loaded() -> bool {
+    let expected = DescriptorTablePointer {
+        limit: (size_of::<InterruptDescriptorTable>() - 1) as u16,
+        base: VirtAddr::from_ptr(&*IDT),
+    };
+    check_table_pointer("IDT", &sidt(), &expected)
+}
+
 #[naked]
 extern "x86-interrupt" fn timer_interrupt(_frame: InterruptStackFrame) {
     fn timer_handler(context: VirtAddr) -> VirtAddr {
-        super::apic::end_of_interrupt();
+        // `task::schedule` raises the vector with `int`, only a real timer interrupt is a tick.
+        if super::apic::is_in_service(InterruptIndex::Timer as u8) {
+            if current_lapic_id() == *BSP_LAPIC_ID {
+                TICKS.fetch_add(1, Ordering::Relaxed);
+            }
+            let counters = &current_cpu().counters;
+            counters.count_interrupt();
+            counters.count_tick();
+            super::watchdog::pet();
+            super::apic::end_of_interrupt();
+        }
+        // A tick or an `int` before `start_schedule` has no scheduler to switch with.
+        if !START_SCHEDULE.load(Ordering::SeqCst) {
+            return context;
+        }
         let mut scheduler = SCHEDULER.lock();
 
         let address = scheduler.schedule(context);
@@ -109,11 +151,21 @@ extern "x86-interrupt" fn timer_interrupt(_frame: InterruptStackFrame) {
     unsafe {
         core::arch::asm!(
             "cli",
+            // Swap the GS bases if the interrupted code is in ring 3.
+            "test byte ptr [rsp + 8], 3",
+            "jz 2f",
+            "swapgs",
+            "2:",
             crate::push_context!(),
             "mov rdi, rsp",
             "call {timer_handler}",
             "mov rsp, rax",
             crate::pop_context!(),
+            // The frame may be the one of another thread, swap if it returns to ring 3.
+            "test byte ptr [rsp + 8], 3",
+            "jz 3f",
+            "swapgs",
+            "3:",
             "sti",
             "iretq",
             timer_handler = sym timer_handler,
@@ -122,23 +174,33 @@ extern "x86-interrupt" fn timer_interrupt(_frame: InterruptStackFrame) {
     }
 }
 
-extern "x86-interrupt" fn lapic_error(_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn lapic_error(frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&frame);
     log::error!("Local APIC error!");
     super::apic::end_of_interrupt();
 }
 
-extern "x86-interrupt" fn spurious_interrupt(_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn spurious_interrupt(frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&frame);
     log::debug!("Received spurious interrupt!");
     super::apic::end_of_interrupt();
 }
 
 extern "x86-interrupt" fn segment_not_present(frame: InterruptStackFrame, error_code: u64) {
+    let _gs = KernelGs::enter(&frame);
     log::error!("Exception: Segment Not Present\n{:#?}", frame);
     log::error!("Error Code: {:#x}", error_code);
     panic!("Unrecoverable fault occured, halting!");
 }
 
 extern "x86-interrupt" fn general_protection_fault(frame: InterruptStackFrame, error_code: u64) {
+    let _gs = KernelGs::enter(&frame);
+    if frame.code_segment.rpl() == PrivilegeLevel::Ring3 {
+        log::warn!("Exception: General Protection Fault in user mode\n{:#?}", frame);
+        log::warn!("Error Code: {:#x}", error_code);
+        kill_current_process();
+    }
+
     //log::error!("Processor: {}", get_lapic_id());
     log::error!("Exception: General Protection Fault\n{:#?}", frame);
     log::error!("Error Code: {:#x}", error_code);
@@ -161,33 +223,96 @@ extern "x86-interrupt" fn general_protection_fault(frame: InterruptStackFrame, e
 }
 
 extern "x86-interrupt" fn invalid_opcode(frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&frame);
     log::error!("Exception: Invalid Opcode\n{:#?}", frame);
     x86_64::instructions::hlt();
 }
 
+extern "x86-interrupt" fn non_maskable_interrupt(frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter_paranoid();
+    if super::panic::PANICKED.load(Ordering::SeqCst) {
+        super::panic::halt();
+    }
+    if super::watchdog::handle_nmi(&frame) {
+        return;
+    }
+    log::warn!("Exception: Non-maskable Interrupt\n{:#?}", frame);
+}
+
 extern "x86-interrupt" fn breakpoint(frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&frame);
     log::debug!("Exception: Breakpoint\n{:#?}", frame);
 }
 
 extern "x86-interrupt" fn double_fault(frame: InterruptStackFrame, error_code: u64) -> ! {
+    let _gs = KernelGs::enter_paranoid();
     log::error!("Exception: Double Fault\n{:#?}", frame);
     log::error!("Error Code: {:#x}", error_code);
     panic!("Unrecoverable fault occured, halting!");
 }
 
-extern "x86-interrupt" fn keyboard_interrupt(_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn keyboard_interrupt(frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&frame);
+    current_cpu().counters.count_interrupt();
     let scancode: u8 = unsafe { PortReadOnly::new(0x60).read() };
     crate::drivers::keyboard::add_scancode(scancode);
     super::apic::end_of_interrupt();
 }
 
-extern "x86-interrupt" fn mouse_interrupt(_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn mouse_interrupt(frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&frame);
+    current_cpu().counters.count_interrupt();
     let packet = unsafe { PortReadOnly::new(0x60).read() };
     crate::drivers::mouse::MOUSE.lock().process_packet(packet);
     super::apic::end_of_interrupt();
 }
 
+extern "x86-interrupt" fn serial_interrupt(frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&frame);
+    current_cpu().counters.count_interrupt();
+    let data = unsafe { PortReadOnly::new(0x3f8).read() };
+    crate::drivers::serial::add_received(data);
+    super::apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn rtc_interrupt(frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&frame);
+    current_cpu().counters.count_interrupt();
+    crate::drivers::rtc::acknowledge_interrupt();
+    super::apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn watchdog_interrupt(frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&frame);
+    current_cpu().counters.count_interrupt();
+    super::watchdog::check();
+    super::apic::end_of_interrupt();
+}
+
 extern "x86-interrupt" fn page_fault(frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
+    let _gs = KernelGs::enter(&frame);
+    // The first write to a copy-on-write page is retried once it has its own frame.
+    let write_protected =
+        PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if error_code.contains(write_protected) {
+        if let Ok(address) = Cr2::read() {
+            if crate::memory::handle_cow_fault(address) {
+                return;
+            }
+        }
+    }
+    // A user access to a file mapping reads the page, the file may block.
+    let user_mode = frame.code_segment.rpl() == PrivilegeLevel::Ring3;
+    if user_mode && !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        if let Ok(address) = Cr2::read() {
+            x86_64::instructions::interrupts::enable();
+            let resolved = crate::task::process::handle_file_fault(address);
+            x86_64::instructions::interrupts::disable();
+            if resolved {
+                return;
+            }
+        }
+    }
     log::warn!("Processor: {}", get_lapic_id());
     log::warn!("Exception: Page Fault\n{:#?}", frame);
     log::warn!("Error Code: {:?}", error_code);
@@ -202,6 +327,21 @@ extern "x86-interrupt" fn page_fault(frame: InterruptStackFrame, error_code: Pag
     x86_64::instructions::hlt();
 }
 
+/// Kills the process of the current thread and switches to another thread, never returns.
+/// Only call this for faults raised in user mode, where no kernel lock can be held.
+fn kill_current_process() -> ! {
+    let thread = crate::task::current_thread().upgrade().unwrap();
+    let process = thread.read().process.upgrade().unwrap();
+    log::warn!("Killing process {:?}", process.read().id);
+    drop(thread);
+    crate::task::Process::kill(process);
+
+    // The thread is terminated, so the scheduler never switches back to it.
+    loop {
+        crate::task::schedule();
+    }
+}
+
 pub type IrqHandler = fn(irq: usize, frame: InterruptStackFrame);
 
 pub static IRQ_HANDLER: Mutex<IrqHandler> = Mutex::new(default_irq_handler);
@@ -1,10 +1,14 @@
 pub mod acpi;
 pub mod apic;
 pub mod gdt;
+pub mod idle;
 pub mod interrupts;
+pub mod panic;
 pub mod smp;
+pub mod watchdog;
 
 use acpi::ACPI;
+use core::sync::atomic::Ordering;
 use x86_64::instructions::port::Port;
 
 use crate::drivers::pci::{
@@ -12,6 +16,12 @@ use crate::drivers::pci::{
     PORT_PCI_CONFIG_DATA,
 };
 
+/// Returns the timer ticks since the scheduler started, they advance at `apic::tick_hz()`.
+/// Cheaper than reading the HPET, for timeouts and accounting which only need the tick resolution.
+pub fn ticks() -> u64 {
+    interrupts::TICKS.load(Ordering::Relaxed)
+}
+
 /// TraitPciArch Pci架构相关函数，任何架构都应独立实现trait里的函数
 pub trait TraitPciArch {
     /// @brief 读取寄存器值，x86_64架构通过读取两个特定io端口实现
@@ -0,0 +1,153 @@
+use core::arch::asm;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use uart_16550::SerialPort;
+use x86_64::registers::control::{Cr2, Cr3};
+
+use crate::console::{is_headless, CONSOLE, TERMINAL};
+use crate::drivers::serial::SERIAL;
+
+#[cfg(feature = "smp")]
+use {super::acpi::ACPI, super::apic::get_lapic, x2apic::lapic::IpiAllShorthand};
+
+const MAX_BACKTRACE_DEPTH: usize = 16;
+
+/// Set by the first CPU which panics, the others halt in the NMI handler once it is set.
+pub static PANICKED: AtomicBool = AtomicBool::new(false);
+
+/// The general purpose registers at the time of the panic.
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+impl Registers {
+    /// Captures the current general purpose registers.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut registers = Self::default();
+        unsafe {
+            asm!(
+                "mov [{0} + 0x00], rax",
+                "mov [{0} + 0x08], rbx",
+                "mov [{0} + 0x10], rcx",
+                "mov [{0} + 0x18], rdx",
+                "mov [{0} + 0x20], rsi",
+                "mov [{0} + 0x28], rdi",
+                "mov [{0} + 0x30], rbp",
+                "mov [{0} + 0x38], rsp",
+                "mov [{0} + 0x40], r8",
+                "mov [{0} + 0x48], r9",
+                "mov [{0} + 0x50], r10",
+                "mov [{0} + 0x58], r11",
+                "mov [{0} + 0x60], r12",
+                "mov [{0} + 0x68], r13",
+                "mov [{0} + 0x70], r14",
+                "mov [{0} + 0x78], r15",
+                in(reg) &mut registers,
+                options(nostack, preserves_flags),
+            );
+        }
+        registers
+    }
+}
+
+/// Prints a post-mortem to the console and the serial port.
+///
+/// The framework doesn't define the `#[panic_handler]` itself, the kernel calls this from its own one.
+/// Halts the other CPUs with an NMI, then dumps the registers, CR2/CR3, the message and a backtrace.
+/// The backtrace follows the frame pointers, so build with `-C force-frame-pointers=yes` to get it.
+///
+/// To check it by hand, boot with `-smp 4 -serial stdio` and panic in a kernel thread:
+/// every CPU should stop printing and the dump should appear once on the serial port.
+pub fn panic_handler(info: &PanicInfo) -> ! {
+    x86_64::instructions::interrupts::disable();
+    let registers = Registers::capture();
+
+    if PANICKED.swap(true, Ordering::SeqCst) {
+        // Another CPU or a panic inside this handler is already dumping.
+        halt();
+    }
+
+    // The APs parked in the bootloader with `nosmp` have no NMI handler of the framework.
+    #[cfg(feature = "smp")]
+    if ACPI.is_initialized() && !crate::kargs::flag("nosmp") {
+        unsafe { get_lapic().send_nmi_all(IpiAllShorthand::AllExcludingSelf) };
+    }
+
+    // The other CPUs are halted now, so a lock still held belongs to one of them
+    // or to the code which panicked, it is never going to be released.
+    unsafe {
+        if SERIAL.is_locked() {
+            SERIAL.force_unlock();
+        }
+        if CONSOLE.is_locked() {
+            CONSOLE.force_unlock();
+        }
+        if !is_headless() && TERMINAL.is_locked() {
+            TERMINAL.force_unlock();
+        }
+    }
+
+    let mut serial = unsafe { SerialPort::new(0x3f8) };
+    let print = |serial: &mut SerialPort, args: core::fmt::Arguments| {
+        let _ = serial.write_fmt(args);
+        if !is_headless() {
+            crate::console::_print(args);
+        }
+    };
+
+    print(&mut serial, format_args!("\nKernel panic: {}\n", info));
+    print(&mut serial, format_args!("{:#x?}\n", registers));
+    match Cr2::read() {
+        Ok(address) => print(&mut serial, format_args!("CR2: {:#x}\n", address)),
+        Err(error) => print(&mut serial, format_args!("CR2: {:?}\n", error)),
+    }
+    let (frame, flags) = Cr3::read();
+    print(
+        &mut serial,
+        format_args!("CR3: {:#x} {:?}\n", frame.start_address(), flags),
+    );
+
+    print(&mut serial, format_args!("Backtrace:\n"));
+    let mut rbp = registers.rbp;
+    for depth in 0..MAX_BACKTRACE_DEPTH {
+        if rbp == 0 || rbp % 8 != 0 || rbp < 0xffff_8000_0000_0000 {
+            break;
+        }
+        let return_address = unsafe { *((rbp + 8) as *const u64) };
+        if return_address == 0 {
+            break;
+        }
+        print(&mut serial, format_args!("  {:>2}: {:#x}\n", depth, return_address));
+        rbp = unsafe { *(rbp as *const u64) };
+    }
+
+    halt();
+}
+
+/// Halts the current CPU forever.
+pub fn halt() -> ! {
+    loop {
+        x86_64::instructions::interrupts::disable();
+        x86_64::instructions::hlt();
+    }
+}
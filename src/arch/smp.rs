@@ -1,50 +1,333 @@
-use core::sync::atomic::Ordering;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
-use limine::request::SmpRequest;
-use limine::response::SmpResponse;
-use limine::smp::Cpu;
 use spin::{Lazy, RwLock};
+use x86_64::instructions::segmentation::GS;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::{PrivilegeLevel, VirtAddr};
 
-use super::apic::calibrate_timer;
 use super::gdt::CpuInfo;
-use super::interrupts::IDT;
-use crate::arch::apic::get_lapic;
-use crate::drivers::hpet::HPET_INIT;
-use crate::task::scheduler::SCHEDULER_INIT;
-use crate::{user, START_SCHEDULE};
+use crate::InitError;
 
+#[cfg(feature = "smp")]
+use {
+    super::apic::{calibrate_timer, enable_timer},
+    super::interrupts::{check_idt_loaded, IDT},
+    crate::arch::apic::get_lapic,
+    crate::drivers::hpet::{uptime_ms, HPET_INIT},
+    crate::task::scheduler::SCHEDULER_INIT,
+    crate::{user, START_SCHEDULE},
+    core::sync::atomic::AtomicUsize,
+    limine::request::SmpRequest,
+    limine::response::SmpResponse,
+    limine::smp::Cpu,
+};
+
+#[cfg(feature = "smp")]
+const AP_PARK_TIMEOUT_MS: u64 = 1000;
+
+#[cfg(feature = "smp")]
 #[used]
 #[link_section = ".requests"]
 static SMP_REQUEST: SmpRequest = SmpRequest::new();
 
+/// The CPUs the framework runs on, only the BSP without the `smp` feature.
 pub static CPUS: Lazy<RwLock<Cpus>> = Lazy::new(|| RwLock::new(Cpus::new()));
+
+#[cfg(feature = "smp")]
 pub static BSP_LAPIC_ID: Lazy<u32> = Lazy::new(|| SMP_RESPONSE.bsp_lapic_id());
+#[cfg(feature = "smp")]
 static SMP_RESPONSE: Lazy<&SmpResponse> = Lazy::new(|| SMP_REQUEST.get_response().unwrap());
 
+/// The APs which are set up and wait for `start_schedule`.
+#[cfg(feature = "smp")]
+static PARKED_APS: AtomicUsize = AtomicUsize::new(0);
+
+/// Waits for every AP to be set up and parked on `START_SCHEDULE`, for at most `AP_PARK_TIMEOUT_MS`.
+/// An AP which is late after that starts scheduling as soon as it is parked.
+#[cfg(feature = "smp")]
+pub fn wait_for_parked_aps() {
+    let aps = CPUS.read().len() - 1;
+    let start = uptime_ms();
+    while PARKED_APS.load(Ordering::SeqCst) < aps {
+        if uptime_ms() - start > AP_PARK_TIMEOUT_MS {
+            log::warn!(
+                "Only {} of {} APs are parked, starting the scheduling anyway",
+                PARKED_APS.load(Ordering::SeqCst),
+                aps
+            );
+            return;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// There are no APs to wait for without the `smp` feature.
+#[cfg(not(feature = "smp"))]
+pub fn wait_for_parked_aps() {}
+
+/// Without the `smp` feature the APs are never woken up, so the BSP id comes from CPUID.
+#[cfg(not(feature = "smp"))]
+pub static BSP_LAPIC_ID: Lazy<u32> = Lazy::new(cpuid_lapic_id);
+
+/// Returns an error if the bootloader didn't answer the SMP request, `BSP_LAPIC_ID` needs it.
+#[cfg(feature = "smp")]
+pub fn check_available() -> Result<(), InitError> {
+    SMP_REQUEST.get_response().map(|_| ()).ok_or(InitError::NoSmp)
+}
+
+/// A uniprocessor build doesn't make the SMP request.
+#[cfg(not(feature = "smp"))]
+pub fn check_available() -> Result<(), InitError> {
+    Ok(())
+}
+
+/// Returns the lapic id of the current CPU from CPUID, it can be read before the local APIC is set up.
+/// The x2APIC id of leaf 0xb is used when there is one, it is the same as the xAPIC id below 256.
+#[allow(unused_unsafe)]
+pub fn cpuid_lapic_id() -> u32 {
+    use core::arch::x86_64::{__cpuid, __cpuid_count};
+
+    // The older nightlies the crate builds on still declare the CPUID intrinsics unsafe.
+    unsafe {
+        if __cpuid(0).eax >= 0xb && __cpuid_count(0xb, 0).ebx != 0 {
+            __cpuid_count(0xb, 0).edx
+        } else {
+            __cpuid(1).ebx >> 24
+        }
+    }
+}
+
+#[cfg(feature = "smp")]
 unsafe extern "C" fn ap_entry(smp_info: &Cpu) -> ! {
 
     CPUS.read().get(smp_info.lapic_id).load();
+    CPUS.read().get(smp_info.lapic_id).per_cpu.load();
+    CPUS.read().get(smp_info.lapic_id).check_loaded();
     IDT.load();
+    check_idt_loaded();
+    crate::drivers::fpu::init();
 
     while !HPET_INIT.load(Ordering::SeqCst) {}
 
     let mut lapic = get_lapic();
     lapic.enable();
+    lapic.disable_timer();
     calibrate_timer(&mut lapic);
-    lapic.enable_timer();
 
     while !SCHEDULER_INIT.load(Ordering::SeqCst) {}
 
     user::init();
 
+    PARKED_APS.fetch_add(1, Ordering::SeqCst);
     while !START_SCHEDULE.load(Ordering::SeqCst) {}
+    enable_timer();
     x86_64::instructions::interrupts::enable();
 
-    loop {
-        x86_64::instructions::hlt();
-        //crate::serial_print!(".");
+    // The idle thread of the scheduler takes over, a second idle loop would only take turns with it.
+    crate::task::exit_current_thread();
+}
+
+/// The data of a CPU that the GS base points to, so that each field is a single `gs:[offset]` read.
+/// While user code runs the kernel GS base holds it instead, and every entry from ring 3
+/// swaps it in with `KernelGs`, so the user GS base is kept and user code never sees the pointer.
+#[repr(C)]
+pub struct PerCpu {
+    lapic_id: u64,
+    current_thread: u64,
+    cpu_info: u64,
+}
+
+impl PerCpu {
+    pub const fn new(lapic_id: u32) -> Self {
+        Self {
+            lapic_id: lapic_id as u64,
+            current_thread: 0,
+            cpu_info: 0,
+        }
+    }
+
+    /// Sets the `CpuInfo` this is part of, it must be leaked first so it never moves.
+    pub fn set_cpu_info(&mut self, cpu_info: *const CpuInfo) {
+        self.cpu_info = cpu_info as u64;
+    }
+
+    /// Points the GS base of the current CPU to this, the kernel GS base holds the user one.
+    pub fn load(&self) {
+        KernelGsBase::write(VirtAddr::zero());
+        GsBase::write(VirtAddr::from_ptr(self));
+        PER_CPU_LOADED.store(true, Ordering::SeqCst);
+    }
+
+    /// Sets the current thread pointer, for CPUs other than the current one.
+    pub fn set_current_thread(&mut self, thread: *const ()) {
+        self.current_thread = thread as u64;
+    }
+
+    /// Returns the pointer to the thread running on this CPU, which may be another one.
+    pub fn current_thread(&self) -> *const () {
+        unsafe { core::ptr::read_volatile(&self.current_thread) as *const () }
+    }
+}
+
+/// Set once the BSP loaded its per-CPU data. Before that the GS base is unset,
+/// but only the BSP runs, and each AP loads its own before it does anything else.
+static PER_CPU_LOADED: AtomicBool = AtomicBool::new(false);
+
+/// Returns the lapic id of the current CPU, also before the per-CPU data is loaded.
+pub fn current_cpu_id() -> u32 {
+    if PER_CPU_LOADED.load(Ordering::Relaxed) {
+        current_lapic_id()
+    } else {
+        *BSP_LAPIC_ID
+    }
+}
+
+/// Returns whether the thread is running on a CPU other than the current one.
+pub fn runs_on_other_cpu(thread: *const ()) -> bool {
+    let current = current_lapic_id();
+    let cpus = CPUS.read();
+    cpus.0.iter().any(|(&lapic_id, cpu_info)| {
+        lapic_id != current && cpu_info.per_cpu.current_thread() == thread
+    })
+}
+
+/// Swaps the per-CPU GS base in for an interrupt handler and back out when dropped.
+/// Create it before anything reads the per-CPU data and keep it until the handler returns.
+pub struct KernelGs {
+    swapped: bool,
+}
+
+impl KernelGs {
+    /// Swaps the GS bases if the interrupted code ran in ring 3.
+    #[inline]
+    pub fn enter(frame: &InterruptStackFrame) -> Self {
+        Self::swap_if(frame.code_segment.rpl() == PrivilegeLevel::Ring3)
+    }
+
+    /// Swaps the GS bases if the user one is loaded, for an NMI or a double fault. Those may
+    /// arrive right after `syscall` in ring 0, before its `swapgs`. User code can't `wrgsbase`
+    /// and the GDT bases are 0, so a zero GS base is always the user one.
+    #[inline]
+    pub fn enter_paranoid() -> Self {
+        Self::swap_if(GsBase::read().is_null())
+    }
+
+    #[inline]
+    fn swap_if(swap: bool) -> Self {
+        if swap {
+            unsafe { GS::swap() };
+        }
+        Self { swapped: swap }
+    }
+}
+
+impl Drop for KernelGs {
+    #[inline]
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe { GS::swap() };
+        }
+    }
+}
+
+/// Returns the lapic id of the current CPU without accessing the local APIC.
+#[inline]
+pub fn current_lapic_id() -> u32 {
+    let lapic_id: u64;
+    unsafe { asm!("mov {}, gs:[0]", out(reg) lapic_id, options(nostack, readonly, preserves_flags)) };
+    lapic_id as u32
+}
+
+/// Returns the pointer to the thread running on the current CPU, null before the scheduler runs.
+#[inline]
+pub fn current_thread_ptr() -> *const () {
+    let thread: u64;
+    unsafe { asm!("mov {}, gs:[8]", out(reg) thread, options(nostack, readonly, preserves_flags)) };
+    thread as *const ()
+}
+
+/// Sets the pointer to the thread running on the current CPU.
+#[inline]
+pub fn set_current_thread_ptr(thread: *const ()) {
+    unsafe { asm!("mov gs:[8], {}", in(reg) thread as u64, options(nostack, preserves_flags)) };
+}
+
+/// The counters of a CPU, as returned by `cpu_stats`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuStats {
+    /// The interrupts serviced, timer ticks included.
+    pub interrupts: u64,
+    /// The switches to a different thread.
+    pub context_switches: u64,
+    /// The timer ticks, they advance at `apic::tick_hz()`.
+    pub ticks: u64,
+    /// The timer ticks which found the CPU in its idle loop.
+    pub idle_ticks: u64,
+}
+
+/// The counters of a CPU, kept in its `CpuInfo`.
+#[derive(Default)]
+pub struct CpuCounters {
+    interrupts: AtomicU64,
+    context_switches: AtomicU64,
+    ticks: AtomicU64,
+    idle_ticks: AtomicU64,
+    idle: AtomicBool,
+}
+
+impl CpuCounters {
+    pub fn count_interrupt(&self) {
+        self.interrupts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_context_switch(&self) {
+        self.context_switches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a timer tick, an idle tick too if the CPU is in its idle loop.
+    pub fn count_tick(&self) {
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+        if self.idle.load(Ordering::Relaxed) {
+            self.idle_ticks.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Marks whether the CPU is waiting for work in its idle loop.
+    pub fn set_idle(&self, idle: bool) {
+        self.idle.store(idle, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> CpuStats {
+        CpuStats {
+            interrupts: self.interrupts.load(Ordering::Relaxed),
+            context_switches: self.context_switches.load(Ordering::Relaxed),
+            ticks: self.ticks.load(Ordering::Relaxed),
+            idle_ticks: self.idle_ticks.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Returns the counters of every CPU by lapic id, for tools which show the CPU usage.
+/// The utilization of a CPU is `1 - idle_ticks / ticks`.
+pub fn cpu_stats() -> BTreeMap<u32, CpuStats> {
+    let cpus = CPUS.read();
+    cpus.0
+        .iter()
+        .map(|(&lapic_id, cpu_info)| (lapic_id, cpu_info.counters.stats()))
+        .collect()
+}
+
+/// Returns the `CpuInfo` of the current CPU, without taking `CPUS`.
+pub fn current_cpu() -> &'static CpuInfo {
+    let cpu_info: u64;
+    // The `CpuInfo`s are leaked, so they live forever.
+    unsafe {
+        asm!("mov {}, gs:[16]", out(reg) cpu_info, options(nostack, readonly, preserves_flags));
+        &*(cpu_info as *const CpuInfo)
     }
 }
 
@@ -67,7 +350,7 @@ impl Cpus {
 impl Cpus {
     pub fn new() -> Self {
         let mut cpus = BTreeMap::new();
-        cpus.insert(*BSP_LAPIC_ID, Box::leak(Box::new(CpuInfo::new())));
+        cpus.insert(*BSP_LAPIC_ID, Box::leak(Box::new(CpuInfo::new(*BSP_LAPIC_ID))));
         Cpus(cpus)
     }
 
@@ -79,14 +362,17 @@ impl Cpus {
         let bsp_info = self.get_mut(*BSP_LAPIC_ID);
         bsp_info.init();
         bsp_info.load();
+        bsp_info.per_cpu.load();
+        bsp_info.check_loaded();
     }
 
+    #[cfg(feature = "smp")]
     pub fn init_ap(&mut self) {
         for cpu in SMP_RESPONSE.cpus() {
             if cpu.id == *BSP_LAPIC_ID {
                 continue;
             }
-            let info = Box::leak(Box::new(CpuInfo::new()));
+            let info = Box::leak(Box::new(CpuInfo::new(cpu.lapic_id)));
             info.init();
             self.0.insert(cpu.lapic_id, info);
             cpu.goto_address.write(ap_entry);
@@ -0,0 +1,153 @@
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use uart_16550::SerialPort;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::apic::{get_lapic, route_gsi};
+use super::interrupts::InterruptIndex;
+use super::smp::{current_cpu, current_lapic_id, Cpus, BSP_LAPIC_ID, CPUS};
+use crate::drivers::hpet::{self, uptime_ms, HPET, HPET_INIT};
+use crate::START_SCHEDULE;
+
+/// How often the watchdog checks when the CPUs last ticked.
+const CHECK_PERIOD_MS: u64 = 100;
+
+static WATCHDOG_STARTED: AtomicBool = AtomicBool::new(false);
+static TIMEOUT_MS: AtomicU64 = AtomicU64::new(0);
+
+/// The per-CPU state of the watchdog, kept in the `CpuInfo`.
+#[derive(Default)]
+pub struct WatchdogState {
+    /// The uptime in milliseconds of the last timer tick of the CPU.
+    last_pet: AtomicU64,
+    /// Whether the current stall of the CPU was already reported.
+    reported: AtomicBool,
+    /// Set before the NMI which asks the CPU to dump its context.
+    dump_requested: AtomicBool,
+}
+
+/// Starts the watchdog, which reports every CPU whose timer didn't tick for `timeout_ms`.
+///
+/// It runs on a periodic HPET interrupt routed to the BSP, so it still fires when a CPU spins
+/// with interrupts disabled and its APIC timer is starved. A stuck CPU is logged once per stall,
+/// and sent an NMI which dumps its interrupted context. The BSP can't report itself being stuck
+/// with interrupts disabled, since the watchdog runs on it.
+///
+/// # Testing
+///
+/// With the `smp` feature, start the watchdog and the scheduling, then spawn a kernel thread
+/// which runs `x86_64::instructions::interrupts::disable()` followed by `loop {}`. Within
+/// `timeout_ms` the log names the CPU running it, and the NMI dump which follows has its
+/// instruction pointer inside the loop.
+pub fn start(timeout_ms: u64) -> Result<(), &'static str> {
+    if !HPET_INIT.load(Ordering::SeqCst) {
+        return Err("The HPET isn't initialized!");
+    }
+    if WATCHDOG_STARTED.swap(true, Ordering::SeqCst) {
+        return Err("The watchdog is already running!");
+    }
+    let Some(timer) = hpet::allocate_comparator_where(|caps| caps.periodic && caps.routes != 0)
+    else {
+        WATCHDOG_STARTED.store(false, Ordering::SeqCst);
+        return Err("No free HPET timer can fire periodically on the IOAPIC!");
+    };
+    let routes = HPET.timer_route_capability(timer as u32);
+
+    TIMEOUT_MS.store(timeout_ms, Ordering::SeqCst);
+    refresh_all(&CPUS.read(), uptime_ms());
+
+    // The highest inputs are above the ISA IRQs, so they aren't shared with a legacy device.
+    let gsi = 31 - routes.leading_zeros();
+    route_gsi(gsi, InterruptIndex::Watchdog as u8, *BSP_LAPIC_ID as u8);
+    // The clock speed is the tick period in femtoseconds.
+    let period = CHECK_PERIOD_MS * 1_000_000_000_000 / HPET.clock_speed() as u64;
+    HPET.start_periodic_timer(timer as u32, gsi, period);
+
+    log::info!(
+        "Watchdog started on HPET timer {}, GSI {}, timeout: {} ms",
+        timer,
+        gsi,
+        timeout_ms
+    );
+    Ok(())
+}
+
+/// Records that the current CPU is alive, its timer handler calls it on every tick.
+/// The state is reached through the per-CPU data, so a CPU holding `CPUS` doesn't stop it.
+pub fn pet() {
+    if WATCHDOG_STARTED.load(Ordering::SeqCst) {
+        current_cpu().watchdog.last_pet.store(uptime_ms(), Ordering::SeqCst);
+    }
+}
+
+/// Checks when every CPU last ticked, the HPET interrupt of the watchdog calls it.
+/// Nothing it takes may wait on a stuck CPU, the check is skipped until the next period instead.
+pub fn check() {
+    let now = uptime_ms();
+    // The scheduler holds `CPUS` for writing while it switches, maybe on the stuck CPU.
+    let Some(cpus) = CPUS.try_read() else {
+        return;
+    };
+    // The timers only tick once the scheduling started.
+    if !START_SCHEDULE.load(Ordering::SeqCst) {
+        refresh_all(&cpus, now);
+        return;
+    }
+
+    let timeout = TIMEOUT_MS.load(Ordering::SeqCst);
+    for &lapic_id in cpus.iter_id() {
+        let state = &cpus.get(lapic_id).watchdog;
+        let stalled = now.saturating_sub(state.last_pet.load(Ordering::SeqCst));
+        if stalled < timeout {
+            state.reported.store(false, Ordering::SeqCst);
+            continue;
+        }
+        if state.reported.swap(true, Ordering::SeqCst) {
+            continue;
+        }
+
+        report_stall(lapic_id, stalled);
+        // The BSP is running this, only its timer is stuck, its context says nothing.
+        if lapic_id != current_lapic_id() {
+            state.dump_requested.store(true, Ordering::SeqCst);
+            unsafe { get_lapic().send_nmi(lapic_id) };
+        }
+    }
+}
+
+/// Dumps the interrupted context if the watchdog sent the NMI, returns whether it did.
+pub fn handle_nmi(frame: &InterruptStackFrame) -> bool {
+    if !WATCHDOG_STARTED.load(Ordering::SeqCst) {
+        return false;
+    }
+    // The NMI may interrupt the scheduler while it holds `CPUS` for writing.
+    let Some(cpus) = CPUS.try_read() else {
+        return false;
+    };
+    let lapic_id = current_lapic_id();
+    if !cpus.get(lapic_id).watchdog.dump_requested.swap(false, Ordering::SeqCst) {
+        return false;
+    }
+    log::error!("Watchdog: CPU {} was stuck at\n{:#?}", lapic_id, frame);
+    true
+}
+
+/// Logs the stall, or writes it straight to COM1 if the CPU may be stuck holding the console.
+fn report_stall(lapic_id: u32, stalled: u64) {
+    if !crate::console::is_busy() {
+        log::error!("Watchdog: CPU {} hasn't ticked for {} ms, it's stuck!", lapic_id, stalled);
+        return;
+    }
+    let mut serial = unsafe { SerialPort::new(0x3f8) };
+    let _ = writeln!(
+        serial,
+        "Watchdog: CPU {} hasn't ticked for {} ms, it's stuck!",
+        lapic_id, stalled
+    );
+}
+
+fn refresh_all(cpus: &Cpus, now: u64) {
+    for &lapic_id in cpus.iter_id() {
+        cpus.get(lapic_id).watchdog.last_pet.store(now, Ordering::SeqCst);
+    }
+}
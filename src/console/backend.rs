@@ -0,0 +1,62 @@
+use core::fmt::{self, Write};
+
+use crate::drivers::{keyboard, serial::{self, SERIAL}};
+
+use super::TERMINAL;
+
+/// A place the console output goes to and the input comes from.
+pub trait ConsoleBackend: Write + Send {
+    /// Reads a byte of input, returns None if there is no input.
+    fn read_input(&mut self) -> Option<u8>;
+
+    /// Clears the output and moves the cursor home.
+    fn clear(&mut self);
+
+    /// Moves the cursor to the top left corner.
+    fn home(&mut self) {
+        let _ = self.write_str("\x1b[H");
+    }
+
+    /// Clears the line of the cursor and moves the cursor to its start.
+    fn clear_line(&mut self) {
+        let _ = self.write_str("\r\x1b[2K");
+    }
+}
+
+/// Prints to the terminal on the TTYs and reads the keyboard scancodes.
+pub struct TerminalBackend;
+
+impl Write for TerminalBackend {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        TERMINAL.lock().write_str(s)
+    }
+}
+
+impl ConsoleBackend for TerminalBackend {
+    fn read_input(&mut self) -> Option<u8> {
+        keyboard::get_scancode()
+    }
+
+    fn clear(&mut self) {
+        TERMINAL.lock().write_str("\x1b[2J\x1b[H").unwrap();
+    }
+}
+
+/// Prints to and reads from the COM1 serial port.
+pub struct SerialBackend;
+
+impl Write for SerialBackend {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        SERIAL.lock().write_str(s)
+    }
+}
+
+impl ConsoleBackend for SerialBackend {
+    fn read_input(&mut self) -> Option<u8> {
+        serial::get_received()
+    }
+
+    fn clear(&mut self) {
+        SERIAL.lock().write_str("\x1b[2J\x1b[H").unwrap();
+    }
+}
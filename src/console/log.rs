@@ -1,12 +1,25 @@
 use colorz::ansi::{Blue, Green, Red, Yellow};
 use colorz::{Colorize, Style};
 
+use super::log_buffer::{self, Timestamp};
 use crate::{println, serial_println};
 
+/// The level the records are filtered at without a `loglevel` kernel argument.
+const DEFAULT_LEVEL: log::LevelFilter = log::LevelFilter::Info;
+
 pub fn init() {
     static LOGGER: Logger = Logger;
     log::set_logger(&LOGGER).unwrap();
-    log::set_max_level(log::LevelFilter::Debug);
+
+    let argument = crate::kargs::get("loglevel");
+    let level = argument.map(|argument| argument.parse::<log::LevelFilter>());
+    log::set_max_level(match level {
+        Some(Ok(level)) => level,
+        _ => DEFAULT_LEVEL,
+    });
+    if let (Some(argument), Some(Err(_))) = (argument, level) {
+        log::warn!("Unknown log level {:?}, using {}", argument, DEFAULT_LEVEL);
+    }
 }
 
 const ERROR_STYLE: Style = Style::new().fg(Red).const_into_runtime_style();
@@ -31,25 +44,34 @@ impl Logger {
 
 impl log::Log for Logger {
     fn enabled(&self, metadata: &log::Metadata) -> bool {
-        metadata.level() <= log::Level::Debug
+        metadata.level() <= log::max_level()
     }
 
     fn log(&self, record: &log::Record) {
         if self.enabled(record.metadata()) {
             let level = record.level();
             let level_style = Logger::get_style(level);
+            let timestamp = log_buffer::timestamp();
+            log_buffer::push(level, timestamp, record.args());
+            let timestamp = Timestamp(timestamp);
+            // The console already prints to the serial port when headless.
+            let headless = super::is_headless();
 
             match record.level() {
                 log::Level::Debug | log::Level::Trace => {
-                    serial_println!(
-                        "[{}] {}, {}:{}",
-                        level.style_with(level_style),
-                        record.args(),
-                        record.file().unwrap_or("unknown"),
-                        record.line().unwrap_or(0)
-                    );
+                    if !headless {
+                        serial_println!(
+                            "[{}] [{}] {}, {}:{}",
+                            timestamp,
+                            level.style_with(level_style),
+                            record.args(),
+                            record.file().unwrap_or("unknown"),
+                            record.line().unwrap_or(0)
+                        );
+                    }
                     println!(
-                        "[{}] {}, {}:{}",
+                        "[{}] [{}] {}, {}:{}",
+                        timestamp,
                         level.style_with(level_style),
                         record.args(),
                         record.file().unwrap_or("unknown"),
@@ -57,8 +79,20 @@ impl log::Log for Logger {
                     );
                 }
                 _ => {
-                    serial_println!("[{}] {}", level.style_with(level_style), record.args());
-                    println!("[{}] {}", level.style_with(level_style), record.args());
+                    if !headless {
+                        serial_println!(
+                            "[{}] [{}] {}",
+                            timestamp,
+                            level.style_with(level_style),
+                            record.args()
+                        );
+                    }
+                    println!(
+                        "[{}] [{}] {}",
+                        timestamp,
+                        level.style_with(level_style),
+                        record.args()
+                    );
                 }
             }
         }
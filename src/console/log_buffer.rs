@@ -0,0 +1,139 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use log::{Level, LevelFilter};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::drivers::hpet;
+
+const LOG_BUFFER_SIZE: usize = 256;
+/// The bytes kept of a message, the rest is cut off.
+const MESSAGE_SIZE: usize = 160;
+
+/// A log record kept in the log buffer.
+#[derive(Clone, Copy)]
+pub struct LogEntry {
+    pub level: Level,
+    /// Nanoseconds since the HPET is enabled, 0 if it is not enabled yet.
+    pub timestamp: u64,
+    message: [u8; MESSAGE_SIZE],
+    len: usize,
+}
+
+impl LogEntry {
+    const EMPTY: Self = Self {
+        level: Level::Trace,
+        timestamp: 0,
+        message: [0; MESSAGE_SIZE],
+        len: 0,
+    };
+
+    /// Returns the message, cut off after `MESSAGE_SIZE` bytes.
+    pub fn message(&self) -> &str {
+        // `write_str` only cuts at char boundaries.
+        core::str::from_utf8(&self.message[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for LogEntry {
+    /// Appends what fits, once something is cut off nothing more is added.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.len == MESSAGE_SIZE {
+            return Ok(());
+        }
+        let mut len = s.len().min(MESSAGE_SIZE - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.message[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len = if len < s.len() { MESSAGE_SIZE } else { self.len + len };
+        Ok(())
+    }
+}
+
+/// The records in fixed slots, so logging never allocates, e.g. in an interrupt handler.
+struct LogBuffer {
+    entries: [LogEntry; LOG_BUFFER_SIZE],
+    /// The slot of the oldest record.
+    head: usize,
+    len: usize,
+}
+
+impl LogBuffer {
+    fn iter(&self) -> impl Iterator<Item = &LogEntry> {
+        (0..self.len).map(|i| &self.entries[(self.head + i) % LOG_BUFFER_SIZE])
+    }
+}
+
+static LOG_BUFFER: Mutex<LogBuffer> = Mutex::new(LogBuffer {
+    entries: [LogEntry::EMPTY; LOG_BUFFER_SIZE],
+    head: 0,
+    len: 0,
+});
+
+/// A timestamp in nanoseconds, displayed as seconds.milliseconds.
+#[derive(Debug, Clone, Copy)]
+pub struct Timestamp(pub u64);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let millis = self.0 / 1_000_000;
+        write!(f, "{:>5}.{:03}", millis / 1000, millis % 1000)
+    }
+}
+
+/// Returns the time for log records in nanoseconds.
+pub fn timestamp() -> u64 {
+    hpet::uptime_ns()
+}
+
+/// Adds a record to the log buffer, dropping the oldest one if the buffer is full.
+pub fn push(level: Level, timestamp: u64, args: &fmt::Arguments) {
+    let mut entry = LogEntry {
+        level,
+        timestamp,
+        ..LogEntry::EMPTY
+    };
+    let _ = entry.write_fmt(*args);
+
+    interrupts::without_interrupts(|| {
+        let mut buffer = LOG_BUFFER.lock();
+        if buffer.len == LOG_BUFFER_SIZE {
+            buffer.head = (buffer.head + 1) % LOG_BUFFER_SIZE;
+            buffer.len -= 1;
+        }
+        let slot = (buffer.head + buffer.len) % LOG_BUFFER_SIZE;
+        buffer.entries[slot] = entry;
+        buffer.len += 1;
+    });
+}
+
+/// Returns whether the buffer is locked, the next `push` would wait for it.
+pub(crate) fn is_locked() -> bool {
+    LOG_BUFFER.is_locked()
+}
+
+/// Returns the log records in the buffer, from the oldest to the newest.
+pub fn dmesg() -> Vec<String> {
+    interrupts::without_interrupts(|| {
+        LOG_BUFFER
+            .lock()
+            .iter()
+            .map(|entry| {
+                format!(
+                    "[{}] [{}] {}",
+                    Timestamp(entry.timestamp),
+                    entry.level,
+                    entry.message()
+                )
+            })
+            .collect()
+    })
+}
+
+/// Sets the maximum level of the log records to print and keep, same as `console::set_log_level`.
+pub fn set_level(level: LevelFilter) {
+    super::set_log_level(level);
+}
@@ -1,36 +1,211 @@
 use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use alloc::boxed::Box;
 use spin::{Lazy, Mutex};
-use tty::TTYDrawTarget;
+use tty::{TTYDrawTarget, TtyFont};
+use uart_16550::SerialPort;
 use x86_64::instructions::interrupts;
 
+use crate::arch::panic::PANICKED;
+use crate::arch::smp::cpuid_lapic_id;
+
 use crate::drivers::display::Display;
-use os_terminal::{font::{BitmapFont, TrueTypeFont}, Terminal};
+use os_terminal::Terminal;
 
+mod backend;
 mod log;
+pub mod log_buffer;
+pub mod psf;
 pub mod tty;
 
-pub static CONSOLE: Lazy<Mutex<Terminal<TTYDrawTarget>>> =
-    Lazy::new(|| Mutex::new(Terminal::new(TTYDrawTarget::new(0))));
+pub use backend::{ConsoleBackend, SerialBackend, TerminalBackend};
+pub use psf::{PsfError, PsfFont};
+
+/// The terminal drawing on the TTYs, used by `TerminalBackend`.
+pub static TERMINAL: Lazy<Mutex<Terminal<TTYDrawTarget>>> =
+    Lazy::new(|| Mutex::new(Terminal::new(TTYDrawTarget::new(tty::TERMINAL_TTY))));
+
+/// The backend `print!` and `println!` go to, selected in `init`.
+pub static CONSOLE: Mutex<Option<Box<dyn ConsoleBackend>>> = Mutex::new(None);
+
+/// The lapic id of the CPU writing to `CONSOLE` in `_print`, `NO_OWNER` if none.
+static CONSOLE_OWNER: AtomicU32 = AtomicU32::new(NO_OWNER);
+const NO_OWNER: u32 = u32::MAX;
+
+/// Whether the console is bound to the serial port because there is no frame buffer.
+static HEADLESS: AtomicBool = AtomicBool::new(false);
 
 pub fn init() {
+    if !Display::is_available() {
+        HEADLESS.store(true, Ordering::SeqCst);
+        set_backend(Box::new(SerialBackend));
+        log::init();
+        ::log::warn!("No frame buffer found, using the serial port as console");
+        return;
+    }
+
     tty::init();
+    TERMINAL.lock().set_font_manager(TtyFont::Bitmap.font_manager());
+    set_backend(Box::new(TerminalBackend));
     log::init();
-    CONSOLE.lock().set_font_manager(Box::new(BitmapFont{}));
 }
 
-/// Sets the font of the terminal on TTY0.
-pub fn set_font(size: f32,font: &'static [u8]) {
-    CONSOLE.lock().set_font_manager(Box::new(TrueTypeFont::new(size, font)));
+/// Sets the most verbose level which is logged, the records below it are neither printed nor kept.
+/// The initial level comes from the `loglevel` kernel argument, `info` without it.
+pub fn set_log_level(level: ::log::LevelFilter) {
+    ::log::set_max_level(level);
 }
 
-#[inline]
-pub fn _print(args: fmt::Arguments) {
+/// Returns whether a print would wait for a lock held elsewhere, e.g. by a CPU stuck printing.
+pub fn is_busy() -> bool {
+    CONSOLE.is_locked() || crate::drivers::serial::SERIAL.is_locked() || log_buffer::is_locked()
+}
+
+/// Replaces the backend of the console.
+pub fn set_backend(backend: Box<dyn ConsoleBackend>) {
+    interrupts::without_interrupts(|| {
+        *CONSOLE.lock() = Some(backend);
+    });
+}
+
+/// Returns whether the console is bound to the serial port instead of the TTYs.
+pub fn is_headless() -> bool {
+    HEADLESS.load(Ordering::Relaxed)
+}
+
+/// Reads a byte of console input from the backend, returns None if there is no input.
+pub fn read_input() -> Option<u8> {
+    interrupts::without_interrupts(|| CONSOLE.lock().as_mut()?.read_input())
+}
+
+/// Clears the console output.
+pub fn clear() {
+    interrupts::without_interrupts(|| {
+        if let Some(console) = CONSOLE.lock().as_mut() {
+            console.clear();
+        }
+    });
+}
+
+/// Sets a PSF1 or PSF2 bitmap font on the current TTY, e.g. one of the Linux console fonts.
+pub fn set_psf_font(font: &'static [u8]) -> Result<(), PsfError> {
+    PsfFont::parse(font)?;
+    if !is_headless() {
+        let _ = tty::set_tty_font(tty::CURRENT_TTY.load(Ordering::Relaxed), TtyFont::Psf(font));
+    }
+    Ok(())
+}
+
+/// Moves the cursor of the console to the top left corner.
+pub fn home() {
     interrupts::without_interrupts(|| {
-        CONSOLE.lock().write_fmt(args).unwrap();
+        if let Some(console) = CONSOLE.lock().as_mut() {
+            console.home();
+        }
     });
 }
 
+/// Clears the line of the console cursor and moves the cursor to its start.
+pub fn clear_line() {
+    interrupts::without_interrupts(|| {
+        if let Some(console) = CONSOLE.lock().as_mut() {
+            console.clear_line();
+        }
+    });
+}
+
+/// Sets the TrueType font of the current TTY, use `tty::set_font` for another TTY.
+pub fn set_font(size: f32, font: &'static [u8]) {
+    if is_headless() {
+        return;
+    }
+    let _ = tty::set_font(tty::CURRENT_TTY.load(Ordering::Relaxed), size, font);
+}
+
+/// Resizes all TTYs after a display mode change and lets the terminal pick up the new size.
+pub fn resize(width: usize, height: usize) {
+    if is_headless() {
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut terminal = TERMINAL.lock();
+        tty::resize_all(width, height);
+
+        // The terminal only recalculates its size when the font manager is set.
+        let current = tty::get_tty(tty::CURRENT_TTY.load(Ordering::Relaxed));
+        let font = current.read().font();
+        terminal.set_font_manager(font.font_manager());
+    });
+}
+
+/// The bytes `_print` writes with the interrupts disabled, before re-enabling them briefly.
+const PRINT_CHUNK_SIZE: usize = 256;
+
+/// Held by `_print` across the chunks of an output, so concurrent writers don't interleave.
+/// The interrupts stay enabled while it is held.
+static PRINT_LOCK: Mutex<()> = Mutex::new(());
+
+/// Prints to the console backend.
+///
+/// The output is formatted first, then written in chunks with the interrupts only disabled
+/// while a chunk is rendered. Called with the interrupts already disabled, e.g. from an
+/// interrupt handler, it writes everything at once, possibly between the chunks of another print.
+pub fn _print(args: fmt::Arguments) {
+    if !interrupts::are_enabled() {
+        write_console(|console| console.write_fmt(args));
+        return;
+    }
+
+    let output = alloc::fmt::format(args);
+    let _guard = PRINT_LOCK.lock();
+    let mut rest = output.as_str();
+    while !rest.is_empty() {
+        let (chunk, next) = split_chunk(rest, PRINT_CHUNK_SIZE);
+        interrupts::without_interrupts(|| write_console(|console| console.write_str(chunk)));
+        rest = next;
+    }
+}
+
+/// Runs `write` on the console backend, the interrupts must be disabled.
+///
+/// When the current CPU already holds the console, e.g. a fault while drawing, or after a
+/// panic when the holder may never release it, the output goes straight to COM1 instead.
+fn write_console(write: impl FnOnce(&mut dyn Write) -> fmt::Result) {
+    let cpu = cpuid_lapic_id();
+    let console = if CONSOLE_OWNER.load(Ordering::SeqCst) == cpu {
+        None
+    } else if PANICKED.load(Ordering::SeqCst) {
+        CONSOLE.try_lock()
+    } else {
+        Some(CONSOLE.lock())
+    };
+
+    let Some(mut console) = console else {
+        let _ = write(&mut unsafe { SerialPort::new(0x3f8) });
+        return;
+    };
+    if let Some(backend) = console.as_mut() {
+        let mut backend: &mut dyn ConsoleBackend = &mut **backend;
+        CONSOLE_OWNER.store(cpu, Ordering::SeqCst);
+        let _ = write(&mut backend);
+        CONSOLE_OWNER.store(NO_OWNER, Ordering::SeqCst);
+    }
+}
+
+/// Splits at most `size` bytes off the string, on a character boundary.
+/// `size` must be at least 4 so that any character fits.
+fn split_chunk(s: &str, size: usize) -> (&str, &str) {
+    if s.len() <= size {
+        return (s, "");
+    }
+    let mut index = size;
+    while !s.is_char_boundary(index) {
+        index -= 1;
+    }
+    s.split_at(index)
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => (
@@ -0,0 +1,221 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt::{self, Formatter};
+use os_terminal::font::{ContentInfo, FontManager, Rasterized};
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE_512: u8 = 0x01;
+const PSF1_MODE_HAS_TABLE: u8 = 0x02 | 0x04;
+const PSF1_SEPARATOR: u16 = 0xffff;
+const PSF1_START_SEQUENCE: u16 = 0xfffe;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HAS_TABLE: u32 = 0x01;
+const PSF2_SEPARATOR: u8 = 0xff;
+const PSF2_START_SEQUENCE: u8 = 0xfe;
+
+/// Errors that can occur when parsing a PSF font.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsfError {
+    /// The data is neither a PSF1 nor a PSF2 font.
+    BadMagic,
+    /// The header describes glyphs of no size.
+    BadHeader,
+    /// The data ends before the glyphs or the unicode table do.
+    Truncated,
+}
+
+impl fmt::Display for PsfError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not a PSF font"),
+            Self::BadHeader => write!(f, "invalid PSF header"),
+            Self::Truncated => write!(f, "truncated PSF font"),
+        }
+    }
+}
+
+/// A PC Screen Font, version 1 or 2, as the Linux console uses them.
+pub struct PsfFont {
+    width: usize,
+    height: usize,
+    /// The glyphs with a byte of coverage per pixel, as the terminal draws them.
+    glyphs: Vec<Vec<Vec<u8>>>,
+    /// The glyph of every character, for each of the bold and italic variants the terminal asks for.
+    /// os-terminal doesn't expose the character of a `ContentInfo`, so it is looked up whole.
+    map: BTreeMap<ContentInfo, usize>,
+    fallback: usize,
+}
+
+struct Layout<'a> {
+    width: usize,
+    height: usize,
+    count: usize,
+    glyph_size: usize,
+    glyphs: &'a [u8],
+    table: Option<&'a [u8]>,
+}
+
+impl PsfFont {
+    /// Parses a PSF1 or PSF2 font, with its unicode table if it has one.
+    /// Without a table the glyph at index `n` is the character `n`.
+    pub fn parse(data: &[u8]) -> Result<Self, PsfError> {
+        let (layout, characters) = if data.starts_with(&PSF2_MAGIC) {
+            let layout = Self::psf2_layout(data)?;
+            let characters = layout.table.map(|table| Self::psf2_table(table, layout.count));
+            (layout, characters)
+        } else if data.starts_with(&PSF1_MAGIC) {
+            let layout = Self::psf1_layout(data)?;
+            let characters = layout.table.map(|table| Self::psf1_table(table, layout.count));
+            (layout, characters)
+        } else {
+            return Err(PsfError::BadMagic);
+        };
+
+        let characters = characters.unwrap_or_else(|| {
+            (0..layout.count)
+                .filter_map(|index| Some((char::from_u32(index as u32)?, index)))
+                .collect()
+        });
+
+        let mut map = BTreeMap::new();
+        for (character, index) in characters {
+            for (bold, italic) in [(false, false), (true, false), (false, true), (true, true)] {
+                map.entry(ContentInfo::new(character, bold, italic))
+                    .or_insert(index);
+            }
+        }
+        let fallback = map
+            .get(&ContentInfo::new('?', false, false))
+            .copied()
+            .unwrap_or(0);
+
+        let row_size = layout.width.div_ceil(8);
+        let glyphs = layout
+            .glyphs
+            .chunks_exact(layout.glyph_size)
+            .take(layout.count)
+            .map(|glyph| {
+                (0..layout.height)
+                    .map(|y| {
+                        let row = &glyph[y * row_size..(y + 1) * row_size];
+                        (0..layout.width)
+                            .map(|x| match row[x / 8] & (0x80 >> (x % 8)) {
+                                0 => 0,
+                                _ => 0xff,
+                            })
+                            .collect()
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Ok(Self {
+            width: layout.width,
+            height: layout.height,
+            glyphs,
+            map,
+            fallback,
+        })
+    }
+
+    fn psf1_layout(data: &[u8]) -> Result<Layout<'_>, PsfError> {
+        let [_, _, mode, glyph_size, ..] = *data else {
+            return Err(PsfError::Truncated);
+        };
+        let glyph_size = glyph_size as usize;
+        if glyph_size == 0 {
+            return Err(PsfError::BadHeader);
+        }
+        let count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+
+        let glyphs_end = 4 + count * glyph_size;
+        let glyphs = data.get(4..glyphs_end).ok_or(PsfError::Truncated)?;
+        let table = (mode & PSF1_MODE_HAS_TABLE != 0).then(|| &data[glyphs_end..]);
+        Ok(Layout {
+            width: 8,
+            height: glyph_size,
+            count,
+            glyph_size,
+            glyphs,
+            table,
+        })
+    }
+
+    fn psf2_layout(data: &[u8]) -> Result<Layout<'_>, PsfError> {
+        let field = |index: usize| -> Result<usize, PsfError> {
+            let bytes = data.get(index * 4..index * 4 + 4).ok_or(PsfError::Truncated)?;
+            Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+        };
+        let (header_size, flags, count) = (field(2)?, field(3)? as u32, field(4)?);
+        let (glyph_size, height, width) = (field(5)?, field(6)?, field(7)?);
+        if count == 0 || width == 0 || height == 0 || glyph_size < width.div_ceil(8) * height {
+            return Err(PsfError::BadHeader);
+        }
+
+        let glyphs_end = count
+            .checked_mul(glyph_size)
+            .and_then(|size| size.checked_add(header_size))
+            .ok_or(PsfError::BadHeader)?;
+        let glyphs = data.get(header_size..glyphs_end).ok_or(PsfError::Truncated)?;
+        let table = (flags & PSF2_HAS_TABLE != 0).then(|| &data[glyphs_end..]);
+        Ok(Layout {
+            width,
+            height,
+            count,
+            glyph_size,
+            glyphs,
+            table,
+        })
+    }
+
+    /// Reads the UCS-2 characters of each glyph, the multi-character sequences are skipped.
+    fn psf1_table(table: &[u8], count: usize) -> Vec<(char, usize)> {
+        let mut characters = Vec::new();
+        let mut values = table
+            .chunks_exact(2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+
+        for index in 0..count {
+            let mut in_sequence = false;
+            for value in values.by_ref() {
+                match value {
+                    PSF1_SEPARATOR => break,
+                    PSF1_START_SEQUENCE => in_sequence = true,
+                    _ if in_sequence => {}
+                    _ => characters.extend(char::from_u32(value as u32).map(|c| (c, index))),
+                }
+            }
+        }
+        characters
+    }
+
+    /// Reads the UTF-8 characters of each glyph, the multi-character sequences are skipped.
+    fn psf2_table(table: &[u8], count: usize) -> Vec<(char, usize)> {
+        let mut characters = Vec::new();
+        let mut entries = table.split(|&byte| byte == PSF2_SEPARATOR);
+
+        for (index, entry) in (0..count).zip(entries.by_ref()) {
+            let single = entry
+                .split(|&byte| byte == PSF2_START_SEQUENCE)
+                .next()
+                .unwrap_or_default();
+            let Ok(single) = core::str::from_utf8(single) else {
+                continue;
+            };
+            characters.extend(single.chars().map(|c| (c, index)));
+        }
+        characters
+    }
+}
+
+impl FontManager for PsfFont {
+    fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    fn rasterize(&mut self, info: ContentInfo) -> Rasterized<'_> {
+        let index = self.map.get(&info).copied().unwrap_or(self.fallback);
+        Rasterized::Owned(self.glyphs[index].clone())
+    }
+}
@@ -1,152 +1,228 @@
 use core::{
-    alloc::Layout,
-    slice::from_raw_parts_mut,
+    fmt::{self, Formatter},
     sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 
-use alloc::{alloc::alloc, sync::Arc, vec::Vec};
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use os_terminal::font::{BitmapFont, FontManager, TrueTypeFont};
 use os_terminal::DrawTarget;
 use spin::{Mutex, RwLock};
-use x86_64::{
-    structures::paging::{Page, PageTableFlags},
-    VirtAddr,
-};
 
-use crate::{
-    drivers::display::Display,
-    memory::{FRAME_ALLOCATOR, KERNEL_PAGE_TABLE},
-};
+use super::psf::PsfFont;
+use crate::drivers::display::Display;
+
+/// The font a TTY is rendered with.
+#[derive(Debug, Clone, Copy)]
+pub enum TtyFont {
+    /// The built-in Noto Sans Mono bitmap font.
+    Bitmap,
+    TrueType { size: f32, data: &'static [u8] },
+    /// A PSF1 or PSF2 font, which `PsfFont::parse` accepted.
+    Psf(&'static [u8]),
+}
+
+impl TtyFont {
+    /// Creates the font manager for the terminal.
+    pub fn font_manager(&self) -> Box<dyn FontManager> {
+        match *self {
+            Self::Bitmap => Box::new(BitmapFont {}),
+            Self::TrueType { size, data } => Box::new(TrueTypeFont::new(size, data)),
+            Self::Psf(data) => Box::new(PsfFont::parse(data).expect("Invalid PSF font")),
+        }
+    }
+}
 
 pub struct TTY {
-    buffer: &'static mut [u8],
+    buffer: Vec<u8>,
+    /// The frame buffer while this is the current TTY, the pixels are drawn there directly.
+    vram: Option<&'static mut [u8]>,
     width: usize,
     height: usize,
+    /// The bytes per row, the same as the frame buffer's so that the vram can be copied in one go.
+    pitch: usize,
+    font: TtyFont,
 }
 
 impl TTY {
     pub fn new(width: usize, height: usize) -> Self {
+        Self::with_pitch(width, height, width * 4)
+    }
+
+    /// Creates a TTY whose rows are `pitch` bytes apart, at least `width * 4`.
+    pub fn with_pitch(width: usize, height: usize, pitch: usize) -> Self {
+        let pitch = pitch.max(width * 4);
         Self {
-            buffer: unsafe {
-                let addr = alloc(Layout::from_size_align(width * height * 4, 4096).unwrap());
-                from_raw_parts_mut(addr, width * height * 4)
-            },
+            buffer: vec![0; pitch * height],
+            vram: None,
             width,
             height,
+            pitch,
+            font: TtyFont::Bitmap,
+        }
+    }
+
+    pub fn font(&self) -> TtyFont {
+        self.font
+    }
+
+    /// Returns the pixels the TTY draws to, the vram if it is the current TTY.
+    fn pixels(&mut self) -> &mut [u8] {
+        match self.vram.as_deref_mut() {
+            Some(vram) => vram,
+            None => &mut self.buffer,
         }
     }
 
     pub fn write_pixel(&mut self, x: usize, y: usize, pixel: [u8; 4]) {
-        let pos = self.width * y + x;
-        let pos = pos * 4;
+        let pos = self.pitch * y + x * 4;
         let [r, g, b, a] = pixel;
         let pixel = [b, g, r, a];
-        self.buffer[pos..pos + 4].copy_from_slice(&pixel);
+        self.pixels()[pos..pos + 4].copy_from_slice(&pixel);
     }
 
     pub fn read_pixel(&mut self, x: usize, y: usize) -> [u8; 4] {
-        let pos = self.width * y + x;
-        let pos = pos * 4;
-        let [b, g, r, a] = &self.buffer[pos..pos + 4] else {
+        let pos = self.pitch * y + x * 4;
+        let [b, g, r, a] = self.pixels()[pos..pos + 4] else {
             unreachable!()
         };
-        [*r, *g, *b, *a]
+        [r, g, b, a]
     }
 
-    pub fn buffer(&self) -> (VirtAddr, usize) {
-        (VirtAddr::from_ptr(self.buffer.as_ptr()), self.buffer.len())
+    /// Reallocates the buffer for the new size and pitch, keeping the content that still fits.
+    /// The TTY must not be attached to the vram.
+    pub fn resize(&mut self, width: usize, height: usize, pitch: usize) {
+        let mut new = TTY::with_pitch(width, height, pitch);
+        new.font = self.font;
+        let copy_width = self.width.min(width) * 4;
+
+        for y in 0..self.height.min(height) {
+            let old_pos = self.pitch * y;
+            let new_pos = new.pitch * y;
+            new.buffer[new_pos..new_pos + copy_width]
+                .copy_from_slice(&self.buffer[old_pos..old_pos + copy_width]);
+        }
+
+        core::mem::swap(self, &mut new);
+    }
+
+    /// Copies the content into the vram and draws there from now on.
+    fn attach_to_vram(&mut self, vram: &'static mut [u8]) {
+        let length = vram.len().min(self.buffer.len());
+        vram[..length].copy_from_slice(&self.buffer[..length]);
+        self.vram = Some(vram);
+    }
+
+    /// Saves the vram content into the buffer and draws there from now on.
+    fn detach_from_vram(&mut self) {
+        if let Some(vram) = self.vram.take() {
+            let length = vram.len().min(self.buffer.len());
+            self.buffer[..length].copy_from_slice(&vram[..length]);
+        }
     }
 }
 
-pub static TTYS: Mutex<Vec<Arc<RwLock<TTY>>>> = Mutex::new(Vec::new());
+/// The TTYs indexed by id, destroyed TTYs leave a free slot behind.
+pub static TTYS: Mutex<Vec<Option<Arc<RwLock<TTY>>>>> = Mutex::new(Vec::new());
 pub static CURRENT_TTY: AtomicUsize = AtomicUsize::new(0);
+/// The TTY `TERMINAL` draws on, it is never destroyed.
+pub const TERMINAL_TTY: usize = 0;
 pub static INIT: AtomicBool = AtomicBool::new(false);
 
+/// Errors that can occur when using the TTYs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtyError {
+    /// There is no TTY with the id.
+    InvalidId(usize),
+    /// The terminal draws on the TTY, so it can't be destroyed.
+    InUse(usize),
+}
+
+impl fmt::Display for TtyError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidId(id) => write!(f, "TTY {} does not exist", id),
+            Self::InUse(id) => write!(f, "TTY {} is in use", id),
+        }
+    }
+}
+
 /// Switches to the specified TTY.
-pub fn switch_to(tty: usize) {
-    x86_64::instructions::interrupts::disable();
-    let init = INIT.load(Ordering::SeqCst);
+/// Returns an error and keeps the current TTY if the TTY does not exist.
+///
+/// The frame buffer stays mapped, the content of the old TTY is copied out of it and the new one copied in.
+pub fn switch_to(tty: usize) -> Result<(), TtyError> {
+    let font = x86_64::instructions::interrupts::without_interrupts(|| {
+        let ttys = TTYS.lock();
+        switch_locked(&ttys, tty)
+    })?;
+
+    if INIT.load(Ordering::SeqCst) {
+        apply_font(font);
+    }
+    Ok(())
+}
 
-    let mut kernel_page_table = KERNEL_PAGE_TABLE.lock();
-    let mut frame_allocator = FRAME_ALLOCATOR.lock();
-    let ttys = TTYS.lock();
-    let frame_buffer = Display::new().get_frame_buffer();
+/// Does the switch of `switch_to` with `TTYS` locked, returns the font of the new TTY.
+/// The TTY is looked up under the lock, so a `destroy` can't free it in between.
+fn switch_locked(ttys: &[Option<Arc<RwLock<TTY>>>], tty: usize) -> Result<TtyFont, TtyError> {
+    let Some(new_tty) = ttys.get(tty).and_then(Option::as_ref) else {
+        return Err(TtyError::InvalidId(tty));
+    };
 
-    if init {
+    if INIT.load(Ordering::SeqCst) {
         let last_tty_id = CURRENT_TTY.load(Ordering::Relaxed);
-        let last_tty = ttys[last_tty_id].clone();
-
-        let (buffer_ptr, buffer_len) = last_tty.read().buffer();
-        
-        for page_cnt in 0..buffer_len/4096 {
-            use x86_64::structures::paging::FrameAllocator;
-            use x86_64::structures::paging::Mapper;
-
-            let ptr = buffer_ptr + page_cnt as u64 * 4096;
-
-            unsafe {
-                let frame = frame_allocator.allocate_frame().unwrap();
-
-                let (_, flush) = kernel_page_table
-                    .unmap(Page::containing_address(ptr))
-                    .unwrap();
-                flush.flush();
-
-                kernel_page_table
-                    .map_to(
-                        Page::containing_address(ptr),
-                        frame,
-                        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                        &mut *frame_allocator,
-                    )
-                    .unwrap()
-                    .flush();
-            }
-        }
-
-        last_tty.write().buffer.copy_from_slice(frame_buffer);
+        ttys[last_tty_id].as_ref().unwrap().write().detach_from_vram();
     }
 
     CURRENT_TTY.store(tty, Ordering::Relaxed);
 
-    let vram = frame_buffer;
-    let mut vram_ptr = VirtAddr::from_ptr(vram.as_ptr());
-
-    let tty = ttys[tty].clone();
-    let (buffer_ptr, buffer_len) = tty.read().buffer();
-
-    vram.copy_from_slice(tty.read().buffer);
-
-    for ptr in buffer_ptr..buffer_ptr + buffer_len as u64 {
-        use x86_64::structures::paging::FrameDeallocator;
-        use x86_64::structures::paging::Mapper;
-
-        unsafe {
-            let frame = kernel_page_table
-                .translate_page(Page::containing_address(vram_ptr))
-                .unwrap();
-            let (maped_frame, flush) = kernel_page_table
-                .unmap(Page::containing_address(ptr))
-                .unwrap();
-
-            frame_allocator.deallocate_frame(maped_frame);
-            flush.flush();
-
-            kernel_page_table
-                .map_to(
-                    Page::containing_address(ptr),
-                    frame,
-                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                    &mut *frame_allocator,
-                )
-                .unwrap()
-                .flush();
-        }
-        vram_ptr += 1;
-    }
+    let frame_buffer = Display::new().get_frame_buffer();
+    let mut new_tty = new_tty.write();
+    new_tty.attach_to_vram(frame_buffer);
+    Ok(new_tty.font)
+}
+
+/// Sets the TrueType font of a TTY, it is used whenever the TTY is the current one.
+pub fn set_font(id: usize, size: f32, font: &'static [u8]) -> Result<(), TtyError> {
+    set_tty_font(id, TtyFont::TrueType { size, data: font })
+}
 
-    if init {
-        x86_64::instructions::interrupts::enable();
+/// Sets the font of a TTY, applying it right away if the TTY is the current one.
+pub fn set_tty_font(id: usize, font: TtyFont) -> Result<(), TtyError> {
+    let tty = try_get_tty(id).ok_or(TtyError::InvalidId(id))?;
+    tty.write().font = font;
+    if CURRENT_TTY.load(Ordering::Relaxed) == id {
+        apply_font(font);
     }
+    Ok(())
+}
+
+/// Hands the font to the terminal, which lays out its cells again for the new glyph size.
+/// os-terminal keeps a single font manager for every terminal, so it follows the current TTY.
+fn apply_font(font: TtyFont) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        super::TERMINAL.lock().set_font_manager(font.font_manager());
+    });
+}
+
+/// Resizes all TTYs, the current TTY is moved off the vram while it is resized.
+/// Call `console::resize` instead so that the terminal picks up the new size.
+pub fn resize_all(width: usize, height: usize) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let ttys = TTYS.lock();
+        let current = ttys[CURRENT_TTY.load(Ordering::Relaxed)].clone().unwrap();
+
+        current.write().detach_from_vram();
+
+        let pitch = Display::new().info().pitch;
+        for tty in ttys.iter().flatten() {
+            tty.write().resize(width, height, pitch);
+        }
+
+        current
+            .write()
+            .attach_to_vram(Display::new().get_frame_buffer());
+    });
 }
 
 pub struct TTYDrawTarget {
@@ -174,17 +250,79 @@ impl DrawTarget for TTYDrawTarget {
 }
 
 pub fn init() {
-    let (width, height) = super::Display::new().size();
+    let info = Display::new().info();
     let mut ttys = TTYS.lock();
     for _ in 0..6 {
-        ttys.push(Arc::new(RwLock::new(TTY::new(width, height))));
+        let tty = TTY::with_pitch(info.width, info.height, info.pitch);
+        ttys.push(Some(Arc::new(RwLock::new(tty))));
     }
     drop(ttys);
-    switch_to(0);
+    switch_to(0).unwrap();
     INIT.store(true, Ordering::SeqCst);
 }
 
-/// Gets the current TTY.
+/// Gets the TTY with the id.
+/// Panics if the TTY does not exist, use `try_get_tty` if the id may be invalid.
 pub fn get_tty(id: usize) -> Arc<RwLock<TTY>> {
-    return TTYS.lock()[id].clone();
+    return TTYS.lock()[id].clone().unwrap();
+}
+
+/// Gets the TTY with the id, returns None if the TTY does not exist.
+pub fn try_get_tty(id: usize) -> Option<Arc<RwLock<TTY>>> {
+    TTYS.lock().get(id).cloned().flatten()
+}
+
+/// Creates a new TTY sized to the display and returns its id.
+/// The slot of a destroyed TTY is reused if there is one.
+pub fn create() -> usize {
+    let info = Display::new().info();
+    let tty = TTY::with_pitch(info.width, info.height, info.pitch);
+    let tty = Some(Arc::new(RwLock::new(tty)));
+
+    let mut ttys = TTYS.lock();
+    match ttys.iter().position(|tty| tty.is_none()) {
+        Some(id) => {
+            ttys[id] = tty;
+            id
+        }
+        None => {
+            ttys.push(tty);
+            ttys.len() - 1
+        }
+    }
+}
+
+/// Destroys the TTY with the id and frees its buffer.
+/// If it is the current TTY, the last other live TTY becomes current first.
+/// The TTY the terminal draws on can't be destroyed.
+pub fn destroy(id: usize) -> Result<(), TtyError> {
+    // `switch_to` changes the current TTY with the lock held, so it can't become current meanwhile.
+    // A print from an interrupt handler takes the lock as well.
+    let font = x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut ttys = TTYS.lock();
+        if ttys.get(id).map_or(true, Option::is_none) {
+            return Err(TtyError::InvalidId(id));
+        }
+        if id == TERMINAL_TTY {
+            return Err(TtyError::InUse(id));
+        }
+
+        let mut font = None;
+        if CURRENT_TTY.load(Ordering::Relaxed) == id {
+            let other = (0..ttys.len())
+                .rev()
+                .find(|&other| other != id && ttys[other].is_some())
+                .unwrap_or(TERMINAL_TTY);
+            font = Some(switch_locked(&ttys, other)?);
+        }
+
+        // The buffer is freed once the last reference to the TTY is dropped.
+        ttys[id] = None;
+        Ok(font)
+    })?;
+
+    if let Some(font) = font.filter(|_| INIT.load(Ordering::SeqCst)) {
+        apply_font(font);
+    }
+    Ok(())
 }
@@ -11,8 +11,9 @@ impl Bitmap {
         Self { inner }
     }
 
+    /// Returns the number of bits.
     pub fn len(&self) -> usize {
-        self.inner.len()
+        self.inner.len() * 8
     }
 
     pub fn get(&self, index: usize) -> bool {
@@ -1 +1,3 @@
-pub mod bitmap;
\ No newline at end of file
+pub mod bitmap;
+pub mod ring;
+pub mod slab;
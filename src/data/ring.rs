@@ -0,0 +1,95 @@
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A wait-free ring buffer with one producer and one consumer, e.g. an interrupt handler and a thread.
+///
+/// The ring is shared by reference, so nothing stops two contexts from calling `push` at once,
+/// both are unsafe and their callers make sure there is one producer and one consumer at a time.
+/// The capacity `N` must be a power of two.
+pub struct SpscRing<T, const N: usize> {
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+    /// The count of popped items, only written by the consumer.
+    head: AtomicUsize,
+    /// The count of pushed items, only written by the producer.
+    tail: AtomicUsize,
+}
+
+unsafe impl<T: Send, const N: usize> Sync for SpscRing<T, N> {}
+
+impl<T, const N: usize> SpscRing<T, N> {
+    pub const fn new() -> Self {
+        assert!(N.is_power_of_two(), "The capacity must be a power of two");
+        Self {
+            slots: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pushes the value, returns false and drops it if the ring is full.
+    ///
+    /// # Safety
+    ///
+    /// No other `push` on this ring may run at the same time, e.g. on another CPU.
+    pub unsafe fn push(&self, value: T) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == N {
+            return false;
+        }
+
+        unsafe { (*self.slots[tail % N].get()).write(value) };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Pops the oldest value, returns None if the ring is empty.
+    ///
+    /// # Safety
+    ///
+    /// No other `pop` on this ring may run at the same time, or a value is read twice.
+    pub unsafe fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+
+        let value = unsafe { (*self.slots[head % N].get()).assume_init_read() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    /// Returns the number of values in the ring, it may change right after.
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Acquire);
+        tail.wrapping_sub(head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == N
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<T, const N: usize> Default for SpscRing<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for SpscRing<T, N> {
+    fn drop(&mut self) {
+        // `&mut self` makes this the only consumer.
+        while unsafe { self.pop() }.is_some() {}
+    }
+}
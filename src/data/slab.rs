@@ -0,0 +1,99 @@
+use alloc::boxed::Box;
+
+/// A handle to an object in a `Slab`, it is the index of the slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SlabHandle(usize);
+
+impl SlabHandle {
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+enum Slot<T> {
+    Free { next: Option<usize> },
+    Used(T),
+}
+
+/// A pool of fixed-size objects, allocated once with a fixed capacity.
+/// Allocating and freeing are O(1), the free slots form a list of indices.
+pub struct Slab<T> {
+    slots: Box<[Slot<T>]>,
+    free_head: Option<usize>,
+    len: usize,
+}
+
+impl<T> Slab<T> {
+    /// Creates a slab with room for `capacity` objects.
+    pub fn new(capacity: usize) -> Self {
+        let slots = (0..capacity)
+            .map(|index| Slot::Free {
+                next: (index + 1 < capacity).then_some(index + 1),
+            })
+            .collect();
+
+        Self {
+            slots,
+            free_head: (capacity > 0).then_some(0),
+            len: 0,
+        }
+    }
+
+    /// Moves the value into a free slot, returns None if the slab is full.
+    pub fn alloc(&mut self, value: T) -> Option<SlabHandle> {
+        let index = self.free_head?;
+        let Slot::Free { next } = self.slots[index] else {
+            unreachable!("The free list points to a used slot");
+        };
+        self.free_head = next;
+        self.slots[index] = Slot::Used(value);
+        self.len += 1;
+        Some(SlabHandle(index))
+    }
+
+    /// Frees the slot and returns its value, returns None if the slot isn't allocated.
+    pub fn free(&mut self, handle: SlabHandle) -> Option<T> {
+        let slot = self.slots.get_mut(handle.0)?;
+        if let Slot::Free { .. } = slot {
+            return None;
+        }
+        let slot = core::mem::replace(slot, Slot::Free { next: self.free_head });
+        self.free_head = Some(handle.0);
+        self.len -= 1;
+        match slot {
+            Slot::Used(value) => Some(value),
+            Slot::Free { .. } => unreachable!(),
+        }
+    }
+
+    pub fn get(&self, handle: SlabHandle) -> Option<&T> {
+        match self.slots.get(handle.0)? {
+            Slot::Used(value) => Some(value),
+            Slot::Free { .. } => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, handle: SlabHandle) -> Option<&mut T> {
+        match self.slots.get_mut(handle.0)? {
+            Slot::Used(value) => Some(value),
+            Slot::Free { .. } => None,
+        }
+    }
+
+    /// Returns the number of allocated objects.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.free_head.is_none()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+}
@@ -1,5 +1,9 @@
+use core::ops::Range;
 use core::slice::from_raw_parts_mut;
 use limine::request::FramebufferRequest;
+use x86_64::VirtAddr;
+
+use crate::memory::convert_virtual_to_physical;
 
 use os_terminal::DrawTarget;
 
@@ -7,6 +11,20 @@ use os_terminal::DrawTarget;
 #[link_section = ".requests"]
 static FRAMEBUFFER_REQUEST: FramebufferRequest = FramebufferRequest::new();
 
+/// Returns the physical ranges of the frame buffers Limine gives to the kernel.
+/// It doesn't allocate, so the frame allocator can read it before there is a heap.
+pub fn framebuffer_ranges() -> impl Iterator<Item = Range<u64>> {
+    FRAMEBUFFER_REQUEST
+        .get_response()
+        .into_iter()
+        .flat_map(|response| response.framebuffers())
+        .map(|frame_buffer| {
+            let start = convert_virtual_to_physical(VirtAddr::from_ptr(frame_buffer.addr()));
+            let start = start.as_u64();
+            start..start + frame_buffer.pitch() * frame_buffer.height()
+        })
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum PixelFormat {
     Rgb,
@@ -15,23 +33,54 @@ pub enum PixelFormat {
     Unknown,
 }
 
+/// The geometry and the pixel layout of the frame buffer, as Limine reports them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramebufferInfo {
+    pub width: usize,
+    pub height: usize,
+    /// The bytes between the starts of two rows, it may be more than `width * bpp / 8`.
+    pub pitch: usize,
+    pub bpp: u16,
+    pub red_mask_size: u8,
+    pub red_mask_shift: u8,
+    pub green_mask_size: u8,
+    pub green_mask_shift: u8,
+    pub blue_mask_size: u8,
+    pub blue_mask_shift: u8,
+}
+
 pub struct Display {
     buffer: &'static mut [u8],
-    width: usize,
-    height: usize,
-    stride: usize,
+    info: FramebufferInfo,
     bytes_per_pixel: usize,
     pixel_format: PixelFormat,
 }
 
 impl Display {
+    /// Returns whether Limine gives the kernel a usable frame buffer.
+    pub fn is_available() -> bool {
+        FRAMEBUFFER_REQUEST
+            .get_response()
+            .is_some_and(|response| response.framebuffers().next().is_some())
+    }
+
     /// Creates a new `Display`
     pub fn new() -> Self {
         let response = FRAMEBUFFER_REQUEST.get_response().unwrap();
         let frame_buffer = response.framebuffers().next().take().unwrap();
 
-        let width = frame_buffer.width() as _;
-        let height = frame_buffer.height() as _;
+        let info = FramebufferInfo {
+            width: frame_buffer.width() as _,
+            height: frame_buffer.height() as _,
+            pitch: frame_buffer.pitch() as _,
+            bpp: frame_buffer.bpp(),
+            red_mask_size: frame_buffer.red_mask_size(),
+            red_mask_shift: frame_buffer.red_mask_shift(),
+            green_mask_size: frame_buffer.green_mask_size(),
+            green_mask_shift: frame_buffer.green_mask_shift(),
+            blue_mask_size: frame_buffer.blue_mask_size(),
+            blue_mask_shift: frame_buffer.blue_mask_shift(),
+        };
 
         let pixel_format = match (
             frame_buffer.red_mask_shift(),
@@ -44,24 +93,23 @@ impl Display {
             _ => PixelFormat::Unknown,
         };
 
-        let pitch = frame_buffer.pitch() as usize;
-        let bpp = frame_buffer.bpp() as usize;
-        let stride = (pitch / 4) as _;
-        let bytes_per_pixel = (bpp / 8) as _;
-
-        let buffer_size = stride * height * bytes_per_pixel;
+        let bytes_per_pixel = info.bpp as usize / 8;
+        let buffer_size = info.pitch * info.height;
         let buffer = unsafe { from_raw_parts_mut(frame_buffer.addr(), buffer_size) };
 
         Self {
             buffer,
-            width,
-            height,
-            stride,
+            info,
             bytes_per_pixel,
             pixel_format,
         }
     }
 
+    /// Returns the geometry and the pixel layout of the frame buffer.
+    pub fn info(&self) -> FramebufferInfo {
+        self.info
+    }
+
     /// Returns a mutable reference to the frame buffer which Limine gives to the kernel.
     pub fn get_frame_buffer(&self) -> &'static mut [u8] {
         unsafe { from_raw_parts_mut(self.buffer.as_ptr() as *mut u8, self.buffer.len()) }
@@ -70,11 +118,11 @@ impl Display {
 
 impl DrawTarget for Display {
     fn size(&self) -> (usize, usize) {
-        (self.width, self.height)
+        (self.info.width, self.info.height)
     }
 
     fn draw_pixel(&mut self, x: usize, y: usize, color: (u8, u8, u8)) {
-        let byte_offset = (y * self.stride + x) * self.bytes_per_pixel;
+        let byte_offset = y * self.info.pitch + x * self.bytes_per_pixel;
         let write_range = byte_offset..(byte_offset + self.bytes_per_pixel);
 
         let color = match self.pixel_format {
@@ -87,3 +135,92 @@ impl DrawTarget for Display {
         self.buffer[write_range].copy_from_slice(&color[..self.bytes_per_pixel]);
     }
 }
+
+/// Draws shapes and images on the frame buffer, everything is clipped to the display.
+///
+/// It draws straight into the vram, so the current TTY overwrites it when the terminal redraws.
+pub struct Canvas {
+    display: Display,
+}
+
+impl Canvas {
+    pub fn new() -> Self {
+        Self {
+            display: Display::new(),
+        }
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        self.display.size()
+    }
+
+    /// Draws a pixel, signed so that lines can start off the display.
+    pub fn draw_pixel(&mut self, x: isize, y: isize, color: (u8, u8, u8)) {
+        let (width, height) = self.size();
+        if (0..width as isize).contains(&x) && (0..height as isize).contains(&y) {
+            self.display.draw_pixel(x as usize, y as usize, color);
+        }
+    }
+
+    /// Fills the rectangle at `(x, y)` of `width` by `height` pixels.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: (u8, u8, u8)) {
+        let (display_width, display_height) = self.size();
+        let x_end = x.saturating_add(width).min(display_width);
+        let y_end = y.saturating_add(height).min(display_height);
+
+        for y in y..y_end {
+            for x in x..x_end {
+                self.display.draw_pixel(x, y, color);
+            }
+        }
+    }
+
+    /// Draws a line from `start` to `end` included, with Bresenham's algorithm.
+    pub fn draw_line(&mut self, start: (isize, isize), end: (isize, isize), color: (u8, u8, u8)) {
+        let (mut x, mut y) = start;
+        let dx = (end.0 - x).abs();
+        let dy = -(end.1 - y).abs();
+        let step_x = if x < end.0 { 1 } else { -1 };
+        let step_y = if y < end.1 { 1 } else { -1 };
+        let mut error = dx + dy;
+
+        loop {
+            self.draw_pixel(x, y, color);
+            if (x, y) == end {
+                break;
+            }
+            let double_error = 2 * error;
+            if double_error >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if double_error <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Copies an image of `width` by `height` pixels to `(x, y)`.
+    /// The image is packed RGB888 rows, the pixels outside the display are skipped.
+    pub fn blit(&mut self, x: usize, y: usize, source: &[u8], width: usize, height: usize) {
+        assert!(source.len() >= width * height * 3, "The image is smaller than its size");
+        let (display_width, display_height) = self.size();
+        let copy_width = width.min(display_width.saturating_sub(x));
+        let copy_height = height.min(display_height.saturating_sub(y));
+
+        for row in 0..copy_height {
+            for column in 0..copy_width {
+                let offset = (row * width + column) * 3;
+                let color = (source[offset], source[offset + 1], source[offset + 2]);
+                self.display.draw_pixel(x + column, y + row, color);
+            }
+        }
+    }
+}
+
+impl Default for Canvas {
+    fn default() -> Self {
+        Self::new()
+    }
+}
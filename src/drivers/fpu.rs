@@ -6,6 +6,21 @@
 
 // 还是来自rCore的FPU寄存器切换代码
 
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+/// Enables the FPU and SSE on the current CPU, so `fxsave` and `fxrstor` cover the XMM registers.
+/// Every CPU calls it before it runs a thread.
+pub fn init() {
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+        core::arch::asm!("fninit", options(nomem, nostack));
+    }
+}
+
 // state saved by fxsave64
 // 512 bytes
 // https://www.felixcloutier.com/x86/fxsave#tbl-3-47
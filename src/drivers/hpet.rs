@@ -1,4 +1,4 @@
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use core::{cell::UnsafeCell, ptr};
 use x86_64::PhysAddr;
 
@@ -8,6 +8,34 @@ use crate::memory::convert_physical_to_virtual;
 pub static HPET: Hpet = Hpet::uninit();
 pub static HPET_INIT: AtomicBool = AtomicBool::new(false);
 
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_64BIT_CAPABLE: u64 = 1 << 5;
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0b11111 << TIMER_ROUTE_SHIFT;
+
+/// The counter value when the HPET was enabled.
+static BOOT_COUNTER: AtomicU64 = AtomicU64::new(0);
+/// The last counter value extended to 64 bits, for HPETs with a 32-bit counter.
+static EXTENDED_COUNTER: AtomicU64 = AtomicU64::new(0);
+/// The comparators handed out by `allocate_comparator`, bit `n` is the comparator `n`.
+/// An HPET has at most 32 of them.
+static ALLOCATED_COMPARATORS: AtomicU32 = AtomicU32::new(0);
+
+/// What a comparator of the HPET can do, see `comparator_caps`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComparatorCaps {
+    /// Whether it can fire periodically.
+    pub periodic: bool,
+    /// Whether its comparator is 64 bits wide, otherwise it is 32 bits.
+    pub is_64bit: bool,
+    /// The IOAPIC inputs it can be routed to, bit `n` is the GSI `n`.
+    pub routes: u32,
+}
+
 pub fn init() {
     let acpi = ACPI.try_get().unwrap();
     let physical_address = PhysAddr::new(acpi.hpet_info.base_address as u64);
@@ -15,13 +43,96 @@ pub fn init() {
 
     HPET.init(virtual_address.as_u64());
     HPET.enable_counter();
+    BOOT_COUNTER.store(HPET.get_monotonic_counter(), Ordering::SeqCst);
 
     log::debug!("HPET clock speed: {} femto seconds", HPET.clock_speed());
     log::debug!("HPET timers: {} available", HPET.timers_count());
+    log::debug!("HPET counter: {} bits", if HPET.is_64bit() { 64 } else { 32 });
 
     HPET_INIT.store(true, Ordering::SeqCst);
 }
 
+/// Returns the number of comparators of the HPET, 0 before it is initialized.
+pub fn num_comparators() -> usize {
+    if !HPET_INIT.load(Ordering::SeqCst) {
+        return 0;
+    }
+    HPET.timers_count() as usize
+}
+
+/// Returns the capabilities of the comparator `n`, None if there is no such comparator.
+pub fn comparator_caps(n: usize) -> Option<ComparatorCaps> {
+    if n >= num_comparators() {
+        return None;
+    }
+    let timer = n as u32;
+    Some(ComparatorCaps {
+        periodic: HPET.timer_periodic_capable(timer),
+        is_64bit: HPET.timer_64bit_capable(timer),
+        routes: HPET.timer_route_capability(timer),
+    })
+}
+
+/// Reserves a free comparator for the caller, so two timer users never program the same one.
+/// Returns None if all of them are taken.
+pub fn allocate_comparator() -> Option<usize> {
+    allocate_comparator_where(|_| true)
+}
+
+/// Reserves the first free comparator whose capabilities `filter` accepts.
+pub fn allocate_comparator_where(filter: impl Fn(ComparatorCaps) -> bool) -> Option<usize> {
+    let count = num_comparators();
+    let mut allocated = None;
+    ALLOCATED_COMPARATORS
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |mask| {
+            allocated = (0..count)
+                .find(|&n| mask & (1 << n) == 0 && comparator_caps(n).is_some_and(&filter));
+            allocated.map(|n| mask | (1 << n))
+        })
+        .ok()?;
+    allocated
+}
+
+/// Stops the comparator `n` and makes it available to `allocate_comparator` again.
+pub fn free_comparator(n: usize) {
+    if n >= num_comparators() {
+        return;
+    }
+    HPET.stop_timer(n as u32);
+    ALLOCATED_COMPARATORS.fetch_and(!(1 << n), Ordering::SeqCst);
+}
+
+/// Returns the nanoseconds since the HPET was enabled, 0 before that.
+/// With a 32-bit counter this must be called at least once per counter wraparound.
+pub fn uptime_ns() -> u64 {
+    if !HPET_INIT.load(Ordering::SeqCst) {
+        return 0;
+    }
+    let ticks = HPET.get_monotonic_counter() - BOOT_COUNTER.load(Ordering::Relaxed);
+    ticks_to_ns(ticks, HPET.clock_speed())
+}
+
+/// Returns the milliseconds since the HPET was enabled, 0 before that.
+pub fn uptime_ms() -> u64 {
+    uptime_ns() / 1_000_000
+}
+
+/// Converts HPET ticks to nanoseconds, the clock speed is the tick period in femtoseconds.
+pub fn ticks_to_ns(ticks: u64, clock_speed: u32) -> u64 {
+    (ticks as u128 * clock_speed as u128 / 1_000_000) as u64
+}
+
+/// Extends a 32-bit counter value to 64 bits, using the last extended value to count the wraps.
+/// The high dword is incremented whenever the low value is lower than the last one.
+pub fn extend_counter(last: u64, counter: u32) -> u64 {
+    let extended = (last & !0xffff_ffff) | counter as u64;
+    if extended < last {
+        extended + (1 << 32)
+    } else {
+        extended
+    }
+}
+
 pub struct Hpet {
     base_addr: UnsafeCell<u64>,
 }
@@ -58,6 +169,15 @@ impl Hpet {
         }
     }
 
+    /// Returns whether the main counter of the HPET is 64 bits wide.
+    pub fn is_64bit(&self) -> bool {
+        unsafe {
+            let base_addr = *self.base_addr.get();
+            let value = ptr::read_volatile(base_addr as *const u64);
+            value & (1 << 13) != 0
+        }
+    }
+
     /// Enable the HPET counter.
     pub fn enable_counter(&self) {
         unsafe {
@@ -83,10 +203,84 @@ impl Hpet {
         }
     }
 
+    /// Read the counter extended to 64 bits, counting the wraparounds of a 32-bit counter.
+    pub fn get_monotonic_counter(&self) -> u64 {
+        if self.is_64bit() {
+            return self.get_counter();
+        }
+
+        // The last value is loaded before the counter is read, so a value stored in between
+        // by another CPU can't make the counter look like it wrapped.
+        let mut last = EXTENDED_COUNTER.load(Ordering::Acquire);
+        loop {
+            let extended = extend_counter(last, self.get_counter() as u32);
+            match EXTENDED_COUNTER.compare_exchange_weak(
+                last,
+                extended,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return extended,
+                Err(current) if current >= extended => return current,
+                Err(current) => last = current,
+            }
+        }
+    }
+
+    fn timer_config_addr(&self, timer: u32) -> u64 {
+        unsafe { *self.base_addr.get() + 0x100 + 0x20 * timer as u64 }
+    }
+
+    /// Returns the IOAPIC inputs timer `timer` can be routed to, bit `n` is the GSI `n`.
+    pub fn timer_route_capability(&self, timer: u32) -> u32 {
+        let config = unsafe { ptr::read_volatile(self.timer_config_addr(timer) as *const u64) };
+        (config >> 32) as u32
+    }
+
+    /// Returns whether timer `timer` can fire periodically.
+    pub fn timer_periodic_capable(&self, timer: u32) -> bool {
+        let config = unsafe { ptr::read_volatile(self.timer_config_addr(timer) as *const u64) };
+        config & TIMER_PERIODIC_CAPABLE != 0
+    }
+
+    /// Returns whether the comparator of timer `timer` is 64 bits wide.
+    pub fn timer_64bit_capable(&self, timer: u32) -> bool {
+        let config = unsafe { ptr::read_volatile(self.timer_config_addr(timer) as *const u64) };
+        config & TIMER_64BIT_CAPABLE != 0
+    }
+
+    /// Starts timer `timer` firing every `period` ticks, edge-triggered on the IOAPIC input `gsi`.
+    /// The first interrupt comes `period` ticks from now.
+    pub fn start_periodic_timer(&self, timer: u32, gsi: u32, period: u64) {
+        let config_addr = self.timer_config_addr(timer);
+        let comparator_addr = config_addr + 0x8;
+        unsafe {
+            let old = ptr::read_volatile(config_addr as *const u64);
+            let config = (old & !(TIMER_ROUTE_MASK | TIMER_LEVEL_TRIGGERED))
+                | (gsi as u64) << TIMER_ROUTE_SHIFT
+                | TIMER_INT_ENABLE
+                | TIMER_PERIODIC
+                | TIMER_VALUE_SET;
+            ptr::write_volatile(config_addr as *mut u64, config);
+            // With the value-set bit the first write sets the comparator, the second the period.
+            ptr::write_volatile(comparator_addr as *mut u64, self.get_counter() + period);
+            ptr::write_volatile(comparator_addr as *mut u64, period);
+        }
+    }
+
+    /// Stops the interrupts of timer `timer`.
+    pub fn stop_timer(&self, timer: u32) {
+        let config_addr = self.timer_config_addr(timer);
+        unsafe {
+            let old = ptr::read_volatile(config_addr as *const u64);
+            ptr::write_volatile(config_addr as *mut u64, old & !TIMER_INT_ENABLE);
+        }
+    }
+
     /// Get the time
     #[inline]
     pub fn get_time_elapsed(&self) -> u64 {
-        self.get_counter() * (self.clock_speed() as u64 / 1_000_000)
+        self.get_monotonic_counter() * (self.clock_speed() as u64 / 1_000_000)
     }
 }
 
@@ -1,22 +1,325 @@
-use crossbeam_queue::ArrayQueue;
-use spin::Lazy;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::task::{Context, Poll, Waker};
+use pc_keyboard::{KeyEvent, ScancodeSet, ScancodeSet1};
+use spin::{Mutex, MutexGuard, Once};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::data::ring::SpscRing;
+use crate::task::thread::WeakSharedThread;
+use crate::task::{block_current, unblock, Thread};
 
 const SCANCODE_QUEUE_SIZE: usize = 128;
+const SUBSCRIBER_QUEUE_SIZE: usize = 64;
+const PORT_TRY_TIMES: u16 = 10_000;
+const COMMAND_RETRY_TIMES: u8 = 3;
+
+const RESPONSE_ACK: u8 = 0xfa;
+const RESPONSE_RESEND: u8 = 0xfe;
+
+const SCANCODE_CAPS_LOCK: u8 = 0x3a;
+const SCANCODE_NUM_LOCK: u8 = 0x45;
+const SCANCODE_SCROLL_LOCK: u8 = 0x46;
+
+const LED_SCROLL_LOCK: u8 = 1 << 0;
+const LED_NUM_LOCK: u8 = 1 << 1;
+const LED_CAPS_LOCK: u8 = 1 << 2;
+
+/// Filled by the interrupt handler, drained by `dispatch`.
+static SCANCODE_QUEUE: SpscRing<u8, SCANCODE_QUEUE_SIZE> = SpscRing::new();
+/// The scancodes for `get_scancode`.
+static CONSOLE_QUEUE: SpscRing<u8, SCANCODE_QUEUE_SIZE> = SpscRing::new();
 
-static SCANCODE_QUEUE: Lazy<ArrayQueue<u8>> = Lazy::new(|| ArrayQueue::new(SCANCODE_QUEUE_SIZE));
+static DISPATCHER: Mutex<Dispatcher> = Mutex::new(Dispatcher {
+    decoder: ScancodeSet1::new(),
+    scancodes: Vec::new(),
+    key_events: Vec::new(),
+});
 
-pub fn add_scancode(scancode: u8) {
-    if let Err(_) = SCANCODE_QUEUE.push(scancode) {
+/// The wakers of the `KeyStream`s which are waiting, woken by the key waker thread.
+static KEY_WAKERS: Mutex<Vec<Waker>> = Mutex::new(Vec::new());
+/// Set by the interrupt handler when the waiting `KeyStream`s have a scancode to decode.
+static KEY_WAKE_PENDING: AtomicBool = AtomicBool::new(false);
+/// Wakes the `KeyStream`s for the interrupt handler, started by the first stream which waits.
+static KEY_WAKER_THREAD: Once<WeakSharedThread> = Once::new();
+
+/// The lock keys which are on, as the LED bits.
+static LOCK_STATE: AtomicU8 = AtomicU8::new(0);
+/// The bytes left in the current 0xe0 or 0xe1 sequence, they are not lock keys.
+static PREFIX_BYTES: AtomicU8 = AtomicU8::new(0);
+
+/// Queues a scancode from the keyboard interrupt handler.
+pub(crate) fn add_scancode(scancode: u8) {
+    // Answers to `set_leds` which the polling didn't pick up.
+    if scancode == RESPONSE_ACK || scancode == RESPONSE_RESEND {
+        return;
+    }
+    track_lock_keys(scancode);
+    // The keyboard interrupt is routed to a single CPU, so its handler is the only producer.
+    if !unsafe { SCANCODE_QUEUE.push(scancode) } {
         crate::println!("Scancode queue full, dropping keyboard input!");
     }
+    wake_key_streams();
+}
+
+/// Queues scancodes which don't come from the keyboard, e.g. the serial input as key presses.
+/// It must only be called from an interrupt handler on the CPU the keyboard interrupt is routed to,
+/// so the interrupt ring still has a single producer.
+pub(crate) fn inject_scancodes(scancodes: &[u8]) {
+    for &scancode in scancodes {
+        if !unsafe { SCANCODE_QUEUE.push(scancode) } {
+            crate::println!("Scancode queue full, dropping injected input!");
+            break;
+        }
+    }
+    wake_key_streams();
+}
+
+/// Has the key waker thread wake every waiting `KeyStream`, they decode the scancode when polled.
+/// Waking a stream may allocate and dropping its waker may free, so this only unblocks the thread.
+fn wake_key_streams() {
+    KEY_WAKE_PENDING.store(true, Ordering::Release);
+    if let Some(thread) = KEY_WAKER_THREAD.get() {
+        unblock(thread);
+    }
+}
+
+fn run_key_waker() -> ! {
+    loop {
+        block_current(|| !KEY_WAKE_PENDING.load(Ordering::Acquire));
+        if !KEY_WAKE_PENDING.swap(false, Ordering::AcqRel) {
+            continue;
+        }
+        let wakers = interrupts::without_interrupts(|| core::mem::take(&mut *KEY_WAKERS.lock()));
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+/// Toggles the lock state on the make codes of the lock keys, and updates the LEDs to match.
+fn track_lock_keys(scancode: u8) {
+    match scancode {
+        0xe0 => return PREFIX_BYTES.store(1, Ordering::Relaxed),
+        // Pause is 0xe1 0x1d 0x45, the 0x45 is not Num Lock.
+        0xe1 => return PREFIX_BYTES.store(2, Ordering::Relaxed),
+        _ => {}
+    }
+    let prefix_bytes = PREFIX_BYTES.load(Ordering::Relaxed);
+    if prefix_bytes != 0 {
+        PREFIX_BYTES.store(prefix_bytes - 1, Ordering::Relaxed);
+        return;
+    }
+
+    let led = match scancode {
+        SCANCODE_CAPS_LOCK => LED_CAPS_LOCK,
+        SCANCODE_NUM_LOCK => LED_NUM_LOCK,
+        SCANCODE_SCROLL_LOCK => LED_SCROLL_LOCK,
+        _ => return,
+    };
+    let state = LOCK_STATE.fetch_xor(led, Ordering::Relaxed) ^ led;
+    if let Err(err) = write_leds(state) {
+        log::warn!("Failed to set the keyboard LEDs: {}", err);
+    }
+}
+
+/// Returns whether Caps Lock, Num Lock and Scroll Lock are on.
+pub fn lock_state() -> (bool, bool, bool) {
+    let state = LOCK_STATE.load(Ordering::Relaxed);
+    (
+        state & LED_CAPS_LOCK != 0,
+        state & LED_NUM_LOCK != 0,
+        state & LED_SCROLL_LOCK != 0,
+    )
+}
+
+/// Sets the lock state and the keyboard LEDs.
+pub fn set_leds(caps: bool, num: bool, scroll: bool) -> Result<(), &'static str> {
+    let mut state = 0;
+    if caps {
+        state |= LED_CAPS_LOCK;
+    }
+    if num {
+        state |= LED_NUM_LOCK;
+    }
+    if scroll {
+        state |= LED_SCROLL_LOCK;
+    }
+    LOCK_STATE.store(state, Ordering::Relaxed);
+    x86_64::instructions::interrupts::without_interrupts(|| write_leds(state))
+}
+
+/// Sends the set LEDs command 0xed and the LED bits, the interrupts must be disabled
+/// so that the keyboard handler doesn't take the ACKs.
+fn write_leds(state: u8) -> Result<(), &'static str> {
+    unsafe {
+        send_keyboard_byte(0xed)?;
+        send_keyboard_byte(state)
+    }
+}
+
+/// Writes a byte to the keyboard and waits for its ACK, resending it when asked to.
+unsafe fn send_keyboard_byte(byte: u8) -> Result<(), &'static str> {
+    let mut status_port = Port::<u8>::new(0x64);
+    let mut data_port = Port::<u8>::new(0x60);
+
+    for _ in 0..COMMAND_RETRY_TIMES {
+        if !(0..PORT_TRY_TIMES).any(|_| status_port.read() & 0x2 == 0) {
+            return Err("Timed out waiting for the keyboard to accept input!");
+        }
+        data_port.write(byte);
+
+        let mut response = None;
+        for _ in 0..PORT_TRY_TIMES {
+            if status_port.read() & 0x1 == 1 {
+                response = Some(data_port.read());
+                break;
+            }
+        }
+        match response {
+            Some(RESPONSE_ACK) => return Ok(()),
+            Some(RESPONSE_RESEND) => continue,
+            Some(_) => return Err("Unexpected response from the keyboard!"),
+            None => return Err("Timed out waiting for the keyboard ACK!"),
+        }
+    }
+    Err("The keyboard asked to resend too many times!")
 }
 
 /// Return the scan code of the keyboard buffer, returns None is the buffer is empty.
+/// This is the console's own queue, fed like the subscribers, so only one thread should read it.
 pub fn get_scancode() -> Option<u8> {
-    SCANCODE_QUEUE.pop()
+    let _dispatcher = dispatch();
+    unsafe { CONSOLE_QUEUE.pop() }
 }
 
 /// Return whether the keyboard buffer is empty.
 pub fn has_scancode() -> bool {
-    !SCANCODE_QUEUE.is_empty()
+    drop(dispatch());
+    !CONSOLE_QUEUE.is_empty()
+}
+
+/// Receives a copy of every raw scancode since it subscribed.
+pub struct ScancodeSubscriber(Arc<SpscRing<u8, SUBSCRIBER_QUEUE_SIZE>>);
+
+impl ScancodeSubscriber {
+    /// Returns the next scancode, None if there is none yet.
+    pub fn pop(&mut self) -> Option<u8> {
+        drop(dispatch());
+        // The subscriber isn't `Clone`, `&mut self` makes this the only consumer.
+        unsafe { self.0.pop() }
+    }
+}
+
+/// Receives every key event decoded with scancode set 1 since it subscribed.
+pub struct KeyEventSubscriber(Arc<SpscRing<KeyEvent, SUBSCRIBER_QUEUE_SIZE>>);
+
+impl KeyEventSubscriber {
+    /// Returns the next key event, None if there is none yet.
+    pub fn pop(&mut self) -> Option<KeyEvent> {
+        drop(dispatch());
+        // The subscriber isn't `Clone`, `&mut self` makes this the only consumer.
+        unsafe { self.0.pop() }
+    }
+}
+
+/// The key events for async tasks, a `KeyEventSubscriber` which registers a waker when it is empty.
+/// A scancode from the keyboard interrupt gets it woken, so an executor doesn't have to poll it
+/// again and again.
+pub struct KeyStream(KeyEventSubscriber);
+
+impl KeyStream {
+    /// Subscribes to the key events, like `subscribe_key_events`.
+    pub fn new() -> Self {
+        Self(subscribe_key_events())
+    }
+
+    /// Returns the next key event, or registers the waker of `cx` to be woken by the next scancode.
+    pub fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<KeyEvent> {
+        if let Some(event) = self.0.pop() {
+            return Poll::Ready(event);
+        }
+        KEY_WAKER_THREAD.call_once(|| Thread::spawn_kernel(|| run_key_waker()));
+        interrupts::without_interrupts(|| {
+            let mut wakers = KEY_WAKERS.lock();
+            if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+        });
+        // A scancode may have come in before the waker was registered.
+        match self.0.pop() {
+            Some(event) => Poll::Ready(event),
+            None => Poll::Pending,
+        }
+    }
+
+    /// Returns a future of the next key event.
+    pub fn next_key(&mut self) -> NextKey<'_> {
+        NextKey(self)
+    }
+}
+
+impl Default for KeyStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The future returned by `KeyStream::next_key`.
+pub struct NextKey<'a>(&'a mut KeyStream);
+
+impl Future for NextKey<'_> {
+    type Output = KeyEvent;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<KeyEvent> {
+        self.0.poll_next(cx)
+    }
+}
+
+/// Subscribes to the raw scancodes, they are dropped for it when it doesn't keep up.
+pub fn subscribe_scancodes() -> ScancodeSubscriber {
+    let queue = Arc::new(SpscRing::new());
+    DISPATCHER.lock().scancodes.push(queue.clone());
+    ScancodeSubscriber(queue)
+}
+
+/// Subscribes to the decoded key events, they are dropped for it when it doesn't keep up.
+pub fn subscribe_key_events() -> KeyEventSubscriber {
+    let queue = Arc::new(SpscRing::new());
+    DISPATCHER.lock().key_events.push(queue.clone());
+    KeyEventSubscriber(queue)
+}
+
+struct Dispatcher {
+    decoder: ScancodeSet1,
+    scancodes: Vec<Arc<SpscRing<u8, SUBSCRIBER_QUEUE_SIZE>>>,
+    key_events: Vec<Arc<SpscRing<KeyEvent, SUBSCRIBER_QUEUE_SIZE>>>,
+}
+
+/// Drains the interrupt ring into the console queue and every subscriber.
+/// The lock makes the caller the only consumer of the ring and producer of the queues,
+/// it is returned for `get_scancode` to be the only consumer of the console queue too.
+fn dispatch() -> MutexGuard<'static, Dispatcher> {
+    let mut guard = DISPATCHER.lock();
+    let dispatcher = &mut *guard;
+    // A subscriber which was dropped only leaves the reference of the dispatcher.
+    dispatcher.scancodes.retain(|queue| Arc::strong_count(queue) > 1);
+    dispatcher.key_events.retain(|queue| Arc::strong_count(queue) > 1);
+
+    while let Some(scancode) = unsafe { SCANCODE_QUEUE.pop() } {
+        unsafe { CONSOLE_QUEUE.push(scancode) };
+        for queue in dispatcher.scancodes.iter() {
+            unsafe { queue.push(scancode) };
+        }
+        if let Ok(Some(event)) = dispatcher.decoder.advance_state(scancode) {
+            for queue in dispatcher.key_events.iter() {
+                unsafe { queue.push(event.clone()) };
+            }
+        }
+    }
+    guard
 }
@@ -1,4 +1,4 @@
-use crate::memory::FRAME_ALLOCATOR;
+use crate::memory::{pin_frames, unpin_frames, FRAME_ALLOCATOR};
 use x86_64::structures::paging::{FrameDeallocator, PhysFrame};
 use x86_64::{PhysAddr, VirtAddr};
 
@@ -9,6 +9,7 @@ pub mod keyboard;
 pub mod mouse;
 pub mod nvme;
 pub mod pci;
+pub mod ps2;
 pub mod rtc;
 pub mod serial;
 pub mod xhci;
@@ -19,19 +20,26 @@ pub fn init() {
 }
 
 /// Allocate memory for the DMA drivers, `cnt` is the number of physical memory frames you need.
-pub fn alloc_for_dma(cnt: usize) -> (PhysAddr, VirtAddr) {
-    let phys = FRAME_ALLOCATOR.lock().allocate_frames(cnt).unwrap();
+/// The frames are pinned, so the device can keep the physical address until `dealloc_for_dma`.
+pub fn alloc_for_dma(cnt: usize) -> crate::Result<(PhysAddr, VirtAddr)> {
+    let phys = FRAME_ALLOCATOR.lock().allocate_frames(cnt)?;
     let phys = PhysAddr::new(phys);
+    pin_frames(dma_frames(phys, cnt));
     let virt = crate::memory::convert_physical_to_virtual(phys);
-    (phys, virt)
+    Ok((phys, virt))
 }
 
-/// deallocates the physical memory.
-pub fn dealloc_for_dma(virt_addr: VirtAddr, _cnt: usize) {
+/// Unpins and deallocates the `cnt` frames `alloc_for_dma` returned.
+pub fn dealloc_for_dma(virt_addr: VirtAddr, cnt: usize) {
     let phys = crate::memory::convert_virtual_to_physical(virt_addr);
-    unsafe {
-        FRAME_ALLOCATOR
-            .lock()
-            .deallocate_frame(PhysFrame::containing_address(phys));
+    unpin_frames(dma_frames(phys, cnt));
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    for frame in dma_frames(phys, cnt) {
+        unsafe { frame_allocator.deallocate_frame(frame) };
     }
 }
+
+fn dma_frames(phys: PhysAddr, cnt: usize) -> impl Iterator<Item = PhysFrame> {
+    let start = PhysFrame::containing_address(phys);
+    (0..cnt as u64).map(move |index| start + index)
+}
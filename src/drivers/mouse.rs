@@ -1,16 +1,45 @@
 use bitflags::bitflags;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use os_terminal::DrawTarget;
 use spin::{Lazy, Mutex};
-use x86_64::instructions::port::Port;
+use x86_64::instructions::{interrupts, port::Port};
+
+use super::display::Display;
+use crate::arch::apic::{get_lapic_id, route_irq, IrqVector};
+use crate::arch::interrupts::InterruptIndex;
 
 const PORT_READ_TRY_TIMES: u16 = 10_000;
 
 pub static MOUSE: Lazy<Mutex<Mouse>> = Lazy::new(|| Mutex::new(Mouse::new()));
 
+/// The cursor position, copied out of `MOUSE` so that it can be read from the move handler.
+static CURSOR_X: AtomicUsize = AtomicUsize::new(0);
+static CURSOR_Y: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether a PS/2 mouse was found and its IRQ routed.
+static MOUSE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Sets up the PS/2 mouse and routes its IRQ to the current CPU.
+/// Both are skipped when no mouse acknowledges the enable command.
 pub fn init() {
     let mut mouse = MOUSE.lock();
+    if !super::ps2::second_port_available() || !mouse.detect() {
+        log::info!("No PS/2 mouse found, skipped the mouse initialization");
+        return;
+    }
     match mouse.init() {
         Ok(_) => {
             mouse.set_complete_handler(mouse_complete_handler);
+            if Display::is_available() {
+                let (width, height) = Display::new().size();
+                mouse.cursor.set_bounds(width, height);
+            }
+            route_irq(
+                IrqVector::Mouse as u8,
+                InterruptIndex::Mouse as u8,
+                get_lapic_id() as u8,
+            );
+            MOUSE_ENABLED.store(true, Ordering::SeqCst);
             log::debug!("Mouse Type: {:?}", mouse.mouse_type);
             log::info!("Mouse initialized successfully!");
         }
@@ -18,6 +47,27 @@ pub fn init() {
     }
 }
 
+/// Returns the cursor position, inside the display.
+pub fn position() -> (usize, usize) {
+    (CURSOR_X.load(Ordering::Relaxed), CURSOR_Y.load(Ordering::Relaxed))
+}
+
+/// Sets the function called from the mouse interrupt with the new position after every move.
+/// It must not lock `MOUSE`, use its arguments or `position` instead.
+pub fn on_move(handler: fn(usize, usize)) {
+    interrupts::without_interrupts(|| MOUSE.lock().move_handler = Some(handler));
+}
+
+/// Changes the acceleration of the cursor, None to move it by the raw deltas.
+pub fn set_acceleration(acceleration: Option<Acceleration>) {
+    interrupts::without_interrupts(|| MOUSE.lock().cursor.set_acceleration(acceleration));
+}
+
+/// Returns whether the mouse driver is running, false when there is no PS/2 mouse.
+pub fn enabled() -> bool {
+    MOUSE_ENABLED.load(Ordering::SeqCst)
+}
+
 fn mouse_complete_handler(_mouse_state: MouseState) {
     //crate::println!("{:?}", mouse_state);
 }
@@ -60,6 +110,86 @@ pub struct Mouse {
     current_state: MouseState,
     mouse_type: MouseType,
     complete_handler: Option<fn(MouseState)>,
+    move_handler: Option<fn(usize, usize)>,
+    cursor: Cursor,
+}
+
+/// Speeds up the moves longer than `threshold` units in a packet by `multiplier`.
+#[derive(Debug, Copy, Clone)]
+pub struct Acceleration {
+    pub threshold: u16,
+    pub multiplier: u16,
+}
+
+/// The cursor position, kept inside the display.
+#[derive(Debug)]
+pub struct Cursor {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    acceleration: Option<Acceleration>,
+}
+
+impl Cursor {
+    pub const fn new() -> Self {
+        Cursor {
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+            acceleration: None,
+        }
+    }
+
+    /// Sets the area the cursor can move in, e.g. the display size, and clamps the position into it.
+    pub fn set_bounds(&mut self, width: usize, height: usize) {
+        self.width = width.max(1);
+        self.height = height.max(1);
+        self.x = self.x.min(self.width - 1);
+        self.y = self.y.min(self.height - 1);
+    }
+
+    pub fn set_acceleration(&mut self, acceleration: Option<Acceleration>) {
+        self.acceleration = acceleration;
+    }
+
+    pub fn position(&self) -> (usize, usize) {
+        (self.x, self.y)
+    }
+
+    /// Moves the cursor by a packet's deltas, and returns the new position.
+    /// The mouse counts y upwards, the screen downwards.
+    pub fn move_by(&mut self, move_x: i16, move_y: i16) -> (usize, usize) {
+        let move_x = self.accelerate(move_x) as isize;
+        let move_y = -(self.accelerate(move_y) as isize);
+        self.x = self.x.saturating_add_signed(move_x).min(self.width - 1);
+        self.y = self.y.saturating_add_signed(move_y).min(self.height - 1);
+        (self.x, self.y)
+    }
+
+    fn accelerate(&self, delta: i16) -> i32 {
+        match self.acceleration {
+            Some(acceleration) if delta.unsigned_abs() > acceleration.threshold => {
+                delta as i32 * acceleration.multiplier as i32
+            }
+            _ => delta as i32,
+        }
+    }
+}
+
+impl Default for Cursor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Extends a 9-bit packet delta, the sign bit is in the flags byte.
+fn sign_extend(value: u8, negative: bool) -> i16 {
+    match negative {
+        true => value as i16 - 0x100,
+        false => value as i16,
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -90,14 +220,22 @@ impl Mouse {
             current_state: MouseState::new(),
             mouse_type: MouseType::Standard,
             complete_handler: None,
+            move_handler: None,
+            cursor: Cursor::new(),
+        }
+    }
+
+    /// Enables the auxiliary port and the packet streaming, returns whether a mouse acknowledged it.
+    pub fn detect(&mut self) -> bool {
+        unsafe {
+            self.write_command_port(0xa8).is_ok() && self.enable_packet_streaming().is_ok()
         }
     }
 
+    /// Probes the scroll wheel and the additional buttons, call it after `detect`.
     pub fn init(&mut self) -> Result<(), &'static str> {
         unsafe {
-            self.enable_packet_streaming()?
-                .enable_scroll_wheel()?
-                .enable_additional_button()?;
+            self.enable_scroll_wheel()?.enable_additional_button()?;
         }
         Ok(())
     }
@@ -116,20 +254,18 @@ impl Mouse {
                 self.current_state.flags = flags;
             }
             1 => {
-                if !self.current_state.flags.contains(MouseFlags::X_OVERFLOW) {
-                    self.current_state.move_x = packet as i16;
-                    if self.current_state.flags.contains(MouseFlags::X_SIGN) {
-                        self.current_state.move_x = ((packet as u16) | 0xFF00) as i16;
-                    }
-                }
+                let flags = self.current_state.flags;
+                self.current_state.move_x = match flags.contains(MouseFlags::X_OVERFLOW) {
+                    true => 0,
+                    false => sign_extend(packet, flags.contains(MouseFlags::X_SIGN)),
+                };
             }
             2 => {
-                if !self.current_state.flags.contains(MouseFlags::Y_OVERFLOW) {
-                    self.current_state.move_y = packet as i16;
-                    if self.current_state.flags.contains(MouseFlags::Y_SIGN) {
-                        self.current_state.move_y = ((packet as u16) | 0xFF00) as i16;
-                    }
-                }
+                let flags = self.current_state.flags;
+                self.current_state.move_y = match flags.contains(MouseFlags::Y_OVERFLOW) {
+                    true => 0,
+                    false => sign_extend(packet, flags.contains(MouseFlags::Y_SIGN)),
+                };
             }
             3 => {
                 self.current_state.additional_flags = match packet {
@@ -145,6 +281,14 @@ impl Mouse {
             _ => unreachable!(),
         }
         if self.current_packet_index % modulo == modulo - 1 {
+            let (x, y) = self
+                .cursor
+                .move_by(self.current_state.move_x, self.current_state.move_y);
+            CURSOR_X.store(x, Ordering::Relaxed);
+            CURSOR_Y.store(y, Ordering::Relaxed);
+            if let Some(handler) = self.move_handler {
+                handler(x, y);
+            }
             if self.complete_handler.is_some() {
                 (self.complete_handler.unwrap())(self.current_state);
             }
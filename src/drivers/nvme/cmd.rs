@@ -156,6 +156,16 @@ impl NvmeCommand {
         }
     }
 
+    pub fn set_features(c_id: u16, fid: u8, cdw11: u32) -> Self {
+        Self {
+            opcode: 9,
+            c_id,
+            cdw10: u32::from(fid),
+            cdw11,
+            ..Default::default()
+        }
+    }
+
     pub fn io_read(c_id: u16, ns_id: u32, lba: u64, blocks_1: u16, ptr0: u64, ptr1: u64) -> Self {
         Self {
             opcode: 2,
@@ -0,0 +1,128 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Size of the data returned by the Identify command.
+pub const IDENTIFY_DATA_SIZE: usize = 4096;
+
+/// The fields of the Identify Controller data the framework uses.
+#[derive(Debug, Clone, Default)]
+pub struct NvmeControllerInfo {
+    pub vendor_id: u16,
+    pub subsystem_vendor_id: u16,
+    pub serial: String,
+    pub model: String,
+    pub firmware: String,
+    /// Maximum Data Transfer Size, as a power of two of the minimum page size, 0 if unlimited.
+    pub mdts: u8,
+    pub controller_id: u16,
+    /// Version in the same layout as the VS register, 0 before NVMe 1.2.
+    pub version: u32,
+    /// Number of namespaces the controller supports.
+    pub namespace_count: u32,
+    /// Whether a volatile write cache is present.
+    pub volatile_write_cache: bool,
+}
+
+/// A LBA format a namespace can be formatted with.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NvmeLbaFormat {
+    pub metadata_size: u16,
+    /// The block size, 0 if the format gives a size the driver doesn't support.
+    pub block_size: u64,
+    /// Relative performance, 0 is the best.
+    pub relative_performance: u8,
+}
+
+/// The fields of the Identify Namespace data the framework uses.
+#[derive(Debug, Clone, Default)]
+pub struct NvmeNamespaceInfo {
+    pub id: u32,
+    /// Total size in blocks.
+    pub size: u64,
+    /// Number of blocks which may be allocated.
+    pub capacity: u64,
+    /// Number of blocks currently allocated.
+    pub utilization: u64,
+    /// Index of the format in `lba_formats` the namespace is formatted with.
+    pub formatted_lba: usize,
+    pub lba_formats: Vec<NvmeLbaFormat>,
+}
+
+impl NvmeControllerInfo {
+    /// Parses the 4096-byte Identify Controller data.
+    pub fn parse(data: &[u8]) -> Self {
+        assert!(data.len() >= IDENTIFY_DATA_SIZE, "identify data is too short");
+        Self {
+            vendor_id: read_u16(data, 0),
+            subsystem_vendor_id: read_u16(data, 2),
+            serial: read_string(&data[4..24]),
+            model: read_string(&data[24..64]),
+            firmware: read_string(&data[64..72]),
+            mdts: data[77],
+            controller_id: read_u16(data, 78),
+            version: read_u32(data, 80),
+            namespace_count: read_u32(data, 516),
+            volatile_write_cache: data[525] & 1 != 0,
+        }
+    }
+}
+
+impl NvmeNamespaceInfo {
+    /// Parses the 4096-byte Identify Namespace data of namespace `id`.
+    pub fn parse(id: u32, data: &[u8]) -> Self {
+        assert!(data.len() >= IDENTIFY_DATA_SIZE, "identify data is too short");
+        // NLBAF is 0-based.
+        let format_count = data[25] as usize + 1;
+        let lba_formats = (0..format_count.min(16))
+            .map(|index| {
+                let format = read_u32(data, 128 + index * 4);
+                let lbads = (format >> 16) & 0xFF;
+                NvmeLbaFormat {
+                    metadata_size: format as u16,
+                    block_size: if (9..32).contains(&lbads) { 1 << lbads } else { 0 },
+                    relative_performance: ((format >> 24) & 0b11) as u8,
+                }
+            })
+            .collect();
+
+        Self {
+            id,
+            size: read_u64(data, 0),
+            capacity: read_u64(data, 8),
+            utilization: read_u64(data, 16),
+            formatted_lba: (data[26] & 0xF) as usize,
+            lba_formats,
+        }
+    }
+
+    /// Returns the format the namespace is formatted with.
+    pub fn lba_format(&self) -> NvmeLbaFormat {
+        self.lba_formats
+            .get(self.formatted_lba)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Reads an ASCII string, padded with spaces as the spec says, or with zeros as some drives do.
+fn read_string(data: &[u8]) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    data[..end]
+        .iter()
+        .map(|&b| if b.is_ascii_graphic() { b as char } else { ' ' })
+        .collect::<String>()
+        .trim()
+        .into()
+}
@@ -1,7 +1,6 @@
 use crate::drivers::alloc_for_dma;
-use alloc::boxed::Box;
+use crate::Result;
 use core::{
-    error::Error,
     ops::{Deref, DerefMut, Index, IndexMut, Range, RangeFull, RangeTo},
     slice,
 };
@@ -147,14 +146,14 @@ impl IndexMut<RangeFull> for Dma<u8> {
 impl<T> Dma<T> {
     /// Allocates DMA Memory on a huge page
     // TODO: vfio support?
-    pub fn allocate(size: usize) -> Result<Dma<T>, Box<dyn Error>> {
+    pub fn allocate(size: usize) -> Result<Dma<T>> {
         let size = if size % 4096 != 0 {
             ((size >> PAGE_BITS) + 1) << PAGE_BITS
         } else {
             size
         };
 
-        let (paddr, vaddr) = alloc_for_dma(size / PAGE_SIZE);
+        let (paddr, vaddr) = alloc_for_dma(size / PAGE_SIZE)?;
 
         Ok(Dma {
             virt: vaddr.as_mut_ptr(),
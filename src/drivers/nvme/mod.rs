@@ -1,10 +1,13 @@
 mod cmd;
+mod identify;
 mod memory;
 mod nvme;
 mod queues;
 
 use crate::drivers::pci::{get_pci_device_structure_mut, PCI_DEVICE_LINKEDLIST};
+use crate::{Error, Result};
 use alloc::{collections::btree_map::BTreeMap, vec::Vec};
+pub use identify::{NvmeControllerInfo, NvmeLbaFormat, NvmeNamespaceInfo};
 use memory::Dma;
 pub use nvme::{NvmeDevice, NvmeQueuePair};
 pub use queues::QUEUE_LENGTH;
@@ -19,48 +22,63 @@ pub fn init() {
     let mut nvme_cons = NVME_CONS.lock();
     let mut nvme_sizes = NVME_SIZES.lock();
 
-    let mut idx = 0;
     for pci_device in pci_devices {
-        if let None = pci_device.bar_init() {
-            continue;
+        let bus_device_function = pci_device.common_header().bus_device_function;
+        match pci_device.bar_init() {
+            Some(Ok(_)) => {}
+            Some(Err(err)) => {
+                log::warn!("Skipping NVMe {:?}: {}", bus_device_function, err);
+                continue;
+            }
+            None => continue,
         }
 
-        if let Ok(bar) = pci_device
+        let Some((header, len)) = pci_device
             .as_standard_device()
             .unwrap()
             .standard_device_bar
             .get_bar(0)
-        {
-            if let Some((_, len)) = bar.memory_address_size() {
-                let header = bar.virtual_address().unwrap() as usize;
-
-                pci_device.as_mut().enable_master();
-
-                log::info!("NVMe OK");
-                let mut nvme_device =
-                    NvmeDevice::init(header, len as usize).expect("Cannot init NVMe device");
-
-                nvme_device
-                    .identify_controller()
-                    .expect("Cannot identify controller");
-                let ns = nvme_device.identify_namespace_list(0);
+            .ok()
+            .and_then(|bar| Some((bar.virtual_address()?, bar.memory_address_size()?.1)))
+        else {
+            log::warn!("Skipping NVMe {:?}: BAR0 is not a memory BAR", bus_device_function);
+            continue;
+        };
 
-                let mut nvmcap = 0;
-                for n in ns {
-                    let cap = nvme_device.identify_namespace(n).1 as usize;
-                    nvmcap += cap;
-                }
+        // The queues are polled, so INTx is never needed.
+        let pci_device = pci_device.as_mut();
+        pci_device.enable_memory_space();
+        pci_device.enable_bus_master();
+        pci_device.disable_legacy_interrupts();
 
-                nvme_cons.push(nvme_device);
+        match init_controller(header as usize, len as usize) {
+            Ok((nvme_device, nvmcap)) => {
                 log::info!("NVM capacity = {}", nvmcap);
-                nvme_sizes.insert(idx, nvmcap);
+                nvme_sizes.insert(nvme_cons.len(), nvmcap);
+                nvme_cons.push(nvme_device);
             }
+            Err(err) => log::warn!("Skipping NVMe {:?}: {}", bus_device_function, err),
         }
+    }
 
-        idx += 1;
+    if nvme_cons.is_empty() {
+        log::info!("No NVMe drive found, the block functions return `Error::NotFound`");
     }
 }
 
+/// Resets and identifies a controller, returns it with the capacity of its namespaces.
+fn init_controller(header: usize, len: usize) -> Result<(NvmeDevice, usize)> {
+    let mut nvme_device = NvmeDevice::init(header, len)?;
+    nvme_device.identify_controller()?;
+
+    let mut nvmcap = 0;
+    for n in nvme_device.identify_namespace_list(0) {
+        nvmcap += nvme_device.identify_namespace(n).1 as usize;
+    }
+    log::info!("NVMe OK");
+    Ok((nvme_device, nvmcap))
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct NvmeNamespace {
     pub id: u32,
@@ -74,25 +92,100 @@ pub struct NvmeStats {
     pub submissions: u64,
 }
 
-/// Reads a block from the NVMe driver at block block_id
-pub fn read_block(hd: usize, block_id: u64, buf: &mut [u8]) {
-    let dma: Dma<u8> = Dma::allocate(buf.len()).expect("Cannot allocate frame");
+/// Reads blocks from namespace `nsid` of drive `hd`, starting at block `lba`.
+/// The length of `buf` must be a multiple of the block size of the namespace.
+/// Returns `Error::NotFound` if there is no drive `hd` or no such namespace, e.g. on a diskless boot.
+pub fn read_block(hd: usize, nsid: u32, lba: u64, buf: &mut [u8]) -> Result<()> {
+    let mut cons = NVME_CONS.lock();
+    let nvme = cons.get_mut(hd).ok_or(Error::NotFound)?;
+    check_length(&nvme.namespace(nsid)?, buf.len())?;
+
+    let dma: Dma<u8> = Dma::allocate(buf.len())?;
+    nvme.read(nsid, &dma, lba)?;
+    unsafe { buf.as_mut_ptr().copy_from(dma.virt, buf.len()) };
+    Ok(())
+}
+
+/// Writes blocks to namespace `nsid` of drive `hd`, starting at block `lba`.
+/// The length of `buf` must be a multiple of the block size of the namespace.
+/// Returns `Error::NotFound` if there is no drive `hd` or no such namespace, e.g. on a diskless boot.
+pub fn write_block(hd: usize, nsid: u32, lba: u64, buf: &[u8]) -> Result<()> {
+    let mut cons = NVME_CONS.lock();
+    let nvme = cons.get_mut(hd).ok_or(Error::NotFound)?;
+    check_length(&nvme.namespace(nsid)?, buf.len())?;
+
+    let dma: Dma<u8> = Dma::allocate(buf.len())?;
+    unsafe { dma.virt.copy_from(buf.as_ptr(), buf.len()) };
+    nvme.write(nsid, &dma, lba)
+}
+
+/// Same as `read_block`, on the first namespace of the drive.
+pub fn read_block_first_ns(hd: usize, lba: u64, buf: &mut [u8]) -> Result<()> {
+    let nsid = first_namespace(hd).ok_or(Error::NotFound)?;
+    read_block(hd, nsid, lba, buf)
+}
+
+/// Same as `write_block`, on the first namespace of the drive.
+pub fn write_block_first_ns(hd: usize, lba: u64, buf: &[u8]) -> Result<()> {
+    let nsid = first_namespace(hd).ok_or(Error::NotFound)?;
+    write_block(hd, nsid, lba, buf)
+}
+
+fn check_length(ns: &NvmeNamespace, len: usize) -> Result<()> {
+    if len == 0 || len as u64 % ns.block_size != 0 {
+        return Err(Error::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// Shuts down every NVMe drive, so their write caches are flushed.
+/// Call it right before powering off or rebooting. The drives are removed,
+/// so the block functions return `Error::NotFound` afterwards instead of waiting on them.
+pub fn shutdown() {
     let mut cons = NVME_CONS.lock();
-    let nvme = cons.get_mut(hd).expect("Cannot get hd");
-    nvme.read(&dma, block_id).expect("Cannot read");
-    unsafe { buf.as_mut_ptr().copy_from(dma.virt, 512) };
+    for (hd, mut nvme) in cons.drain(..).enumerate() {
+        if let Err(err) = nvme.shutdown() {
+            log::warn!("Failed to shut down NVMe drive {}: {}", hd, err);
+        }
+    }
+    NVME_SIZES.lock().clear();
 }
 
-/// Writes a block to the NVMe driver at block block_id
-pub fn write_block(hd: usize, block_id: u64, buf: &[u8]) {
-    let dma: Dma<u8> = Dma::allocate(buf.len()).expect("Cannot allocate frame");
-    unsafe { dma.virt.copy_from(buf.as_ptr(), 512) };
+/// Sets the interrupt coalescing of drive `hd`, see `NvmeDevice::set_interrupt_coalescing`.
+pub fn set_interrupt_coalescing(hd: usize, threshold: u8, time_100us: u8) -> Result<()> {
     let mut cons = NVME_CONS.lock();
-    let nvme = cons.get_mut(hd).expect("Cannot get hd");
-    nvme.write(&dma, block_id).expect("Cannot write");
+    let nvme = cons.get_mut(hd).ok_or(Error::NotFound)?;
+    nvme.set_interrupt_coalescing(threshold, time_100us)
+}
+
+/// Returns the Identify Controller data of drive `hd`, e.g. its model and serial number.
+pub fn controller_info(hd: usize) -> Option<NvmeControllerInfo> {
+    let cons = NVME_CONS.lock();
+    Some(cons.get(hd)?.controller_info().clone())
+}
+
+/// Returns the Identify Namespace data of namespace `nsid` of drive `hd`.
+pub fn namespace_info(hd: usize, nsid: u32) -> Result<NvmeNamespaceInfo> {
+    let cons = NVME_CONS.lock();
+    let nvme = cons.get(hd).ok_or(Error::NotFound)?;
+    nvme.namespace_info(nsid).cloned()
+}
+
+/// Returns the namespaces of drive `hd` with their geometry, ordered by id.
+pub fn get_namespaces(hd: usize) -> Vec<NvmeNamespace> {
+    let cons = NVME_CONS.lock();
+    cons.get(hd)
+        .map(|nvme| nvme.namespaces.values().copied().collect())
+        .unwrap_or_default()
+}
+
+/// Returns the lowest namespace id of drive `hd`.
+pub fn first_namespace(hd: usize) -> Option<u32> {
+    let cons = NVME_CONS.lock();
+    cons.get(hd)?.namespaces.keys().next().copied()
 }
 
-/// Gets the number of NVMe drives
+/// Gets the number of NVMe drives, 0 on a diskless boot.
 pub fn get_hd_num() -> usize {
     let cons = NVME_CONS.lock();
     cons.len()
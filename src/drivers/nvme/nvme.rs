@@ -1,15 +1,16 @@
-use alloc::boxed::Box;
 use alloc::collections::btree_map::BTreeMap;
-use alloc::string::String;
 use alloc::vec::Vec;
 
 use crate::drivers::nvme::memory::Dma;
+use crate::drivers::hpet::uptime_ms;
 use crate::drivers::nvme::NvmeStats;
+use crate::memory::dma;
 
 use super::cmd::NvmeCommand;
-use super::memory::DmaSlice;
+use super::identify::{NvmeControllerInfo, NvmeNamespaceInfo, IDENTIFY_DATA_SIZE};
+use super::memory::{DmaSlice, PAGE_SIZE};
 use super::{queues::*, NvmeNamespace};
-use core::error::Error;
+use crate::{Error, Result};
 use core::hint::spin_loop;
 
 // clippy doesnt like this
@@ -46,6 +47,45 @@ pub enum NvmeRegs64 {
     PMRMSC = 0xE14, // Persistent Memory Buffer Space Control
 }
 
+/// The most a command may transfer with PRP1 and a single PRP list page of 512 entries.
+const PRP_TRANSFER_SIZE: usize = 512 * PAGE_SIZE;
+
+/// Returns the number of pages a transfer of `bytes` at `addr` touches.
+fn prp_pages(addr: u64, bytes: u64) -> u64 {
+    let offset = addr % PAGE_SIZE as u64;
+    (offset + bytes).div_ceil(PAGE_SIZE as u64)
+}
+
+/// Fills `list` with the addresses of the pages of a physically contiguous transfer after the first one,
+/// which PRP1 points at. Returns the number of entries.
+fn build_prp_list(addr: u64, bytes: u64, list: &mut [u64; 512]) -> usize {
+    let first_page = addr & !(PAGE_SIZE as u64 - 1);
+    let entries = prp_pages(addr, bytes).saturating_sub(1) as usize;
+    assert!(entries <= list.len(), "transfer too large for one PRP list");
+    for (index, entry) in list[..entries].iter_mut().enumerate() {
+        *entry = first_page + (index as u64 + 1) * PAGE_SIZE as u64;
+    }
+    entries
+}
+
+/// Shutdown Notification field of CC
+const CC_SHN_MASK: u32 = 0b11 << 14;
+const CC_SHN_NORMAL: u32 = 0b01 << 14;
+/// Shutdown Status field of CSTS
+const CSTS_SHST_MASK: u32 = 0b11 << 2;
+const CSTS_SHST_COMPLETE: u32 = 0b10 << 2;
+
+/// Feature id of the interrupt coalescing
+const FEATURE_INTERRUPT_COALESCING: u8 = 0x08;
+/// Generic status code: Invalid Field in Command
+const STATUS_INVALID_FIELD: u16 = 0x02;
+
+/// Builds the dword 11 of the interrupt coalescing feature:
+/// the aggregation threshold in bits 7:0 and the aggregation time in bits 15:8.
+fn interrupt_coalescing(threshold: u8, time_100us: u8) -> u32 {
+    u32::from(time_100us) << 8 | u32::from(threshold)
+}
+
 #[allow(non_camel_case_types)]
 #[derive(Copy, Clone, Debug)]
 pub enum NvmeArrayRegs {
@@ -53,50 +93,6 @@ pub enum NvmeArrayRegs {
     CQyHDBL,
 }
 
-// who tf is abbreviating this stuff
-#[repr(C, packed)]
-#[derive(Debug, Clone, Copy)]
-#[allow(unused)]
-struct IdentifyNamespaceData {
-    pub nsze: u64,
-    pub ncap: u64,
-    nuse: u64,
-    nsfeat: u8,
-    pub nlbaf: u8,
-    pub flbas: u8,
-    mc: u8,
-    dpc: u8,
-    dps: u8,
-    nmic: u8,
-    rescap: u8,
-    fpi: u8,
-    dlfeat: u8,
-    nawun: u16,
-    nawupf: u16,
-    nacwu: u16,
-    nabsn: u16,
-    nabo: u16,
-    nabspf: u16,
-    noiob: u16,
-    nvmcap: u128,
-    npwg: u16,
-    npwa: u16,
-    npdg: u16,
-    npda: u16,
-    nows: u16,
-    _rsvd1: [u8; 18],
-    anagrpid: u32,
-    _rsvd2: [u8; 3],
-    nsattr: u8,
-    nvmsetid: u16,
-    endgid: u16,
-    nguid: [u8; 16],
-    eui64: u64,
-    pub lba_format_support: [u32; 16],
-    _rsvd3: [u8; 192],
-    vendor_specific: [u8; 3712],
-}
-
 pub struct NvmeQueuePair {
     pub id: u16,
     pub sub_queue: NvmeSubQueue,
@@ -140,6 +136,7 @@ impl NvmeQueuePair {
             };
 
             if let Some(tail) = self.sub_queue.submit_checked(entry) {
+                dma::wmb();
                 unsafe {
                     core::ptr::write_volatile(self.sub_queue.doorbell as *mut u32, tail as u32);
                 }
@@ -162,7 +159,7 @@ impl NvmeQueuePair {
             core::ptr::write_volatile(self.comp_queue.doorbell as *mut u32, tail as u32);
         }
         self.sub_queue.head = c_entry.sq_head as usize;
-        let status = c_entry.status >> 1;
+        let status = c_entry.status_code();
         if status != 0 {
             log::error!(
                 "Status: 0x{:x}, Status Code 0x{:x}, Status Code Type: 0x{:x}",
@@ -182,7 +179,7 @@ impl NvmeQueuePair {
                 core::ptr::write_volatile(self.comp_queue.doorbell as *mut u32, tail as u32);
             }
             self.sub_queue.head = c_entry.sq_head as usize;
-            let status = c_entry.status >> 1;
+            let status = c_entry.status_code();
             if status != 0 {
                 log::error!(
                     "Status: 0x{:x}, Status Code 0x{:x}, Status Code Type: 0x{:x}",
@@ -209,8 +206,12 @@ pub struct NvmeDevice {
     io_sq: NvmeSubQueue,
     io_cq: NvmeCompQueue,
     buffer: Dma<u8>,           // 2MiB of buffer
-    prp_list: Dma<[u64; 512]>, // Address of PRP's, devices doesn't necessarily support 2MiB page sizes; 8 Bytes * 512 = 4096
+    prp_list: Dma<[u64; 512]>, // PRP list of the current transfer, 8 Bytes * 512 = 4096
     pub namespaces: BTreeMap<u32, NvmeNamespace>,
+    controller_info: NvmeControllerInfo,
+    /// The MDTS of the controller in bytes, `usize::MAX` if it has no limit.
+    max_transfer_bytes: usize,
+    namespace_infos: BTreeMap<u32, NvmeNamespaceInfo>,
     pub stats: NvmeStats,
     q_id: u16,
 }
@@ -221,7 +222,7 @@ unsafe impl Sync for NvmeDevice {}
 
 #[allow(unused)]
 impl NvmeDevice {
-    pub fn init(addr: usize, len: usize) -> Result<Self, Box<dyn Error>> {
+    pub fn init(addr: usize, len: usize) -> Result<Self> {
         let mut dev = Self {
             addr: addr as *mut u8,
             dstrd: {
@@ -240,14 +241,13 @@ impl NvmeDevice {
             buffer: Dma::allocate(4096)?,
             prp_list: Dma::allocate(8 * 512)?,
             namespaces: BTreeMap::new(),
+            controller_info: NvmeControllerInfo::default(),
+            max_transfer_bytes: usize::MAX,
+            namespace_infos: BTreeMap::new(),
             stats: NvmeStats::default(),
             q_id: 1,
         };
 
-        for i in 1..512 {
-            dev.prp_list[i - 1] = (dev.buffer.phys + i * 4096) as u64;
-        }
-
         log::info!("CAP: 0x{:x}", dev.get_reg64(NvmeRegs64::CAP as u64));
         log::info!("VS: 0x{:x}", dev.get_reg32(NvmeRegs32::VS as u32));
         log::info!("CC: 0x{:x}", dev.get_reg32(NvmeRegs32::CC as u32));
@@ -258,14 +258,7 @@ impl NvmeDevice {
         dev.set_reg32(NvmeRegs32::CC as u32, ctrl_config);
 
         // Wait for not ready
-        loop {
-            let csts = dev.get_reg32(NvmeRegs32::CSTS as u32);
-            if csts & 1 == 1 {
-                spin_loop();
-            } else {
-                break;
-            }
-        }
+        dev.wait_ready(false)?;
 
         // Configure Admin Queues
         dev.set_reg64(NvmeRegs64::ASQ as u32, dev.admin_sq.get_addr() as u64);
@@ -295,14 +288,7 @@ impl NvmeDevice {
         dev.set_reg32(NvmeRegs32::CC as u32, ctrl_config);
 
         // wait for ready
-        loop {
-            let csts = dev.get_reg32(NvmeRegs32::CSTS as u32);
-            if csts & 1 == 0 {
-                spin_loop();
-            } else {
-                break;
-            }
-        }
+        dev.wait_ready(true)?;
 
         let q_id = dev.q_id;
         let addr = dev.io_cq.get_addr();
@@ -326,49 +312,41 @@ impl NvmeDevice {
         Ok(dev)
     }
 
-    pub fn identify_controller(&mut self) -> Result<(), Box<dyn Error>> {
+    pub fn identify_controller(&mut self) -> Result<()> {
         log::info!("Trying to identify controller");
-        let _entry = self.submit_and_complete_admin(NvmeCommand::identify_controller);
-
-        log::info!("Dumping identify controller");
-        let mut serial = String::new();
-        let data = &self.buffer;
-
-        for &b in &data[4..24] {
-            if b == 0 {
-                break;
-            }
-            serial.push(b as char);
-        }
-
-        let mut model = String::new();
-        for &b in &data[24..64] {
-            if b == 0 {
-                break;
-            }
-            model.push(b as char);
+        self.submit_and_complete_admin(NvmeCommand::identify_controller)?;
+
+        let info = NvmeControllerInfo::parse(&self.buffer[..IDENTIFY_DATA_SIZE]);
+        // MDTS is a power of two of the minimum memory page size, 2 ^ (12 + CAP.MPSMIN).
+        if info.mdts != 0 {
+            let mpsmin = ((self.get_reg64(NvmeRegs64::CAP as u64) >> 48) & 0xF) as u32;
+            self.max_transfer_bytes = 1usize
+                .checked_shl(12 + mpsmin + info.mdts as u32)
+                .unwrap_or(usize::MAX);
+            log::info!("  - Maximum transfer size: {} bytes", self.max_transfer_bytes);
         }
-
-        let mut firmware = String::new();
-        for &b in &data[64..72] {
-            if b == 0 {
-                break;
-            }
-            firmware.push(b as char);
-        }
-
         log::info!(
             "  - Model: {} Serial: {} Firmware: {}",
-            model.trim(),
-            serial.trim(),
-            firmware.trim()
+            info.model,
+            info.serial,
+            info.firmware
         );
-
+        self.controller_info = info;
         Ok(())
     }
 
+    /// Returns the Identify Controller data read by `identify_controller`.
+    pub fn controller_info(&self) -> &NvmeControllerInfo {
+        &self.controller_info
+    }
+
+    /// Returns the Identify Namespace data of a namespace found by `identify_namespace`.
+    pub fn namespace_info(&self, ns_id: u32) -> Result<&NvmeNamespaceInfo> {
+        self.namespace_infos.get(&ns_id).ok_or(Error::NotFound)
+    }
+
     // 1 to 1 Submission/Completion Queue Mapping
-    pub fn create_io_queue_pair(&mut self, len: usize) -> Result<NvmeQueuePair, Box<dyn Error>> {
+    pub fn create_io_queue_pair(&mut self, len: usize) -> Result<NvmeQueuePair> {
         let q_id = self.q_id;
         log::info!("Requesting i/o queue pair with id {q_id}");
 
@@ -407,7 +385,7 @@ impl NvmeDevice {
         })
     }
 
-    pub fn delete_io_queue_pair(&mut self, qpair: NvmeQueuePair) -> Result<(), Box<dyn Error>> {
+    pub fn delete_io_queue_pair(&mut self, qpair: NvmeQueuePair) -> Result<()> {
         log::info!("Deleting i/o queue pair with id {}", qpair.id);
         self.submit_and_complete_admin(|c_id, _| {
             NvmeCommand::delete_io_submission_queue(c_id, qpair.id)
@@ -438,21 +416,10 @@ impl NvmeDevice {
             NvmeCommand::identify_namespace(c_id, addr, id)
         });
 
-        let namespace_data: IdentifyNamespaceData =
-            unsafe { *(self.buffer.virt as *const IdentifyNamespaceData) };
-
-        // let namespace_data = unsafe { *tmp_buff.virt };
-        let size = namespace_data.nsze;
-        let blocks = namespace_data.ncap;
-
-        // figure out block size
-        let flba_idx = (namespace_data.flbas & 0xF) as usize;
-        let flba_data = (namespace_data.lba_format_support[flba_idx] >> 16) & 0xFF;
-        let block_size = if !(9..32).contains(&flba_data) {
-            0
-        } else {
-            1 << flba_data
-        };
+        let info = NvmeNamespaceInfo::parse(id, &self.buffer[..IDENTIFY_DATA_SIZE]);
+        let size = info.size;
+        let blocks = info.capacity;
+        let block_size = info.lba_format().block_size;
 
         // TODO: check metadata?
         log::info!("Namespace {id}, Size: {size}, Blocks: {blocks}, Block size: {block_size}");
@@ -463,47 +430,90 @@ impl NvmeDevice {
             block_size,
         };
         self.namespaces.insert(id, namespace);
+        self.namespace_infos.insert(id, info);
         (namespace, blocks * block_size)
     }
 
-    // TODO: currently namespace 1 is hardcoded
-    pub fn write(&mut self, data: &impl DmaSlice, mut lba: u64) -> Result<(), Box<dyn Error>> {
-        for chunk in data.chunks(2 * 4096) {
-            let blocks = (chunk.slice.len() as u64 + 512 - 1) / 512;
-            self.namespace_io(1, blocks, lba, chunk.phys_addr as u64, true)?;
-            lba += blocks;
+    /// Returns the geometry of a namespace found by `identify_namespace`.
+    pub fn namespace(&self, ns_id: u32) -> Result<NvmeNamespace> {
+        match self.namespaces.get(&ns_id) {
+            Some(ns) if ns.block_size != 0 => Ok(*ns),
+            Some(_) => Err(Error::Unsupported),
+            None => Err(Error::NotFound),
         }
+    }
+
+    /// Returns the largest transfer a single command may do on `ns`, in bytes.
+    /// It is within the MDTS of the controller, the PRP entries the driver builds and the 16-bit block count,
+    /// and a multiple of both the page size and the block size, so the commands split on page boundaries.
+    fn max_transfer_size(&self, ns: &NvmeNamespace) -> usize {
+        let block_size = ns.block_size as usize;
+        let bytes = self
+            .max_transfer_bytes
+            .min(PRP_TRANSFER_SIZE)
+            .min(0x1_0000 * block_size);
+        (bytes / block_size * block_size).max(block_size)
+    }
 
+    pub fn write(
+        &mut self,
+        ns_id: u32,
+        data: &impl DmaSlice,
+        mut lba: u64,
+    ) -> Result<()> {
+        let ns = self.namespace(ns_id)?;
+        for chunk in data.chunks(self.max_transfer_size(&ns)) {
+            let blocks = (chunk.slice.len() as u64 + ns.block_size - 1) / ns.block_size;
+            self.namespace_io(&ns, blocks, lba, chunk.phys_addr as u64, true)?;
+            lba += blocks;
+        }
         Ok(())
     }
 
-    pub fn read(&mut self, dest: &impl DmaSlice, mut lba: u64) -> Result<(), Box<dyn Error>> {
-        // let ns = *self.namespaces.get(&1).unwrap();
-        for chunk in dest.chunks(2 * 4096) {
-            let blocks = (chunk.slice.len() as u64 + 512 - 1) / 512;
-            self.namespace_io(1, blocks, lba, chunk.phys_addr as u64, false)?;
+    pub fn read(
+        &mut self,
+        ns_id: u32,
+        dest: &impl DmaSlice,
+        mut lba: u64,
+    ) -> Result<()> {
+        let ns = self.namespace(ns_id)?;
+        for chunk in dest.chunks(self.max_transfer_size(&ns)) {
+            let blocks = (chunk.slice.len() as u64 + ns.block_size - 1) / ns.block_size;
+            self.namespace_io(&ns, blocks, lba, chunk.phys_addr as u64, false)?;
             lba += blocks;
         }
         Ok(())
     }
 
-    pub fn write_copied(&mut self, data: &[u8], mut lba: u64) -> Result<(), Box<dyn Error>> {
-        let ns = *self.namespaces.get(&1).unwrap();
-        for chunk in data.chunks(128 * 4096) {
+    pub fn write_copied(
+        &mut self,
+        ns_id: u32,
+        data: &[u8],
+        mut lba: u64,
+    ) -> Result<()> {
+        let ns = self.namespace(ns_id)?;
+        let chunk_size = self.max_transfer_size(&ns).min(self.buffer.size);
+        for chunk in data.chunks(chunk_size) {
             self.buffer[..chunk.len()].copy_from_slice(chunk);
             let blocks = (chunk.len() as u64 + ns.block_size - 1) / ns.block_size;
-            self.namespace_io(1, blocks, lba, self.buffer.phys as u64, true)?;
+            self.namespace_io(&ns, blocks, lba, self.buffer.phys as u64, true)?;
             lba += blocks;
         }
 
         Ok(())
     }
 
-    pub fn read_copied(&mut self, dest: &mut [u8], mut lba: u64) -> Result<(), Box<dyn Error>> {
-        let ns = *self.namespaces.get(&1).unwrap();
-        for chunk in dest.chunks_mut(128 * 4096) {
+    pub fn read_copied(
+        &mut self,
+        ns_id: u32,
+        dest: &mut [u8],
+        mut lba: u64,
+    ) -> Result<()> {
+        let ns = self.namespace(ns_id)?;
+        let chunk_size = self.max_transfer_size(&ns).min(self.buffer.size);
+        for chunk in dest.chunks_mut(chunk_size) {
             let blocks = (chunk.len() as u64 + ns.block_size - 1) / ns.block_size;
-            self.namespace_io(1, blocks, lba, self.buffer.phys as u64, false)?;
+            self.namespace_io(&ns, blocks, lba, self.buffer.phys as u64, false)?;
             lba += blocks;
             chunk.copy_from_slice(&self.buffer[..chunk.len()]);
         }
@@ -522,16 +532,11 @@ impl NvmeDevice {
         assert!(blocks <= 0x1_0000);
         let q_id = 1;
 
+        // The batches stay within a page. They never need the PRP list,
+        // which only one command in flight may use.
         let bytes = blocks * ns.block_size;
-        let ptr1 = if bytes <= 4096 {
-            0
-        } else if bytes <= 8192 {
-            addr + 4096 // self.page_size
-        } else {
-            // idk if this works
-            let offset = (addr - self.buffer.phys as u64) / 8;
-            self.prp_list.phys as u64 + offset
-        };
+        assert!(prp_pages(addr, bytes) <= 2);
+        let ptr1 = self.prp2(addr, bytes);
 
         let entry = if write {
             NvmeCommand::io_write(
@@ -561,7 +566,7 @@ impl NvmeDevice {
         let (tail, c_entry, _) = self.io_cq.complete_n(step as usize);
         self.write_reg_idx(NvmeArrayRegs::CQyHDBL, q_id as u16, tail as u32);
 
-        let status = c_entry.status >> 1;
+        let status = c_entry.status_code();
         if status != 0 {
             log::error!(
                 "Status: 0x{:x}, Status Code 0x{:x}, Status Code Type: 0x{:x}",
@@ -582,7 +587,7 @@ impl NvmeDevice {
         data: &[u8],
         mut lba: u64,
         batch_len: u64,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<()> {
         let ns = *self.namespaces.get(&ns_id).unwrap();
         let block_size = 512;
         let q_id = 1;
@@ -622,7 +627,7 @@ impl NvmeDevice {
         data: &mut [u8],
         mut lba: u64,
         batch_len: u64,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<()> {
         let ns = *self.namespaces.get(&ns_id).unwrap();
         let block_size = 512;
         let q_id = 1;
@@ -655,34 +660,40 @@ impl NvmeDevice {
         Ok(())
     }
 
+    /// Returns the PRP2 entry of a physically contiguous transfer of `bytes` at `addr`.
+    /// It is the second page if the transfer spans two pages, otherwise the PRP list, filled with every
+    /// page after the first one. The command must complete before the list is built again.
+    fn prp2(&mut self, addr: u64, bytes: u64) -> u64 {
+        match prp_pages(addr, bytes) {
+            0 | 1 => 0,
+            2 => (addr & !(PAGE_SIZE as u64 - 1)) + PAGE_SIZE as u64,
+            _ => {
+                build_prp_list(addr, bytes, &mut self.prp_list);
+                self.prp_list.phys as u64
+            }
+        }
+    }
+
     #[inline(always)]
     fn namespace_io(
         &mut self,
-        ns_id: u32,
+        ns: &NvmeNamespace,
         blocks: u64,
         lba: u64,
         addr: u64,
         write: bool,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<()> {
         assert!(blocks > 0);
         assert!(blocks <= 0x1_0000);
 
         let q_id = 1;
 
-        let bytes = blocks * 512;
-        let ptr1 = if bytes <= 4096 {
-            0
-        } else if bytes <= 8192 {
-            // self.buffer.phys as u64 + 4096 // self.page_size
-            addr + 4096 // self.page_size
-        } else {
-            self.prp_list.phys as u64
-        };
+        let ptr1 = self.prp2(addr, blocks * ns.block_size);
 
         let entry = if write {
             NvmeCommand::io_write(
                 self.io_sq.tail as u16,
-                ns_id,
+                ns.id,
                 lba,
                 blocks as u16 - 1,
                 addr,
@@ -691,7 +702,7 @@ impl NvmeDevice {
         } else {
             NvmeCommand::io_read(
                 self.io_sq.tail as u16,
-                ns_id,
+                ns.id,
                 lba,
                 blocks as u16 - 1,
                 addr,
@@ -707,17 +718,59 @@ impl NvmeDevice {
         Ok(())
     }
 
-    fn submit_and_complete_admin<F: FnOnce(u16, usize) -> NvmeCommand>(
+    /// Notifies the controller of a normal shutdown with CC.SHN, so it flushes its volatile write cache,
+    /// then waits for CSTS.SHST to report that it is done.
+    /// The controller must not be used afterwards.
+    pub fn shutdown(&mut self) -> Result<()> {
+        let cc = self.get_reg32(NvmeRegs32::CC as u32) & !CC_SHN_MASK;
+        self.set_reg32(NvmeRegs32::CC as u32, cc | CC_SHN_NORMAL);
+        self.wait_shutdown()
+    }
+
+    /// Configures the interrupt coalescing of the I/O completion queues, with the Set Features command.
+    /// The controller waits for `threshold` + 1 completions or `time_100us` * 100 microseconds,
+    /// whichever comes first, before it raises an interrupt. Zero for both turns it off.
+    ///
+    /// Returns `Error::Unsupported` if the controller rejects the feature.
+    pub fn set_interrupt_coalescing(&mut self, threshold: u8, time_100us: u8) -> Result<()> {
+        let entry = self.submit_admin(|c_id, _| {
+            NvmeCommand::set_features(
+                c_id,
+                FEATURE_INTERRUPT_COALESCING,
+                interrupt_coalescing(threshold, time_100us),
+            )
+        });
+        match entry.status_code() {
+            0 => Ok(()),
+            // Generic command status, Invalid Field in Command: the feature id isn't supported.
+            STATUS_INVALID_FIELD => Err(Error::Unsupported),
+            status => {
+                log::error!("Set interrupt coalescing failed, status: 0x{:x}", status);
+                Err(Error::DeviceError)
+            }
+        }
+    }
+
+    /// Submits an admin command and spins for its completion, whatever its status.
+    fn submit_admin<F: FnOnce(u16, usize) -> NvmeCommand>(
         &mut self,
         cmd_init: F,
-    ) -> Result<NvmeCompletion, Box<dyn Error>> {
+    ) -> NvmeCompletion {
         let cid = self.admin_sq.tail;
         let tail = self.admin_sq.submit(cmd_init(cid as u16, self.buffer.phys));
         self.write_reg_idx(NvmeArrayRegs::SQyTDBL, 0, tail as u32);
 
         let (head, entry, _) = self.admin_cq.complete_spin();
         self.write_reg_idx(NvmeArrayRegs::CQyHDBL, 0, head as u32);
-        let status = entry.status >> 1;
+        entry
+    }
+
+    fn submit_and_complete_admin<F: FnOnce(u16, usize) -> NvmeCommand>(
+        &mut self,
+        cmd_init: F,
+    ) -> Result<NvmeCompletion> {
+        let entry = self.submit_admin(cmd_init);
+        let status = entry.status_code();
         if status != 0 {
             log::error!(
                 "Status: 0x{:x}, Status Code 0x{:x}, Status Code Type: 0x{:x}",
@@ -725,7 +778,7 @@ impl NvmeDevice {
                 status & 0xFF,
                 (status >> 8) & 0x7
             );
-            return Err("Requesting i/o completion queue failed".into());
+            return Err(Error::DeviceError);
         }
         Ok(entry)
     }
@@ -741,9 +794,11 @@ impl NvmeDevice {
     }
 
     /// Sets Queue `qid` Tail Doorbell to `val`
+    /// The submission doorbell is preceded by a write barrier, so the device sees the new entries.
     fn write_reg_idx(&self, reg: NvmeArrayRegs, qid: u16, val: u32) {
         match reg {
             NvmeArrayRegs::SQyTDBL => unsafe {
+                dma::wmb();
                 core::ptr::write_volatile(
                     (self.addr as usize + 0x1000 + ((4 << self.dstrd) * (2 * qid)) as usize)
                         as *mut u32,
@@ -773,6 +828,33 @@ impl NvmeDevice {
         }
     }
 
+    /// Waits for CSTS.RDY to become `ready`, for at most the timeout in CAP.TO.
+    fn wait_ready(&self, ready: bool) -> Result<()> {
+        // CAP.TO is in units of 500 ms.
+        let timeout = ((self.get_reg64(NvmeRegs64::CAP as u64) >> 24) & 0xFF).max(1) * 500;
+        let start = uptime_ms();
+        while (self.get_reg32(NvmeRegs32::CSTS as u32) & 1 == 1) != ready {
+            if uptime_ms() - start > timeout {
+                return Err(Error::Timeout);
+            }
+            spin_loop();
+        }
+        Ok(())
+    }
+
+    /// Waits for CSTS.SHST to report that the shutdown is complete, for at most the timeout in CAP.TO.
+    fn wait_shutdown(&self) -> Result<()> {
+        let timeout = ((self.get_reg64(NvmeRegs64::CAP as u64) >> 24) & 0xFF).max(1) * 500;
+        let start = uptime_ms();
+        while self.get_reg32(NvmeRegs32::CSTS as u32) & CSTS_SHST_MASK != CSTS_SHST_COMPLETE {
+            if uptime_ms() - start > timeout {
+                return Err(Error::Timeout);
+            }
+            spin_loop();
+        }
+        Ok(())
+    }
+
     /// Returns the register at `self.addr` + `reg`.
     ///
     /// # Panics
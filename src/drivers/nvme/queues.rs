@@ -1,10 +1,10 @@
-use alloc::boxed::Box;
-
 use super::cmd::NvmeCommand;
 use super::memory::Dma;
-use core::error::Error;
+use crate::Result;
 use core::hint::spin_loop;
 
+use crate::memory::dma;
+
 /// NVMe spec 4.6
 /// Completion queue entry
 #[allow(dead_code)]
@@ -25,6 +25,14 @@ pub struct NvmeCompletion {
     pub status: u16,
 }
 
+impl NvmeCompletion {
+    /// Returns the Status Code in bits 7:0 and the Status Code Type in bits 10:8,
+    /// without the phase tag and the Command Retry Delay, More and Do Not Retry bits.
+    pub fn status_code(&self) -> u16 {
+        (self.status >> 1) & 0x7ff
+    }
+}
+
 /// maximum amount of submission entries on a 2MiB huge page
 pub const QUEUE_LENGTH: usize = 1024;
 
@@ -39,7 +47,7 @@ pub struct NvmeSubQueue {
 }
 
 impl NvmeSubQueue {
-    pub fn new(len: usize, doorbell: usize) -> Result<Self, Box<dyn Error>> {
+    pub fn new(len: usize, doorbell: usize) -> Result<Self> {
         Ok(Self {
             commands: Dma::allocate(4096)?,
             head: 0,
@@ -90,7 +98,7 @@ pub struct NvmeCompQueue {
 
 // TODO: error handling
 impl NvmeCompQueue {
-    pub fn new(len: usize, doorbell: usize) -> Result<Self, Box<dyn Error>> {
+    pub fn new(len: usize, doorbell: usize) -> Result<Self> {
         Ok(Self {
             commands: Dma::allocate(4096)?,
             head: 0,
@@ -102,15 +110,20 @@ impl NvmeCompQueue {
 
     #[inline(always)]
     pub fn complete(&mut self) -> Option<(usize, NvmeCompletion, usize)> {
-        let entry = &self.commands[self.head];
-
-        if ((entry.status & 1) == 1) == self.phase {
+        let entry_ptr = &self.commands[self.head] as *const NvmeCompletion;
+        // The device writes the entry, so it must be read again on every poll.
+        let status = unsafe { core::ptr::read_volatile(entry_ptr) }.status;
+
+        if ((status & 1) == 1) == self.phase {
+            // Read the rest of the entry only after its phase bit.
+            dma::rmb();
+            let entry = unsafe { core::ptr::read_volatile(entry_ptr) };
             let prev = self.head;
             self.head = (self.head + 1) % self.len;
             if self.head == 0 {
                 self.phase = !self.phase;
             }
-            Some((self.head, entry.clone(), prev))
+            Some((self.head, entry, prev))
         } else {
             None
         }
@@ -4,6 +4,7 @@
 use alloc::vec::Vec;
 use alloc::{boxed::Box, collections::LinkedList};
 use bitflags::bitflags;
+use conquer_once::spin::OnceCell;
 use core::{
     convert::TryFrom,
     fmt::{self, Display, Formatter},
@@ -27,6 +28,8 @@ pub static PCI_ROOT_0: Lazy<Option<PciRoot>> = Lazy::new(|| match PciRoot::new(0
         None
     }
 });
+/// The devices found by `init`, see `enumerate`.
+static PCI_DEVICE_INFOS: OnceCell<Vec<PciDeviceInfo>> = OnceCell::uninit();
 
 /// A list with RwLock, which has all the devices we found.
 pub struct PciDeviceLinkedList {
@@ -64,6 +67,54 @@ impl PciDeviceLinkedList {
     }
 }
 
+/// A summary of a PCI device function, returned by `enumerate`.
+#[derive(Clone, Debug)]
+pub struct PciDeviceInfo {
+    pub bus_device_function: BusDeviceFunction,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class_code: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub header_type: HeaderType,
+    /// The six BARs of a standard device, indexed by BAR number, empty for bridges.
+    /// The upper half of a 64-bit memory BAR is `BarInfo::Unused`, the BARs are not mapped.
+    pub bars: Vec<BarInfo>,
+}
+
+impl PciDeviceInfo {
+    fn new(device: &dyn PciDeviceStructure) -> Self {
+        let common_header = device.common_header();
+        let bus_device_function = common_header.bus_device_function;
+        let bars = match device.header_type() {
+            HeaderType::Standard => match pci_bar_probe(bus_device_function) {
+                Ok(bars) => (0..6).map(|i| bars.get_bar(i).unwrap().clone()).collect(),
+                Err(e) => {
+                    warn!("Failed to probe the bars of {:?}: {}", bus_device_function, e);
+                    Vec::new()
+                }
+            },
+            _ => Vec::new(),
+        };
+        PciDeviceInfo {
+            bus_device_function,
+            vendor_id: common_header.vendor_id,
+            device_id: common_header.device_id,
+            class_code: common_header.class_code,
+            subclass: common_header.subclass,
+            prog_if: common_header.prog_if,
+            header_type: device.header_type(),
+            bars,
+        }
+    }
+}
+
+/// Returns all the device functions found on the PCI buses.
+/// The list is built once by `init`, it is empty before that.
+pub fn enumerate() -> Vec<PciDeviceInfo> {
+    PCI_DEVICE_INFOS.get().cloned().unwrap_or_default()
+}
+
 /// Return the device structure corresponding to the class code and subclass.
 /// The device structure is mutable.
 pub fn get_pci_device_structure_mut<'a>(
@@ -102,8 +153,16 @@ const BAR0_OFFSET: u8 = 0x10;
 const STATUS_COMMAND_OFFSET: u8 = 0x04;
 /// ID for vendor-specific PCI capabilities.(Virtio Capabilities)
 pub const PCI_CAP_ID_VNDR: u8 = 0x09;
+/// ID for the MSI-X capability.
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
 pub const PORT_PCI_CONFIG_ADDRESS: u16 = 0xcf8;
 pub const PORT_PCI_CONFIG_DATA: u16 = 0xcfc;
+/// 端口IO只能访问配置空间的前256字节
+const LEGACY_CONFIG_SPACE_SIZE: u16 = 0x100;
+/// PCIe每个function的配置空间大小，包括扩展配置空间
+const EXTENDED_CONFIG_SPACE_SIZE: u16 = 0x1000;
+/// ECAM中每条bus配置空间的大小
+const ECAM_BUS_SIZE: u64 = 1 << 20;
 // pci设备分组的id
 pub type SegmentGroupNumber = u16; //理论上最多支持65535个Segment_Group
 
@@ -200,6 +259,8 @@ pub enum PciError {
     GetWrongHeader,
     UnrecognisedHeaderType,
     PciDeviceStructureTransformError,
+    /// The register offset is unaligned or past the configuration space of the function.
+    InvalidConfigOffset(u16),
 }
 
 impl Display for PciError {
@@ -214,6 +275,9 @@ impl Display for PciError {
             Self::PciDeviceStructureTransformError => {
                 write!(f, "Found None When transform Pci device structure")
             }
+            Self::InvalidConfigOffset(offset) => {
+                write!(f, "Invalid configuration space offset {:#x}", offset)
+            }
         }
     }
 }
@@ -272,6 +336,35 @@ pub trait PciDeviceStructure: Send + Sync {
             command as u32,
         );
     }
+    /// Sets and clears bits of the Command register, read back from the device first.
+    fn update_command(&mut self, set: Command, clear: Command) {
+        let bus_device_function = self.common_header().bus_device_function;
+        let value = PciArch::read_config(&bus_device_function, STATUS_COMMAND_OFFSET) as u16;
+        let command = (Command::from_bits_truncate(value) | set) - clear;
+        self.set_command(command);
+    }
+    /// Lets the device respond to memory space accesses, needed for memory BARs.
+    fn enable_memory_space(&mut self) {
+        self.update_command(Command::MEMORY_SPACE, Command::empty());
+    }
+    /// Lets the device respond to I/O space accesses, needed for I/O BARs.
+    fn enable_io_space(&mut self) {
+        self.update_command(Command::IO_SPACE, Command::empty());
+    }
+    /// Lets the device issue DMA as a bus master.
+    fn enable_bus_master(&mut self) {
+        self.update_command(Command::BUS_MASTER, Command::empty());
+    }
+    /// Masks the INTx# pin, for devices using MSI/MSI-X or polling.
+    fn disable_legacy_interrupts(&mut self) {
+        self.update_command(Command::INTERRUPT_DISABLE, Command::empty());
+    }
+    /// Returns whether the MSI-X capability of the device is present and enabled.
+    fn msix_enabled(&self) -> bool {
+        self.capabilities().map_or(false, |mut capabilities| {
+            capabilities.any(|cap| cap.id == PCI_CAP_ID_MSIX && cap.private_header & 0x8000 != 0)
+        })
+    }
     /// Returns the mutable reference to the common header.
     fn common_header_mut(&mut self) -> &mut PciDeviceStructureHeader;
     /// @brief 读取standard设备的bar寄存器，映射后将结果加入结构体的standard_device_bar变量
@@ -284,7 +377,10 @@ pub trait PciDeviceStructure: Send + Sync {
         None
     }
     fn enable_master(&mut self) {
-        self.set_command(Command::IO_SPACE | Command::MEMORY_SPACE | Command::BUS_MASTER);
+        self.update_command(
+            Command::IO_SPACE | Command::MEMORY_SPACE | Command::BUS_MASTER,
+            Command::empty(),
+        );
     }
 }
 
@@ -497,13 +593,14 @@ impl PciRoot {
     /// @brief  完成物理地址到虚拟地址的映射，并将虚拟地址加入mmio_base变量
     /// @return 返回错误或Ok(0)
     fn map(&mut self) -> Result<u8, PciError> {
-        let bus_number = self.bus_end - self.bus_begin + 1;
-        let bus_number_double = (bus_number + 1) / 2;
+        // 每条bus占用1MB的配置空间(32个设备 * 8个功能 * 4096字节)
+        let bus_number = (self.bus_end - self.bus_begin) as u64 + 1;
+        let page_count = bus_number * ECAM_BUS_SIZE / 4096;
 
         let paddr = PhysAddr::new(self.physical_address_base);
         let vaddr = convert_physical_to_virtual(paddr);
 
-        for i in 0..bus_number_double {
+        for i in 0..page_count {
             let mut kernel_page_table = KERNEL_PAGE_TABLE.lock();
             unsafe {
                 kernel_page_table.map_to_with_table_flags_general(
@@ -548,7 +645,7 @@ impl PciRoot {
     /// @param register_offset 寄存器在设备中的offset
     /// @param data 要写入的值
     pub fn write_config(
-        &mut self,
+        &self,
         bus_device_function: BusDeviceFunction,
         register_offset: u16,
         data: u32,
@@ -561,6 +658,12 @@ impl PciRoot {
             (self.mmio_base.unwrap().add((address >> 2) as usize)).write_volatile(data)
         }
     }
+    /// @brief 判断设备是否在该PciRoot的bus范围内
+    pub fn contains(&self, bus_device_function: &BusDeviceFunction) -> bool {
+        self.mmio_base.is_some()
+            && (self.bus_begin..=self.bus_end).contains(&bus_device_function.bus)
+    }
+
     /// @brief 返回迭代器，遍历pcie设备的external_capabilities
     pub fn external_capabilities(
         &self,
@@ -573,6 +676,56 @@ impl PciRoot {
         }
     }
 }
+/// @brief 读取配置空间的寄存器（32位），可以访问PCIe扩展配置空间(0x100-0xfff)
+/// @param bus_device_function PCI设备的唯一标识
+/// @param offset 寄存器在配置空间中的offset
+/// @return u32 寄存器读值结果，无法访问或offset无效（未对齐、超出0xfff）时返回0xffffffff
+pub fn read_config_ext(bus_device_function: &BusDeviceFunction, offset: u16) -> u32 {
+    if !is_valid_config_offset(offset) {
+        return u32::MAX;
+    }
+    match &*PCI_ROOT_0 {
+        Some(root) if root.contains(bus_device_function) => {
+            root.read_config(*bus_device_function, offset)
+        }
+        _ if offset < LEGACY_CONFIG_SPACE_SIZE => {
+            PciArch::read_config(bus_device_function, offset as u8)
+        }
+        _ => u32::MAX,
+    }
+}
+
+/// @brief 写入配置空间的寄存器（32位），可以访问PCIe扩展配置空间(0x100-0xfff)
+/// @param bus_device_function PCI设备的唯一标识
+/// @param offset 寄存器在配置空间中的offset
+/// @param data 要写入的值
+/// @return 无法访问扩展配置空间或offset无效时返回错误
+pub fn write_config_ext(
+    bus_device_function: &BusDeviceFunction,
+    offset: u16,
+    data: u32,
+) -> Result<(), PciError> {
+    if !is_valid_config_offset(offset) {
+        return Err(PciError::InvalidConfigOffset(offset));
+    }
+    match &*PCI_ROOT_0 {
+        Some(root) if root.contains(bus_device_function) => {
+            root.write_config(*bus_device_function, offset, data);
+            Ok(())
+        }
+        _ if offset < LEGACY_CONFIG_SPACE_SIZE => {
+            PciArch::write_config(bus_device_function, offset as u8, data);
+            Ok(())
+        }
+        _ => Err(PciError::SegmentNotFound),
+    }
+}
+
+/// Returns whether `offset` is a dword-aligned register of the 4 KiB configuration space.
+fn is_valid_config_offset(offset: u16) -> bool {
+    offset < EXTENDED_CONFIG_SPACE_SIZE && offset & 0x3 == 0
+}
+
 /// Gets the capabilities 'pointer' for the device function, if any.
 /// @brief 获取第一个capability 的offset
 /// @param bus_device_function PCI设备的唯一标识
@@ -1001,6 +1154,11 @@ pub fn init() {
             HeaderType::Unrecognised(_) => {}
         }
     }
+    let infos = list
+        .iter()
+        .map(|device| PciDeviceInfo::new(device.as_ref()))
+        .collect();
+    PCI_DEVICE_INFOS.init_once(|| infos);
     info!("PCI bus initialized.");
 }
 
@@ -1197,6 +1355,22 @@ impl Default for PciStandardDeviceBar {
 ///@return Result<PciStandardDeviceBar, PciError> 成功则返回对应的PciStandardDeviceBar结构体，失败则返回错误类型
 pub fn pci_bar_init(
     bus_device_function: BusDeviceFunction,
+) -> Result<PciStandardDeviceBar, PciError> {
+    pci_bar_read(bus_device_function, true)
+}
+
+///@brief 读取某个pci设备的bar寄存器的地址与大小，但不进行映射
+///@param self ，bus_device_function PCI设备的唯一标识符
+///@return Result<PciStandardDeviceBar, PciError> 成功则返回对应的PciStandardDeviceBar结构体(virtaddress为0)，失败则返回错误类型
+pub fn pci_bar_probe(
+    bus_device_function: BusDeviceFunction,
+) -> Result<PciStandardDeviceBar, PciError> {
+    pci_bar_read(bus_device_function, false)
+}
+
+fn pci_bar_read(
+    bus_device_function: BusDeviceFunction,
+    map: bool,
 ) -> Result<PciStandardDeviceBar, PciError> {
     let mut device_bar: PciStandardDeviceBar = PciStandardDeviceBar::default();
     let mut bar_index_ignore: u8 = 255;
@@ -1243,16 +1417,18 @@ pub fn pci_bar_init(
             let paddr = PhysAddr::new(address);
             let vaddr = convert_physical_to_virtual(paddr);
 
-            for i in 0..(size / 4096) {
-                let mut kernel_page_table = KERNEL_PAGE_TABLE.lock();
-                unsafe {
-                    kernel_page_table.map_to_with_table_flags_general(
-                        Page::containing_address(vaddr + i as u64 * 4096),
-                        PhysFrame::containing_address(paddr + i as u64 * 4096),
-                        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                    )
-                };
+            if map {
+                for i in 0..(size / 4096) {
+                    let mut kernel_page_table = KERNEL_PAGE_TABLE.lock();
+                    unsafe {
+                        kernel_page_table.map_to_with_table_flags_general(
+                            Page::containing_address(vaddr + i as u64 * 4096),
+                            PhysFrame::containing_address(paddr + i as u64 * 4096),
+                            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                        )
+                    };
+                }
             }
 
             bar_info = BarInfo::Memory {
@@ -1260,7 +1436,7 @@ pub fn pci_bar_init(
                 prefetchable,
                 address,
                 size,
-                virtaddress: vaddr.as_u64(),
+                virtaddress: if map { vaddr.as_u64() } else { 0 },
             };
         }
         match bar_index {
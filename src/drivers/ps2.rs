@@ -0,0 +1,177 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::port::Port;
+
+const PORT_TRY_TIMES: u16 = 10_000;
+
+const CONFIG_FIRST_IRQ: u8 = 1 << 0;
+const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+const CONFIG_FIRST_CLOCK_DISABLED: u8 = 1 << 4;
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+/// Whether the first port passed its interface test and was enabled.
+static FIRST_PORT: AtomicBool = AtomicBool::new(false);
+/// Whether the controller has a second port, which passed its interface test and was enabled.
+static SECOND_PORT: AtomicBool = AtomicBool::new(false);
+
+/// Resets the 8042 controller to a known state and enables the ports that work.
+/// Call it before routing the keyboard and mouse IRQs.
+pub fn init() {
+    let mut controller = Ps2Controller::new();
+    let mut saved_config = None;
+    let (first, second) = match unsafe { controller.configure(&mut saved_config) } {
+        Ok((first, second)) => {
+            log::info!("PS/2 controller initialized, keyboard port: {}, mouse port: {}", first, second);
+            (first, second)
+        }
+        Err(err) => {
+            log::warn!("Failed to initialize the PS/2 controller, keeping its setup: {}", err);
+            unsafe { controller.restore(saved_config) }
+        }
+    };
+    FIRST_PORT.store(first, Ordering::SeqCst);
+    SECOND_PORT.store(second, Ordering::SeqCst);
+}
+
+/// Returns whether the keyboard port of the controller is enabled.
+pub fn first_port_available() -> bool {
+    FIRST_PORT.load(Ordering::SeqCst)
+}
+
+/// Returns whether the mouse port of the controller is enabled.
+pub fn second_port_available() -> bool {
+    SECOND_PORT.load(Ordering::SeqCst)
+}
+
+struct Ps2Controller {
+    command_port: Port<u8>,
+    data_port: Port<u8>,
+}
+
+impl Ps2Controller {
+    const fn new() -> Self {
+        Ps2Controller {
+            command_port: Port::new(0x64),
+            data_port: Port::new(0x60),
+        }
+    }
+
+    /// Restores the config byte saved by `configure` and enables the ports it had enabled,
+    /// so the devices which worked as the firmware set them up keep working.
+    /// The first port is enabled if the config couldn't be read. Returns which ports are enabled.
+    unsafe fn restore(&mut self, saved_config: Option<u8>) -> (bool, bool) {
+        let Some(config) = saved_config else {
+            return (self.write_command(0xae).is_ok(), false);
+        };
+        let _ = self.write_config(config);
+        let first = config & CONFIG_FIRST_CLOCK_DISABLED == 0 && self.write_command(0xae).is_ok();
+        let second =
+            config & CONFIG_SECOND_CLOCK_DISABLED == 0 && self.write_command(0xa8).is_ok();
+        (first, second)
+    }
+
+    /// Runs the self-test and the interface tests, returns which ports were enabled.
+    /// `saved_config` gets the config byte before anything is changed, for `restore`.
+    unsafe fn configure(
+        &mut self,
+        saved_config: &mut Option<u8>,
+    ) -> Result<(bool, bool), &'static str> {
+        // A pending byte of a device would be read as the config.
+        self.discard_output();
+        let config = self.read_config()?;
+        *saved_config = Some(config);
+
+        // Keep the devices quiet while the controller is reconfigured.
+        self.write_command(0xad)?;
+        self.write_command(0xa7)?;
+        self.discard_output();
+
+        let config = self.read_config()?;
+        let config = config & !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ | CONFIG_TRANSLATION);
+        self.write_config(config)?;
+
+        self.write_command(0xaa)?;
+        if self.read_data()? != 0x55 {
+            return Err("The controller failed its self-test!");
+        }
+        // The self-test may reset the controller.
+        self.write_config(config)?;
+
+        // Enabling the second port clears its clock-disabled bit if there is one.
+        self.write_command(0xa8)?;
+        let dual_channel = self.read_config()? & CONFIG_SECOND_CLOCK_DISABLED == 0;
+        self.write_command(0xa7)?;
+
+        self.write_command(0xab)?;
+        let first = self.read_data()? == 0x00;
+        let second = dual_channel && {
+            self.write_command(0xa9)?;
+            self.read_data()? == 0x00
+        };
+        if !first && !second {
+            return Err("No PS/2 port passed its interface test!");
+        }
+
+        let mut config = self.read_config()?;
+        if first {
+            self.write_command(0xae)?;
+            config |= CONFIG_FIRST_IRQ | CONFIG_TRANSLATION;
+        }
+        if second {
+            self.write_command(0xa8)?;
+            config |= CONFIG_SECOND_IRQ;
+        }
+        self.write_config(config)?;
+        Ok((first, second))
+    }
+
+    /// Drops the bytes waiting in the output buffer.
+    unsafe fn discard_output(&mut self) {
+        for _ in 0..PORT_TRY_TIMES {
+            if self.command_port.read() & 0x1 == 0 {
+                break;
+            }
+            self.data_port.read();
+        }
+    }
+
+    unsafe fn read_config(&mut self) -> Result<u8, &'static str> {
+        self.write_command(0x20)?;
+        self.read_data()
+    }
+
+    unsafe fn write_config(&mut self, config: u8) -> Result<(), &'static str> {
+        self.write_command(0x60)?;
+        self.write_data(config)
+    }
+
+    unsafe fn write_command(&mut self, command: u8) -> Result<(), &'static str> {
+        self.wait_for_write()?;
+        self.command_port.write(command);
+        Ok(())
+    }
+
+    unsafe fn write_data(&mut self, value: u8) -> Result<(), &'static str> {
+        self.wait_for_write()?;
+        self.data_port.write(value);
+        Ok(())
+    }
+
+    unsafe fn read_data(&mut self) -> Result<u8, &'static str> {
+        for _ in 0..PORT_TRY_TIMES {
+            if self.command_port.read() & 0x1 == 1 {
+                return Ok(self.data_port.read());
+            }
+        }
+        Err("Timed out waiting for the PS/2 controller to respond!")
+    }
+
+    unsafe fn wait_for_write(&mut self) -> Result<(), &'static str> {
+        for _ in 0..PORT_TRY_TIMES {
+            if self.command_port.read() & 0x2 == 0 {
+                return Ok(());
+            }
+        }
+        Err("Timed out waiting for the PS/2 controller to accept input!")
+    }
+}
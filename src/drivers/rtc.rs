@@ -2,6 +2,23 @@ use time::{error::ComponentRange, Time};
 use time::{Date, Month, OffsetDateTime, PrimitiveDateTime};
 use x86_64::instructions::port::Port;
 
+use crate::arch::apic::{get_lapic_id, route_irq, IrqVector};
+use crate::arch::interrupts::InterruptIndex;
+
+/// Routes the RTC interrupt to the current CPU.
+pub fn init() {
+    route_irq(
+        IrqVector::Rtc as u8,
+        InterruptIndex::Rtc as u8,
+        get_lapic_id() as u8,
+    );
+}
+
+/// Reads the status register C, so that the RTC can raise the next interrupt.
+pub fn acknowledge_interrupt() {
+    RtcDateTime::get_rtc_register(0x0c);
+}
+
 #[derive(Debug)]
 pub struct RtcDateTime {
     second: u8,
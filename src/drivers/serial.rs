@@ -1,8 +1,35 @@
 use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::{Lazy, Mutex};
 use uart_16550::SerialPort;
 use x86_64::instructions::interrupts;
 
+use crate::arch::apic::{get_lapic_id, route_irq, IrqVector};
+use crate::arch::interrupts::InterruptIndex;
+use crate::data::ring::SpscRing;
+use crate::drivers::keyboard;
+
+const RECEIVED_QUEUE_SIZE: usize = 128;
+
+/// The printable characters by their set 1 scancode on a US layout, without and with Shift.
+/// The zeros are keys without a character.
+const UNSHIFTED_KEYS: &[u8; 54] =
+    b"\0\x001234567890-=\0\0qwertyuiop[]\0\0asdfghjkl;'`\0\\zxcvbnm,./";
+const SHIFTED_KEYS: &[u8; 54] =
+    b"\0\0!@#$%^&*()_+\0\0QWERTYUIOP{}\0\0ASDFGHJKL:\"~\0|ZXCVBNM<>?";
+
+const SCANCODE_ESCAPE: u8 = 0x01;
+const SCANCODE_BACKSPACE: u8 = 0x0e;
+const SCANCODE_TAB: u8 = 0x0f;
+const SCANCODE_ENTER: u8 = 0x1c;
+const SCANCODE_CTRL: u8 = 0x1d;
+const SCANCODE_SHIFT: u8 = 0x2a;
+const SCANCODE_SPACE: u8 = 0x39;
+const SCANCODE_RELEASE: u8 = 0x80;
+
+/// Whether the last byte was a CR, so the LF of a CRLF isn't a second Enter.
+static LAST_WAS_CR: AtomicBool = AtomicBool::new(false);
+
 /// Print something to the serial port.
 #[macro_export]
 macro_rules! serial_print {
@@ -28,3 +55,78 @@ pub static SERIAL: Lazy<Mutex<SerialPort>> = Lazy::new(|| {
     serial_port.init();
     Mutex::new(serial_port)
 });
+
+static RECEIVED_QUEUE: SpscRing<u8, RECEIVED_QUEUE_SIZE> = SpscRing::new();
+/// Held while popping `RECEIVED_QUEUE`, so it has a single consumer.
+static RECEIVED_READER: Mutex<()> = Mutex::new(());
+
+/// Routes the COM1 interrupt to the current CPU.
+pub fn init() {
+    route_irq(
+        IrqVector::Serial as u8,
+        InterruptIndex::Serial as u8,
+        get_lapic_id() as u8,
+    );
+}
+
+/// Queues a byte from the serial interrupt handler.
+/// With the console on the serial port, it is also typed as a key press,
+/// so the keyboard subscribers and streams get the serial input as well.
+pub(crate) fn add_received(data: u8) {
+    // The COM1 interrupt is routed to a single CPU, so its handler is the only producer.
+    let _ = unsafe { RECEIVED_QUEUE.push(data) };
+    if crate::console::is_headless() {
+        type_key(data);
+    }
+}
+
+/// Injects the key presses typing the byte, with Shift or Ctrl held if needed.
+/// Bytes no key types, like the rest of an escape sequence after ESC, are dropped.
+fn type_key(byte: u8) {
+    let last_was_cr = LAST_WAS_CR.swap(byte == b'\r', Ordering::Relaxed);
+    let (scancode, modifier) = match byte {
+        b'\r' => (SCANCODE_ENTER, None),
+        b'\n' if last_was_cr => return,
+        b'\n' => (SCANCODE_ENTER, None),
+        0x08 | 0x7f => (SCANCODE_BACKSPACE, None),
+        b'\t' => (SCANCODE_TAB, None),
+        0x1b => (SCANCODE_ESCAPE, None),
+        b' ' => (SCANCODE_SPACE, None),
+        // Ctrl-A to Ctrl-Z.
+        0x01..=0x1a => match find_key(UNSHIFTED_KEYS, byte - 1 + b'a') {
+            Some(scancode) => (scancode, Some(SCANCODE_CTRL)),
+            None => return,
+        },
+        _ => match find_key(UNSHIFTED_KEYS, byte) {
+            Some(scancode) => (scancode, None),
+            None => match find_key(SHIFTED_KEYS, byte) {
+                Some(scancode) => (scancode, Some(SCANCODE_SHIFT)),
+                None => return,
+            },
+        },
+    };
+
+    match modifier {
+        Some(modifier) => keyboard::inject_scancodes(&[
+            modifier,
+            scancode,
+            scancode | SCANCODE_RELEASE,
+            modifier | SCANCODE_RELEASE,
+        ]),
+        None => keyboard::inject_scancodes(&[scancode, scancode | SCANCODE_RELEASE]),
+    }
+}
+
+fn find_key(keys: &[u8], byte: u8) -> Option<u8> {
+    if byte == 0 {
+        return None;
+    }
+    keys.iter().position(|&key| key == byte).map(|scancode| scancode as u8)
+}
+
+/// Return the byte received from the serial port, returns None if the buffer is empty.
+/// The buffer is lock-free with the interrupt handler as its only producer, readers take turns on a lock.
+pub fn get_received() -> Option<u8> {
+    let _reader = RECEIVED_READER.lock();
+    unsafe { RECEIVED_QUEUE.pop() }
+}
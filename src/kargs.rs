@@ -0,0 +1,110 @@
+use limine::request::KernelFileRequest;
+use spin::Once;
+
+#[used]
+#[link_section = ".requests"]
+static KERNEL_FILE_REQUEST: KernelFileRequest = KernelFileRequest::new();
+
+static KERNEL_ARGS: Once<KernelArgs<'static>> = Once::new();
+
+/// The options of a kernel command line, `key=value` pairs and flags separated by spaces.
+/// A value with spaces can be quoted, `key="a b"`. The last occurrence of a key wins.
+/// It only borrows the command line, so it works before the heap is set up.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KernelArgs<'a> {
+    cmdline: &'a str,
+}
+
+impl<'a> KernelArgs<'a> {
+    pub const fn parse(cmdline: &'a str) -> Self {
+        Self { cmdline }
+    }
+
+    /// Returns the options in order, with the value if there is one.
+    pub fn options(&self) -> impl Iterator<Item = (&'a str, Option<&'a str>)> {
+        Tokens(self.cmdline).map(|token| match token.split_once('=') {
+            Some((key, value)) => (key, Some(unquote(value))),
+            None => (token, None),
+        })
+    }
+
+    /// Returns the value of the option `key`, `None` if it isn't given or has no value.
+    pub fn get(&self, key: &str) -> Option<&'a str> {
+        self.options()
+            .filter(|&(name, _)| name == key)
+            .last()
+            .and_then(|(_, value)| value)
+    }
+
+    /// Returns whether the flag `name` is given, without a value.
+    pub fn flag(&self, name: &str) -> bool {
+        self.options().any(|option| option == (name, None))
+    }
+
+    pub fn cmdline(&self) -> &'a str {
+        self.cmdline
+    }
+}
+
+/// Splits a command line at the whitespace outside of double quotes.
+struct Tokens<'a>(&'a str);
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let rest = self.0.trim_start();
+        if rest.is_empty() {
+            self.0 = rest;
+            return None;
+        }
+
+        let mut in_quotes = false;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    in_quotes = !in_quotes;
+                }
+                c.is_whitespace() && !in_quotes
+            })
+            .map_or(rest.len(), |(index, _)| index);
+        self.0 = &rest[end..];
+        Some(&rest[..end])
+    }
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+/// Reads the command line the bootloader passed to the kernel.
+/// `init_framework` calls it first, so every subsystem can consult it.
+pub fn init() {
+    KERNEL_ARGS.call_once(|| {
+        let cmdline = KERNEL_FILE_REQUEST
+            .get_response()
+            .map(|response| response.file().cmdline())
+            .and_then(|cmdline| core::str::from_utf8(cmdline).ok())
+            .unwrap_or_default();
+        KernelArgs::parse(cmdline)
+    });
+}
+
+/// Returns the options of the kernel command line, none before `init`.
+pub fn args() -> KernelArgs<'static> {
+    KERNEL_ARGS.get().copied().unwrap_or_default()
+}
+
+/// Returns the value of the kernel option `key`.
+pub fn get(key: &str) -> Option<&'static str> {
+    args().get(key)
+}
+
+/// Returns whether the kernel flag `name` is given.
+pub fn flag(name: &str) -> bool {
+    args().flag(name)
+}
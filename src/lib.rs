@@ -9,6 +9,7 @@
 #![feature(const_mut_refs)]
 #![feature(strict_provenance)]
 
+use core::fmt;
 use core::sync::atomic::{AtomicBool, Ordering};
 
 extern crate alloc;
@@ -17,43 +18,169 @@ pub mod arch;
 pub mod console;
 pub mod data;
 pub mod drivers;
+pub mod kargs;
 pub mod memory;
+pub mod procfs;
 pub mod task;
 pub mod user;
 
 static START_SCHEDULE: AtomicBool = AtomicBool::new(false);
 
-pub fn init_framework() {
-    memory::init();
+/// The subsystem which failed to come up in `init_framework`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitError {
+    /// The bootloader didn't answer the memory map request.
+    NoMemoryMap,
+    /// The memory map has no usable region big enough for the frame bitmap.
+    NoUsableMemory,
+    /// The bootloader didn't answer the higher half direct map request.
+    NoHhdm,
+    /// The kernel heap couldn't be mapped.
+    HeapMapFailed,
+    /// The bootloader didn't answer the SMP request, which gives the BSP lapic id.
+    NoSmp,
+    /// The bootloader didn't answer the RSDP request.
+    NoRsdp,
+    /// The ACPI tables couldn't be parsed.
+    NoAcpi,
+    /// The MADT doesn't describe an APIC.
+    NoApic,
+    /// There is no HPET table.
+    NoHpet,
+    /// The local APIC couldn't be built.
+    LapicBuildFailed,
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            Self::NoMemoryMap => "no memory map from the bootloader",
+            Self::NoUsableMemory => "no usable memory for the frame allocator",
+            Self::NoHhdm => "no higher half direct map from the bootloader",
+            Self::HeapMapFailed => "failed to map the kernel heap",
+            Self::NoSmp => "no SMP information from the bootloader",
+            Self::NoRsdp => "no RSDP from the bootloader",
+            Self::NoAcpi => "failed to parse the ACPI tables",
+            Self::NoApic => "no APIC in the ACPI tables",
+            Self::NoHpet => "no HPET in the ACPI tables",
+            Self::LapicBuildFailed => "failed to build the local APIC",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+/// The error of the fallible memory and driver APIs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// There aren't enough free frames or heap memory.
+    OutOfMemory,
+    /// There are enough free frames, but the longest contiguous run only has this many.
+    Fragmented(usize),
+    /// The virtual address isn't mapped, or not with the needed flags.
+    NotMapped,
+    /// An argument is out of range or malformed, e.g. a buffer length.
+    InvalidArgument,
+    /// The device reported an error or is in an unexpected state.
+    DeviceError,
+    /// The device didn't answer in time.
+    Timeout,
+    /// The device or the data needs a feature the framework doesn't support.
+    Unsupported,
+    /// There is no such device, namespace or object.
+    NotFound,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            Self::OutOfMemory => "out of memory",
+            Self::Fragmented(_) => "free memory too fragmented",
+            Self::NotMapped => "address not mapped",
+            Self::InvalidArgument => "invalid argument",
+            Self::DeviceError => "device error",
+            Self::Timeout => "device timed out",
+            Self::Unsupported => "not supported",
+            Self::NotFound => "not found",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+impl core::error::Error for Error {}
+
+/// The result of the fallible memory and driver APIs, the error defaults to `Error`.
+pub type Result<T, E = Error> = core::result::Result<T, E>;
+
+/// Brings up the framework, returns which subsystem failed if the machine lacks something it needs.
+/// Nothing is undone on failure, the kernel can only report the error and halt.
+pub fn init_framework() -> Result<(), InitError> {
+    kargs::init();
+    memory::init()?;
     console::init();
+    arch::smp::check_available()?;
     arch::smp::CPUS.write().init_bsp();
+    drivers::fpu::init();
     arch::interrupts::IDT.load();
-    arch::acpi::init();
+    arch::interrupts::check_idt_loaded();
+    arch::acpi::init()?;
     drivers::hpet::init();
 
+    // With `nosmp` the APs stay parked in the bootloader.
     #[cfg(feature = "smp")]
-    arch::smp::CPUS.write().init_ap();
-
-    
+    if !kargs::flag("nosmp") {
+        arch::smp::CPUS.write().init_ap();
+    }
 
-    let mut lapic = arch::apic::get_lapic();
+    let mut lapic = arch::apic::build_lapic().map_err(|err| {
+        log::error!("Failed to build local APIC: {:#?}", err);
+        InitError::LapicBuildFailed
+    })?;
+    // The timer stays masked until `start_schedule`, an early tick has no scheduler to run.
     unsafe {
         lapic.enable();
+        lapic.disable_timer();
         arch::apic::calibrate_timer(&mut lapic);
-        lapic.enable_timer();
     }
 
+    drivers::ps2::init();
     arch::apic::init();
+    drivers::serial::init();
+    drivers::rtc::init();
     drivers::mouse::init();
     drivers::pci::init();
     drivers::nvme::init();
     user::init();
     task::scheduler::init();
+    Ok(())
 }
 
-#[inline]
+/// Starts the scheduling on every CPU.
+///
+/// The BSP calls it once, after `init_framework`. It waits for the APs to be parked first,
+/// so they all start together. A second call does nothing.
+///
+/// # Panics
+///
+/// Panics if the scheduler isn't initialized yet, or if it isn't called on the BSP.
 pub fn start_schedule() {
+    assert!(
+        task::scheduler::SCHEDULER_INIT.load(Ordering::SeqCst),
+        "start_schedule called before init_framework"
+    );
+    assert_eq!(
+        arch::smp::current_lapic_id(),
+        *arch::smp::BSP_LAPIC_ID,
+        "start_schedule called on an AP"
+    );
+    // `START_SCHEDULE` releases the APs, so it is set only after they are parked.
+    static STARTED: AtomicBool = AtomicBool::new(false);
+    if STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    arch::smp::wait_for_parked_aps();
     START_SCHEDULE.store(true, Ordering::SeqCst);
+    arch::apic::enable_timer();
     x86_64::instructions::interrupts::enable();
 }
 
@@ -61,6 +188,9 @@ pub fn addr_of<T>(reffer: &T) -> usize {
     reffer as *const T as usize
 }
 
+/// Casts a shared reference to a mutable one.
+/// This is undefined behavior whenever another reference to the value is alive,
+/// the framework no longer uses it, prefer a lock or `&mut self` instead.
 pub fn ref_to_mut<T>(reffer: &T) -> &mut T {
     unsafe { &mut *(addr_of(reffer) as *const T as *mut T) }
 }
@@ -0,0 +1,224 @@
+use alloc::vec::Vec;
+use spin::Lazy;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags};
+use x86_64::structures::paging::{PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+use super::{convert_physical_to_virtual, BitmapFrameAllocator, GeneralPageTable, FRAME_ALLOCATOR};
+use super::FrameAllocatorGuard;
+use crate::Error;
+
+/// Marks a page mapped read-only which gets a private frame on its first write.
+/// Limine enables CR0.WP, so the writes of the kernel through the mapping fault as well.
+pub const COW_FLAG: PageTableFlags = PageTableFlags::BIT_9;
+
+/// The end of the lower half, no zero page lies above it.
+const USER_HALF_END: u64 = 0x0000_8000_0000_0000;
+
+/// The zeroed frame every zero page maps until it is written, it is never written nor freed.
+/// `memory::init` allocates it, so it is never first touched with the frame allocator locked.
+pub static ZERO_FRAME: Lazy<PhysFrame> = Lazy::new(|| {
+    let frame = interrupts::without_interrupts(|| FRAME_ALLOCATOR.lock().allocate_frame())
+        .expect("No frame left for the zero frame!");
+    let frame_address = convert_physical_to_virtual(frame.start_address());
+    unsafe { core::ptr::write_bytes(frame_address.as_mut_ptr::<u8>(), 0, 4096) };
+    frame
+});
+
+impl GeneralPageTable {
+    /// Maps the range to the zero frame, a writable page is mapped read-only with `COW_FLAG`.
+    /// The pages read as zero and take no memory until they're written.
+    /// On failure the pages mapped so far are unmapped again.
+    ///
+    /// The first write to a page faults and the fault handler takes `FRAME_ALLOCATOR`,
+    /// so the kernel must never write the pages while it holds the allocator,
+    /// e.g. drop the guard passed in before writing allocator metadata into a new heap page.
+    pub fn map_zero_range(
+        &mut self,
+        start_address: VirtAddr,
+        length: u64,
+        flags: PageTableFlags,
+        frame_allocator: &mut BitmapFrameAllocator,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let start_page = Page::<Size4KiB>::containing_address(start_address);
+        let end_page = Page::containing_address(start_address + length - 1u64);
+        let page_range = Page::range_inclusive(start_page, end_page);
+
+        let parent_flags = flags
+            & (PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::USER_ACCESSIBLE);
+        let flags = if flags.contains(PageTableFlags::WRITABLE) {
+            (flags - PageTableFlags::WRITABLE) | COW_FLAG
+        } else {
+            flags
+        };
+        for page in page_range {
+            let frame = *ZERO_FRAME;
+            let result = unsafe {
+                self.map_to_with_table_flags(page, frame, flags, parent_flags, frame_allocator)
+            };
+            if let Err(err) = result {
+                for mapped_page in Page::range(start_page, page) {
+                    if let Ok((_, flush)) = self.unmap(mapped_page) {
+                        flush.ignore();
+                    }
+                }
+                self.flush_range(page_range);
+                return Err(err);
+            }
+        }
+
+        self.flush_range(page_range);
+        Ok(())
+    }
+
+    /// Maps the range to private zeroed frames, for a process with several threads.
+    /// Resolving a zero page on one CPU would leave the others reading the zero frame through
+    /// stale TLB entries, and there is no TLB shootdown, so such a process has no zero pages.
+    /// On failure the pages mapped so far are unmapped and their frames freed again.
+    pub fn map_zeroed_range(
+        &mut self,
+        start_address: VirtAddr,
+        length: u64,
+        flags: PageTableFlags,
+        frame_allocator: &mut BitmapFrameAllocator,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        self.map_zero_range(start_address, length, flags, frame_allocator)?;
+        if self
+            .resolve_cow_range(start_address, length, frame_allocator)
+            .is_ok()
+        {
+            return Ok(());
+        }
+
+        let start_page = Page::<Size4KiB>::containing_address(start_address);
+        let end_page = Page::containing_address(start_address + length - 1u64);
+        let page_range = Page::range_inclusive(start_page, end_page);
+        for page in page_range {
+            if let Ok((frame, flush)) = self.unmap(page) {
+                flush.ignore();
+                if frame != *ZERO_FRAME {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+            }
+        }
+        self.flush_range(page_range);
+        Err(MapToError::FrameAllocationFailed)
+    }
+
+    /// Gives every copy-on-write page a private zeroed frame, see `map_zeroed_range`.
+    /// Only the TLB of the current CPU is flushed.
+    pub fn resolve_all_cow(
+        &mut self,
+        frame_allocator: &mut BitmapFrameAllocator,
+    ) -> crate::Result<()> {
+        let pages: Vec<_> = self
+            .iter_mappings()
+            .take_while(|(page, ..)| page.start_address().as_u64() < USER_HALF_END)
+            .filter(|(_, _, flags)| flags.contains(COW_FLAG))
+            .map(|(page, ..)| page)
+            .collect();
+        for page in pages {
+            self.resolve_cow(page, frame_allocator)?;
+        }
+        Ok(())
+    }
+
+    /// Gives the page a private zeroed frame if it is a copy-on-write zero page.
+    /// Returns whether it was one.
+    pub fn resolve_cow(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame_allocator: &mut BitmapFrameAllocator,
+    ) -> crate::Result<bool> {
+        let Some((_, flags)) = self.query(page.start_address()) else {
+            return Ok(false);
+        };
+        if !flags.contains(COW_FLAG) {
+            return Ok(false);
+        }
+
+        let frame = frame_allocator.allocate_frame().ok_or(Error::OutOfMemory)?;
+        let frame_address = convert_physical_to_virtual(frame.start_address());
+        unsafe { core::ptr::write_bytes(frame_address.as_mut_ptr::<u8>(), 0, 4096) };
+
+        let flags = (flags - COW_FLAG) | PageTableFlags::WRITABLE;
+        // The zero frame isn't freed, the other zero pages still map it.
+        self.unmap(page).map_err(|_| Error::NotMapped)?.1.flush();
+        match unsafe { self.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                return Err(Error::OutOfMemory);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Resolves every copy-on-write page of the range, before the kernel writes it.
+    pub fn resolve_cow_range(
+        &mut self,
+        address: VirtAddr,
+        len: u64,
+        frame_allocator: &mut BitmapFrameAllocator,
+    ) -> crate::Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let end_address = address
+            .as_u64()
+            .checked_add(len - 1)
+            .and_then(|end_address| VirtAddr::try_new(end_address).ok())
+            .ok_or(Error::InvalidArgument)?;
+
+        let start_page = Page::<Size4KiB>::containing_address(address);
+        let end_page = Page::<Size4KiB>::containing_address(end_address);
+        for page in Page::range_inclusive(start_page, end_page) {
+            self.resolve_cow(page, frame_allocator)?;
+        }
+        Ok(())
+    }
+}
+
+/// Resolves a write fault on a copy-on-write page of the current page table.
+/// Returns whether the faulting write can be retried.
+pub fn handle_cow_fault(address: VirtAddr) -> bool {
+    let mut page_table = unsafe { GeneralPageTable::ref_from_current() };
+    let page = Page::<Size4KiB>::containing_address(address);
+
+    interrupts::without_interrupts(|| {
+        let mut frame_allocator = lock_for_fault(address);
+        match page_table.resolve_cow(page, &mut frame_allocator) {
+            Ok(true) => true,
+            // Another CPU may have resolved it while this one waited for the allocator.
+            Ok(false) => page_table
+                .query(address)
+                .is_some_and(|(_, flags)| flags.contains(PageTableFlags::WRITABLE)),
+            Err(err) => {
+                log::error!("Failed to copy the page at {:#x}: {}", address, err);
+                false
+            }
+        }
+    })
+}
+
+/// Locks the frame allocator for a copy-on-write fault at `address`.
+/// Another CPU may hold it for long, e.g. with `frame-check` scanning the bitmap, so it waits.
+/// Panics if the faulting CPU holds it itself, spinning would never end.
+fn lock_for_fault(address: VirtAddr) -> FrameAllocatorGuard<'static> {
+    loop {
+        if let Some(frame_allocator) = FRAME_ALLOCATOR.try_lock() {
+            return frame_allocator;
+        }
+        if FRAME_ALLOCATOR.is_held_by_current_cpu() {
+            panic!(
+                "Copy-on-write fault at {:#x} with the frame allocator held, resolve the page first!",
+                address
+            );
+        }
+        core::hint::spin_loop();
+    }
+}
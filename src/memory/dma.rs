@@ -0,0 +1,51 @@
+//! Barriers for memory shared with devices.
+//!
+//! A DMA driver must call `wmb` after writing descriptors or commands and before ringing the doorbell,
+//! so the device never fetches a stale entry, and `rmb` after seeing a completion (e.g. its phase bit)
+//! and before reading the rest of it or the data it describes.
+//! On x86 normal stores are already ordered with each other, the fences also keep the compiler
+//! from reordering the accesses and cover write-combining and non-temporal stores.
+//! `flush_dcache_range` is only needed for memory a device accesses without snooping the caches.
+
+use core::arch::asm;
+use core::sync::atomic::{compiler_fence, Ordering};
+use x86_64::VirtAddr;
+
+const CACHE_LINE_SIZE: u64 = 64;
+
+/// Orders the stores before it before the stores after it.
+#[inline(always)]
+pub fn wmb() {
+    compiler_fence(Ordering::Release);
+    unsafe { asm!("sfence", options(nostack, preserves_flags)) };
+}
+
+/// Orders the loads before it before the loads after it.
+#[inline(always)]
+pub fn rmb() {
+    unsafe { asm!("lfence", options(nostack, preserves_flags)) };
+    compiler_fence(Ordering::Acquire);
+}
+
+/// Orders all the memory accesses before it before the ones after it.
+#[inline(always)]
+pub fn mb() {
+    compiler_fence(Ordering::SeqCst);
+    unsafe { asm!("mfence", options(nostack, preserves_flags)) };
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Writes back and invalidates the cache lines of the range, then waits for it with `mfence`.
+pub fn flush_dcache_range(address: VirtAddr, len: usize) {
+    if len == 0 {
+        return;
+    }
+    let start = address.align_down(CACHE_LINE_SIZE).as_u64();
+    let end = address.as_u64() + len as u64;
+
+    mb();
+    for line in (start..end).step_by(CACHE_LINE_SIZE as usize) {
+        unsafe { asm!("clflush [{}]", in(reg) line, options(nostack, preserves_flags)) };
+    }
+    mb();
+}
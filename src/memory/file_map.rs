@@ -0,0 +1,249 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags};
+use x86_64::structures::paging::{PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+use super::{convert_physical_to_virtual, GeneralPageTable, FRAME_ALLOCATOR};
+use crate::Error;
+
+/// The file a mapping reads its pages from, the kernel implements it over its block cache.
+/// The calls come from the page fault handler of a user thread with the interrupts enabled,
+/// so they may block, but no lock of the process is held.
+pub trait FileBacking: Send + Sync {
+    /// Reads the page of the file at `offset`, the bytes past the end of the file read as 0.
+    fn read_page(&self, offset: u64, page: &mut [u8]) -> crate::Result<()>;
+    /// Writes the page of the file at `offset` back, without growing the file.
+    fn write_page(&self, offset: u64, page: &[u8]) -> crate::Result<()>;
+}
+
+/// Whether the writes to a mapping go back to the file, like `MAP_SHARED` and `MAP_PRIVATE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapSharing {
+    /// The pages are private copies, the writes are never seen by the file.
+    Private,
+    /// The dirty pages are written back by `sync` and `unmap`.
+    /// Without a page cache, two shared mappings of a file only see each other's writes
+    /// once they're written back and the pages are read again.
+    Shared,
+}
+
+/// A page-aligned range of a process mapped from a file, each page is read on its first access.
+#[derive(Clone)]
+pub struct FileMapping {
+    start: VirtAddr,
+    len: u64,
+    offset: u64,
+    flags: PageTableFlags,
+    sharing: MapSharing,
+    file: Arc<dyn FileBacking>,
+}
+
+impl FileMapping {
+    /// Describes a mapping of `len` bytes of `file` from `offset` at `start`, nothing is mapped.
+    /// `start` and `offset` must be page aligned, `len` is rounded up to whole pages.
+    pub fn new(
+        start: VirtAddr,
+        len: u64,
+        file: Arc<dyn FileBacking>,
+        offset: u64,
+        flags: PageTableFlags,
+        sharing: MapSharing,
+    ) -> crate::Result<Self> {
+        if len == 0 || !start.is_aligned(4096u64) || offset & 0xfff != 0 {
+            return Err(Error::InvalidArgument);
+        }
+        let len = len.checked_next_multiple_of(4096).ok_or(Error::InvalidArgument)?;
+        start
+            .as_u64()
+            .checked_add(len)
+            .ok_or(Error::InvalidArgument)?;
+        Ok(Self {
+            start,
+            len,
+            offset,
+            flags: flags | PageTableFlags::PRESENT,
+            sharing,
+            file,
+        })
+    }
+
+    /// Returns the addresses the mapping covers.
+    pub fn range(&self) -> Range<u64> {
+        self.start.as_u64()..self.start.as_u64() + self.len
+    }
+
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn sharing(&self) -> MapSharing {
+        self.sharing
+    }
+
+    pub fn contains(&self, address: VirtAddr) -> bool {
+        self.range().contains(&address.as_u64())
+    }
+
+    fn file_offset(&self, page: Page<Size4KiB>) -> u64 {
+        self.offset + (page.start_address() - self.start)
+    }
+
+    /// Reads the page of the file which `address` falls in into a new frame.
+    /// No lock is held while the file is read.
+    pub fn read_frame(&self, address: VirtAddr) -> crate::Result<PhysFrame<Size4KiB>> {
+        let page = Page::containing_address(address);
+        let frame = interrupts::without_interrupts(|| FRAME_ALLOCATOR.lock().allocate_frame())
+            .ok_or(Error::OutOfMemory)?;
+
+        let buffer = frame_bytes(frame);
+        buffer.fill(0);
+        if let Err(err) = self.file.read_page(self.file_offset(page), buffer) {
+            free_frame(frame);
+            return Err(err);
+        }
+        Ok(frame)
+    }
+
+    /// Maps the frame read by `read_frame` at the page of `address`.
+    /// The frame is freed if the page was mapped in the meantime, by another thread faulting on it.
+    pub fn map_frame(
+        &self,
+        address: VirtAddr,
+        frame: PhysFrame<Size4KiB>,
+        page_table: &mut GeneralPageTable,
+    ) -> crate::Result<()> {
+        let page = Page::<Size4KiB>::containing_address(address);
+        if page_table.query(page.start_address()).is_some() {
+            free_frame(frame);
+            return Ok(());
+        }
+
+        let result = interrupts::without_interrupts(|| unsafe {
+            page_table.map_to(page, frame, self.flags, &mut *FRAME_ALLOCATOR.lock())
+        });
+        match result {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(_) => {
+                free_frame(frame);
+                Err(Error::OutOfMemory)
+            }
+        }
+    }
+
+    /// Frees a frame read by `read_frame` which isn't going to be mapped.
+    pub fn release_frame(&self, frame: PhysFrame<Size4KiB>) {
+        free_frame(frame);
+    }
+
+    /// Copies the dirty pages of a shared mapping into new frames and marks them clean,
+    /// with the process locked. Nothing is copied for a private mapping.
+    pub fn take_dirty(&self, page_table: &mut GeneralPageTable) -> crate::Result<Vec<DirtyPage>> {
+        if self.sharing != MapSharing::Shared {
+            return Ok(Vec::new());
+        }
+        let dirty: Vec<_> = self
+            .mapped_pages(page_table)
+            .filter(|(_, _, flags)| flags.contains(PageTableFlags::DIRTY))
+            .collect();
+
+        let mut pages = Vec::with_capacity(dirty.len());
+        for (page, frame, flags) in dirty {
+            let copy = interrupts::without_interrupts(|| FRAME_ALLOCATOR.lock().allocate_frame());
+            let Some(copy) = copy else {
+                self.redirty(&pages, page_table);
+                return Err(Error::OutOfMemory);
+            };
+            frame_bytes(copy).copy_from_slice(frame_bytes(frame));
+            pages.push(DirtyPage { page, copy });
+            let clean = flags - PageTableFlags::DIRTY;
+            if let Ok(flush) = unsafe { page_table.update_flags(page, clean) } {
+                flush.flush();
+            }
+        }
+        Ok(pages)
+    }
+
+    /// Writes the pages copied by `take_dirty` to the file.
+    /// No lock is held, since the file may block.
+    pub fn write_back(&self, pages: &[DirtyPage]) -> crate::Result<()> {
+        for dirty in pages {
+            let offset = self.file_offset(dirty.page);
+            self.file.write_page(offset, frame_bytes(dirty.copy))?;
+        }
+        Ok(())
+    }
+
+    /// Marks the pages copied by `take_dirty` dirty again if they couldn't be written back.
+    /// The pages unmapped in the meantime are skipped.
+    pub fn redirty(&self, pages: &[DirtyPage], page_table: &mut GeneralPageTable) {
+        for dirty in pages {
+            let Some((_, flags)) = page_table.query(dirty.page.start_address()) else {
+                continue;
+            };
+            if let Ok(flush) =
+                unsafe { page_table.update_flags(dirty.page, flags | PageTableFlags::DIRTY) }
+            {
+                flush.flush();
+            }
+        }
+    }
+
+    /// Returns whether a page of a shared mapping was written since it was last copied.
+    pub fn is_dirty(&self, page_table: &GeneralPageTable) -> bool {
+        self.sharing == MapSharing::Shared
+            && self
+                .mapped_pages(page_table)
+                .any(|(_, _, flags)| flags.contains(PageTableFlags::DIRTY))
+    }
+
+    /// Unmaps the pages read so far and frees their frames, without writing anything back.
+    pub fn release(&self, page_table: &mut GeneralPageTable) {
+        let pages: Vec<_> = self.mapped_pages(page_table).map(|(page, ..)| page).collect();
+        for page in pages {
+            if let Ok((frame, flush)) = page_table.unmap(page) {
+                flush.flush();
+                free_frame(frame);
+            }
+        }
+    }
+
+    /// Returns the pages of the mapping read so far, only the present tables are walked.
+    fn mapped_pages<'a>(
+        &self,
+        page_table: &'a GeneralPageTable,
+    ) -> impl Iterator<Item = (Page<Size4KiB>, PhysFrame, PageTableFlags)> + 'a {
+        let range = self.range();
+        page_table
+            .iter_mappings()
+            .skip_while(move |(page, ..)| page.start_address().as_u64() < range.start)
+            .take_while(move |(page, ..)| page.start_address().as_u64() < range.end)
+    }
+}
+
+/// A copy of a dirty page of a shared mapping, made by `take_dirty`.
+/// The frame of the copy is freed when it's dropped.
+pub struct DirtyPage {
+    page: Page<Size4KiB>,
+    copy: PhysFrame<Size4KiB>,
+}
+
+impl Drop for DirtyPage {
+    fn drop(&mut self) {
+        free_frame(self.copy);
+    }
+}
+
+fn frame_bytes(frame: PhysFrame<Size4KiB>) -> &'static mut [u8] {
+    let address = convert_physical_to_virtual(frame.start_address());
+    unsafe { core::slice::from_raw_parts_mut(address.as_mut_ptr::<u8>(), 4096) }
+}
+
+fn free_frame(frame: PhysFrame<Size4KiB>) {
+    interrupts::without_interrupts(|| unsafe { FRAME_ALLOCATOR.lock().deallocate_frame(frame) });
+}
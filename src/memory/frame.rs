@@ -1,37 +1,134 @@
+use core::ops::{Deref, DerefMut, Range};
+use core::sync::atomic::{AtomicU32, Ordering};
 use limine::memory_map::EntryType;
 use limine::response::MemoryMapResponse;
 use x86_64::structures::paging::{FrameAllocator, PhysFrame};
 use x86_64::structures::paging::{FrameDeallocator, Size4KiB};
+use spin::{Mutex, MutexGuard};
 use x86_64::PhysAddr;
 
+use crate::arch::smp::current_cpu_id;
 use crate::data::bitmap::Bitmap;
+use crate::drivers::display::framebuffer_ranges;
 use crate::memory::convert_physical_to_virtual;
+use crate::{Error, InitError};
+
+/// The owner of a `LockedFrameAllocator` nobody holds.
+const NO_OWNER: u32 = u32::MAX;
+
+/// The lock of the frame allocator, it records the CPU holding it.
+/// A copy-on-write fault can then tell a lock held by its own CPU, which it would wait for forever,
+/// from one another CPU holds for a long time.
+pub struct LockedFrameAllocator {
+    inner: Mutex<BitmapFrameAllocator>,
+    owner: AtomicU32,
+}
+
+impl LockedFrameAllocator {
+    pub const fn new(allocator: BitmapFrameAllocator) -> Self {
+        Self {
+            inner: Mutex::new(allocator),
+            owner: AtomicU32::new(NO_OWNER),
+        }
+    }
+
+    pub fn lock(&self) -> FrameAllocatorGuard<'_> {
+        let guard = self.inner.lock();
+        self.owner.store(current_cpu_id(), Ordering::Relaxed);
+        FrameAllocatorGuard {
+            guard,
+            owner: &self.owner,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<FrameAllocatorGuard<'_>> {
+        let guard = self.inner.try_lock()?;
+        self.owner.store(current_cpu_id(), Ordering::Relaxed);
+        Some(FrameAllocatorGuard {
+            guard,
+            owner: &self.owner,
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Returns whether the current CPU holds the lock.
+    /// The owner is only set to the current CPU by the current CPU, so it can't be stale.
+    pub fn is_held_by_current_cpu(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) == current_cpu_id()
+    }
+}
+
+/// The guard of `LockedFrameAllocator::lock`, it clears the owner before unlocking.
+pub struct FrameAllocatorGuard<'a> {
+    guard: MutexGuard<'a, BitmapFrameAllocator>,
+    owner: &'a AtomicU32,
+}
+
+impl Deref for FrameAllocatorGuard<'_> {
+    type Target = BitmapFrameAllocator;
+
+    fn deref(&self) -> &BitmapFrameAllocator {
+        &self.guard
+    }
+}
+
+impl DerefMut for FrameAllocatorGuard<'_> {
+    fn deref_mut(&mut self) -> &mut BitmapFrameAllocator {
+        &mut self.guard
+    }
+}
+
+impl Drop for FrameAllocatorGuard<'_> {
+    fn drop(&mut self) {
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+    }
+}
+
 pub struct BitmapFrameAllocator {
     bitmap: Bitmap,
     usable_frames: usize,
+    total_frames: usize,
     next_frame: usize,
 }
 
 impl BitmapFrameAllocator {
-    pub fn init(memory_map: &MemoryMapResponse) -> Self {
+    /// Checks that the memory map has a usable region big enough for the bitmap.
+    pub fn check(memory_map: &MemoryMapResponse) -> Result<(), InitError> {
+        Self::bitmap_region(memory_map).map(|_| ())
+    }
+
+    /// Returns the address and the size of the bitmap.
+    fn bitmap_region(memory_map: &MemoryMapResponse) -> Result<(u64, usize), InitError> {
         let memory_size = memory_map
             .entries()
             .last()
             .map(|region| region.base + region.length)
-            .expect("No memory regions found!");
+            .ok_or(InitError::NoMemoryMap)?;
 
         let bitmap_size = (memory_size / 4096).div_ceil(8) as usize;
 
-        let usable_regions = memory_map
+        let bitmap_address = memory_map
             .entries()
             .iter()
-            .filter(|region| region.entry_type == EntryType::USABLE);
-
-        let bitmap_address = usable_regions
-            .clone()
+            .filter(|region| region.entry_type == EntryType::USABLE)
             .find(|region| region.length >= bitmap_size as u64)
             .map(|region| region.base)
-            .expect("No suitable memory region for bitmap!");
+            .ok_or(InitError::NoUsableMemory)?;
+
+        Ok((bitmap_address, bitmap_size))
+    }
+
+    pub fn init(memory_map: &MemoryMapResponse) -> Self {
+        let (bitmap_address, bitmap_size) =
+            Self::bitmap_region(memory_map).unwrap_or_else(|err| panic!("{}", err));
+
+        let usable_regions = memory_map
+            .entries()
+            .iter()
+            .filter(|region| region.entry_type == EntryType::USABLE);
 
         let bitmap_buffer = unsafe {
             let physical_address = PhysAddr::new(bitmap_address);
@@ -66,21 +163,74 @@ impl BitmapFrameAllocator {
         usable_frames -= bitmap_frame_count;
         (bitmap_frame_start..bitmap_frame_end).for_each(|index| bitmap.set(index, false));
 
+        // Firmware may report the frame buffer as usable, its frames must never be handed out.
+        for range in framebuffer_ranges() {
+            let start = (range.start / 4096) as usize;
+            let end = (range.end.div_ceil(4096) as usize).min(bitmap.len());
+            let reserved = (start..end).filter(|&index| bitmap.get(index)).count();
+            if reserved != 0 {
+                log::warn!("{} frames of the frame buffer at {:#x} were usable", reserved, range.start);
+                (start..end).for_each(|index| bitmap.set(index, false));
+                usable_frames -= reserved;
+            }
+        }
+        while next_frame < bitmap.len() && !bitmap.get(next_frame) {
+            next_frame += 1;
+        }
+
         log::info!("Usable memory: {} KiB", usable_frames * 4);
 
         BitmapFrameAllocator {
             bitmap,
             usable_frames,
+            total_frames: usable_frames,
             next_frame,
         }
     }
 
-    /// Allocates some frames.
-    pub fn allocate_frames(&mut self, cnt: usize) -> Option<u64> {
+    /// Returns the number of frames which are free.
+    pub fn usable_frames(&self) -> usize {
+        self.usable_frames
+    }
+
+    /// Returns the number of frames the allocator manages, the free ones and the allocated ones.
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// Returns the first free frame from `index`, or the end of the bitmap.
+    fn next_free_frame(&self, index: usize) -> usize {
+        (index..self.bitmap.len())
+            .find(|&index| self.bitmap.get(index))
+            .unwrap_or(self.bitmap.len())
+    }
+
+    /// Recounts the free frames in the bitmap and panics if the allocator's state disagrees.
+    /// It scans the whole bitmap, the `frame-check` feature runs it after every change.
+    #[cfg(debug_assertions)]
+    pub fn check_invariants(&self) {
+        let free_frames = (0..self.bitmap.len())
+            .filter(|&index| self.bitmap.get(index))
+            .count();
+        assert_eq!(
+            free_frames, self.usable_frames,
+            "The bitmap has {} free frames, the allocator counts {}",
+            free_frames, self.usable_frames
+        );
+        assert!(self.usable_frames <= self.total_frames);
+        assert!(
+            self.next_frame >= self.bitmap.len() || self.bitmap.get(self.next_frame),
+            "The next frame {:#x} isn't free",
+            self.next_frame * 4096
+        );
+    }
+
+    /// Allocates some contiguous frames, returns the physical address of the first one.
+    pub fn allocate_frames(&mut self, cnt: usize) -> crate::Result<u64> {
         //log::info!("allocate_frames cnt: {}", cnt);
         if cnt > self.usable_frames {
             log::error!("no more usable frames");
-            return None;
+            return Err(Error::OutOfMemory);
         }
 
         self.usable_frames -= cnt;
@@ -97,54 +247,74 @@ impl BitmapFrameAllocator {
             }
         }
         if found {
-            self.next_frame = next;
+            // The frame after the run may be allocated, `allocate_frame` takes `next_frame` as is.
+            self.next_frame = self.next_free_frame(next);
 
             let addr = (next - cnt) * 4096;
 
             for i in next - cnt..next {
                 self.bitmap.set(i, false);
             }
+            debug_assert!(!in_framebuffer(addr as u64, cnt));
+            #[cfg(all(debug_assertions, feature = "frame-check"))]
+            self.check_invariants();
 
             //log::info!("found!");
 
-            return Some(addr as u64);
+            return Ok(addr as u64);
         }
 
-        loop {
-            //log::info!("next: {}", next);
-            if next >= self.bitmap.len() {
-                self.usable_frames += cnt;
-                return None;
-            }
-            while next < self.bitmap.len() && !self.bitmap.get(next) {
-                next += 1;
-            }
-
-            let mut frame_cnt = 0;
+        // The run at `next_frame` is too short, take the first one long enough in the whole bitmap.
+        let Some(run) = self.free_runs().find(|run| run.len() >= cnt) else {
+            self.usable_frames += cnt;
+            let largest_run = self.largest_free_run();
+            log::warn!("No run of {} free frames, the longest has {}", cnt, largest_run);
+            return Err(Error::Fragmented(largest_run));
+        };
 
-            found = false;
-            while next < self.bitmap.len() && self.bitmap.get(next) {
-                next += 1;
-                frame_cnt += 1;
-                if frame_cnt == cnt {
-                    found = true;
-                    break;
-                }
-            }
+        let addr = run.start * 4096;
+        for i in run.start..run.start + cnt {
+            self.bitmap.set(i, false);
+        }
+        // The run may start before `next_frame` and cover it, e.g. the one left by `init`.
+        if (run.start..run.start + cnt).contains(&self.next_frame) {
+            self.next_frame = self.next_free_frame(run.start + cnt);
+        }
+        debug_assert!(!in_framebuffer(addr as u64, cnt));
+        #[cfg(all(debug_assertions, feature = "frame-check"))]
+        self.check_invariants();
 
-            if found {
-                let addr = (next - cnt) * 4096;
+        Ok(addr as u64)
+    }
 
-                for i in next - cnt..next {
-                    self.bitmap.set(i, false);
-                }
+    /// Returns the number of frames in the longest run of contiguous free frames,
+    /// the most `allocate_frames` can allocate at once.
+    pub fn largest_free_run(&self) -> usize {
+        self.free_runs().map(|run| run.len()).max().unwrap_or(0)
+    }
 
-                return Some(addr as u64);
+    /// Returns the runs of contiguous free frames in the whole bitmap, in order.
+    fn free_runs(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        let mut index = 0;
+        core::iter::from_fn(move || {
+            let start = self.next_free_frame(index);
+            if start == self.bitmap.len() {
+                return None;
             }
-        }
+            index = (start..self.bitmap.len())
+                .find(|&index| !self.bitmap.get(index))
+                .unwrap_or(self.bitmap.len());
+            Some(start..index)
+        })
     }
 }
 
+/// Returns whether any of the `count` frames from `address` is in a frame buffer.
+fn in_framebuffer(address: u64, count: usize) -> bool {
+    let frames = address..address + count as u64 * 4096;
+    framebuffer_ranges().any(|range| range.start < frames.end && frames.start < range.end)
+}
+
 unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
         if self.usable_frames == 0 {
@@ -156,10 +326,11 @@ unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
         self.bitmap.set(self.next_frame, false);
 
         let address = self.next_frame * 4096;
+        debug_assert!(!in_framebuffer(address as u64, 1));
 
-        self.next_frame = (self.next_frame + 1..self.bitmap.len())
-            .find(|&index| self.bitmap.get(index))
-            .unwrap_or(self.bitmap.len());
+        self.next_frame = self.next_free_frame(self.next_frame + 1);
+        #[cfg(all(debug_assertions, feature = "frame-check"))]
+        self.check_invariants();
 
         Some(PhysFrame::containing_address(PhysAddr::new(address as u64)))
     }
@@ -167,7 +338,16 @@ unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
 
 impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
     unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
-        let index = frame.start_address().as_u64() / 4096;
-        self.bitmap.set(index as usize, true);
+        let index = (frame.start_address().as_u64() / 4096) as usize;
+        // Freeing a free frame again mustn't count it twice.
+        #[cfg(all(debug_assertions, feature = "frame-check"))]
+        assert!(!self.bitmap.get(index), "Frame {:#x} freed twice!", index * 4096);
+        if !self.bitmap.get(index) {
+            self.bitmap.set(index, true);
+            self.usable_frames += 1;
+            self.next_frame = self.next_frame.min(index);
+        }
+        #[cfg(all(debug_assertions, feature = "frame-check"))]
+        self.check_invariants();
     }
 }
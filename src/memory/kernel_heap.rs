@@ -1,30 +1,65 @@
-use alloc::alloc::Layout;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use good_memory_allocator::SpinLockedAllocator;
 use x86_64::structures::paging::PageTableFlags;
 use x86_64::VirtAddr;
 
 use super::KERNEL_PAGE_TABLE;
 use crate::memory::MemoryManager;
+use crate::InitError;
 
 pub const HEAP_START: usize = 0x114514000000;
 pub const HEAP_SIZE: usize = 64 * 1024 * 1024;
 
+/// The addresses of the kernel heap, it is in the lower half and mapped in every process.
+pub const KERNEL_HEAP_RANGE: Range<u64> = HEAP_START as u64..(HEAP_START + HEAP_SIZE) as u64;
+
 #[global_allocator]
-static ALLOCATOR: SpinLockedAllocator = SpinLockedAllocator::empty();
+static ALLOCATOR: KernelAllocator = KernelAllocator(SpinLockedAllocator::empty());
+
+/// The allocations and frees in progress on any CPU, see `is_heap_locked`.
+static HEAP_USERS: AtomicUsize = AtomicUsize::new(0);
+
+/// The heap allocator, counting its callers since its lock doesn't tell whether it is held.
+struct KernelAllocator(SpinLockedAllocator);
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        HEAP_USERS.fetch_add(1, Ordering::SeqCst);
+        let ptr = self.0.alloc(layout);
+        HEAP_USERS.fetch_sub(1, Ordering::SeqCst);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        HEAP_USERS.fetch_add(1, Ordering::SeqCst);
+        self.0.dealloc(ptr, layout);
+        HEAP_USERS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Returns whether some code may hold the heap lock, e.g. the code an interrupt interrupted.
+/// The lock doesn't disable the interrupts, so a handler which frees then would deadlock.
+pub fn is_heap_locked() -> bool {
+    HEAP_USERS.load(Ordering::SeqCst) != 0
+}
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: Layout) -> ! {
     panic!("Kernel heap allocation error: {:?}", layout)
 }
 
-pub fn init() {
+pub fn init() -> Result<(), InitError> {
     let heap_start = VirtAddr::new(HEAP_START as u64);
 
     let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
     let mut page_table = KERNEL_PAGE_TABLE.lock();
-    <MemoryManager>::alloc_range(heap_start, HEAP_SIZE as u64, flags, &mut page_table).unwrap();
+    <MemoryManager>::alloc_range(heap_start, HEAP_SIZE as u64, flags, &mut page_table)
+        .map_err(|_| InitError::HeapMapFailed)?;
 
     unsafe {
-        ALLOCATOR.init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.0.init(HEAP_START, HEAP_SIZE);
     }
+    Ok(())
 }
@@ -1,6 +1,6 @@
 use core::marker::PhantomData;
 use x86_64::structures::paging::mapper::MapToError;
-use x86_64::structures::paging::{FrameAllocator, PhysFrame};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
 use x86_64::structures::paging::{Mapper, PageTableFlags};
 use x86_64::structures::paging::{Page, PageSize, Size4KiB};
 use x86_64::VirtAddr;
@@ -14,6 +14,8 @@ pub struct MemoryManager<S: PageSize = Size4KiB> {
 }
 
 impl<S: PageSize> MemoryManager<S> {
+    /// Maps the range to newly allocated frames.
+    /// On failure the pages mapped so far are unmapped and their frames freed again.
     pub fn alloc_range(
         start_address: VirtAddr,
         length: u64,
@@ -22,7 +24,7 @@ impl<S: PageSize> MemoryManager<S> {
     ) -> Result<(), MapToError<S>>
     where
         GeneralPageTable: Mapper<S>,
-        BitmapFrameAllocator: FrameAllocator<S>,
+        BitmapFrameAllocator: FrameAllocator<S> + FrameDeallocator<S>,
     {
         let page_range = {
             let start_page = Page::containing_address(start_address);
@@ -32,14 +34,55 @@ impl<S: PageSize> MemoryManager<S> {
         };
         let mut frame_allocator = super::FRAME_ALLOCATOR.lock();
         for page in page_range {
-            let frame = frame_allocator
-                .allocate_frame()
-                .expect("Failed to allocate frame!");
-            Self::map_frame_to_page(frame, page, flags, page_table, &mut *frame_allocator)?;
+            let result = match frame_allocator.allocate_frame() {
+                Some(frame) => unsafe {
+                    page_table
+                        .map_to(page, frame, flags, &mut *frame_allocator)
+                        .inspect_err(|_| frame_allocator.deallocate_frame(frame))
+                },
+                None => Err(MapToError::FrameAllocationFailed),
+            };
+            match result {
+                Ok(flush) => flush.ignore(),
+                Err(err) => {
+                    for mapped_page in Page::range(page_range.start, page) {
+                        if let Ok((frame, flush)) = page_table.unmap(mapped_page) {
+                            flush.ignore();
+                            unsafe { frame_allocator.deallocate_frame(frame) };
+                        }
+                    }
+                    page_table.flush_range(page_range);
+                    return Err(err);
+                }
+            }
         }
+        page_table.flush_range(page_range);
         Ok(())
     }
 
+    /// Unmaps the range mapped by `alloc_range` and frees its frames.
+    /// The pages which aren't mapped are skipped.
+    pub fn dealloc_range(start_address: VirtAddr, length: u64, page_table: &mut GeneralPageTable)
+    where
+        GeneralPageTable: Mapper<S>,
+        BitmapFrameAllocator: FrameDeallocator<S>,
+    {
+        let page_range = {
+            let start_page = Page::containing_address(start_address);
+            let end_address = start_address + length - 1;
+            let end_page = Page::containing_address(end_address);
+            Page::range_inclusive(start_page, end_page)
+        };
+        let mut frame_allocator = super::FRAME_ALLOCATOR.lock();
+        for page in page_range {
+            if let Ok((frame, flush)) = page_table.unmap(page) {
+                flush.ignore();
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+        }
+        page_table.flush_range(page_range);
+    }
+
     /// Maps a frame to a page.
     pub fn map_frame_to_page(
         frame: PhysFrame<S>,
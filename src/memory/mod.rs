@@ -1,17 +1,28 @@
-use frame::BitmapFrameAllocator;
 use limine::request::{HhdmRequest, MemoryMapRequest};
 use spin::{Lazy, Mutex};
+use x86_64::structures::paging::{mapper::MapToError, Size4KiB};
 use x86_64::{instructions::interrupts, PhysAddr, VirtAddr};
 
+use crate::InitError;
+
+mod cow;
+pub mod dma;
+mod file_map;
 mod frame;
 mod kernel_heap;
 mod manager;
 mod page_table;
+mod pin;
 mod user_heap;
 
-pub use kernel_heap::init;
+pub use cow::{handle_cow_fault, COW_FLAG, ZERO_FRAME};
+pub use file_map::{DirtyPage, FileBacking, FileMapping, MapSharing};
+pub use frame::{BitmapFrameAllocator, FrameAllocatorGuard, LockedFrameAllocator};
+pub(crate) use kernel_heap::{is_heap_locked, KERNEL_HEAP_RANGE};
 pub use manager::MemoryManager;
 pub use page_table::*;
+pub use pin::{is_pinned, pin_range, unpin_range};
+pub(crate) use pin::{pin_frames, unpin_frames};
 pub use user_heap::*;
 
 #[used]
@@ -26,9 +37,9 @@ pub static PHYSICAL_MEMORY_OFFSET: Lazy<u64> =
     Lazy::new(|| HHDM_REQUEST.get_response().unwrap().offset());
 
 /// The global Frame Allocator.
-pub static FRAME_ALLOCATOR: Lazy<Mutex<BitmapFrameAllocator>> = Lazy::new(|| {
+pub static FRAME_ALLOCATOR: Lazy<LockedFrameAllocator> = Lazy::new(|| {
     let memory_map = MEMORY_MAP_REQUEST.get_response().unwrap();
-    Mutex::new(BitmapFrameAllocator::init(memory_map))
+    LockedFrameAllocator::new(BitmapFrameAllocator::init(memory_map))
 });
 
 /// The page table that limine prepared for us.
@@ -37,6 +48,38 @@ pub static KERNEL_PAGE_TABLE: Lazy<Mutex<GeneralPageTable>> = Lazy::new(|| {
     Mutex::new(page_table)
 });
 
+/// Checks the memory responses of the bootloader and maps the kernel heap.
+pub fn init() -> Result<(), InitError> {
+    HHDM_REQUEST.get_response().ok_or(InitError::NoHhdm)?;
+    let memory_map = MEMORY_MAP_REQUEST
+        .get_response()
+        .ok_or(InitError::NoMemoryMap)?;
+    BitmapFrameAllocator::check(memory_map)?;
+    kernel_heap::init()?;
+    Lazy::force(&ZERO_FRAME);
+    Ok(())
+}
+
+/// The usage of the physical memory, see `stats`.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+    /// The bytes of usable memory the frame allocator manages.
+    pub total: u64,
+    /// The bytes of it which are free.
+    pub free: u64,
+}
+
+/// Returns the usage of the physical memory.
+pub fn stats() -> MemoryStats {
+    interrupts::without_interrupts(|| {
+        let frame_allocator = FRAME_ALLOCATOR.lock();
+        MemoryStats {
+            total: frame_allocator.total_frames() as u64 * 4096,
+            free: frame_allocator.usable_frames() as u64 * 4096,
+        }
+    })
+}
+
 /// Convert the physical address to a virtual address.
 #[inline]
 pub fn convert_physical_to_virtual(physical_address: PhysAddr) -> VirtAddr {
@@ -50,7 +93,7 @@ pub fn convert_virtual_to_physical(virtual_address: VirtAddr) -> PhysAddr {
 }
 
 /// Copies a page table from the kernel.
-pub fn create_page_table_from_kernel() -> GeneralPageTable {
+pub fn create_page_table_from_kernel() -> Result<GeneralPageTable, MapToError<Size4KiB>> {
     interrupts::without_interrupts(|| {
         let mut frame_allocator = FRAME_ALLOCATOR.lock();
         let page_table_address = KERNEL_PAGE_TABLE.lock().physical_address;
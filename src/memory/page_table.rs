@@ -1,15 +1,23 @@
+use alloc::vec::Vec;
+use x86_64::instructions::{interrupts, tlb};
 use x86_64::registers::control::Cr3;
 use x86_64::structures::paging::mapper::*;
 use x86_64::structures::paging::page::PageRangeInclusive;
 use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
-use x86_64::structures::paging::{Page, Size4KiB};
+use x86_64::structures::paging::{Page, PageSize, Size4KiB};
 use x86_64::structures::paging::{PageTable, PageTableFlags, PhysFrame};
 use x86_64::{PhysAddr, VirtAddr};
 
+use crate::Error;
+
 use super::{
-    convert_physical_to_virtual, BitmapFrameAllocator, FRAME_ALLOCATOR, PHYSICAL_MEMORY_OFFSET,
+    convert_physical_to_virtual, BitmapFrameAllocator, COW_FLAG, FRAME_ALLOCATOR,
+    PHYSICAL_MEMORY_OFFSET,
 };
 
+/// Ranges with more pages than this flush the whole TLB instead of each page.
+const FLUSH_ALL_THRESHOLD: u64 = 32;
+
 /// The page table.
 #[derive(Debug)]
 pub struct GeneralPageTable {
@@ -29,18 +37,69 @@ impl GeneralPageTable {
         }
     }
 
+    /// Returns whether the page table is the current one.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0.start_address() == self.physical_address
+    }
+
+    /// Maps the range to newly allocated frames and flushes the TLB once at the end.
+    /// Returns the number of TLB flush instructions issued, which is 0 if the page table isn't active.
+    pub fn map_range_batched(
+        &mut self,
+        start_address: VirtAddr,
+        length: u64,
+        flags: PageTableFlags,
+        frame_allocator: &mut BitmapFrameAllocator,
+    ) -> Result<u64, MapToError<Size4KiB>> {
+        let start_page = Page::<Size4KiB>::containing_address(start_address);
+        let end_page = Page::containing_address(start_address + length - 1u64);
+        let page_range = Page::range_inclusive(start_page, end_page);
+
+        for page in page_range {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            unsafe { self.map_to(page, frame, flags, frame_allocator)?.ignore() };
+        }
+
+        Ok(self.flush_range(page_range))
+    }
+
+    /// Flushes the pages of the range from the TLB if the page table is active.
+    /// Returns the number of TLB flush instructions issued.
+    pub fn flush_range<S: PageSize>(&self, page_range: PageRangeInclusive<S>) -> u64 {
+        if !self.is_active() || page_range.is_empty() {
+            return 0;
+        }
+
+        let page_count = page_range.end.start_address() - page_range.start.start_address();
+        let page_count = page_count / S::SIZE + 1;
+
+        if page_count > FLUSH_ALL_THRESHOLD {
+            tlb::flush_all();
+            1
+        } else {
+            for page in page_range {
+                tlb::flush(page.start_address());
+            }
+            page_count
+        }
+    }
+
     /// Creates a new page table from the specified physical address.
+    /// Fails without leaking frames if there isn't enough memory for the copy.
     pub unsafe fn new_from_address(
         frame_allocator: &mut BitmapFrameAllocator,
         physical_address: PhysAddr,
-    ) -> GeneralPageTable {
-        let source_page_table =
-            &*convert_physical_to_virtual(physical_address).as_ptr::<PageTable>();
-        let mut new_page_table = Self::new(frame_allocator);
-        let target_page_table = new_page_table.inner.level_4_table_mut();
-
-        Self::new_from_recursion(frame_allocator, source_page_table, target_page_table, 4);
-        new_page_table
+    ) -> Result<GeneralPageTable, MapToError<Size4KiB>> {
+        let new_page_table = Self::new(frame_allocator)?;
+        let target_address = new_page_table.physical_address;
+
+        if let Err(err) = Self::copy_tables(frame_allocator, physical_address, target_address) {
+            frame_allocator.deallocate_frame(PhysFrame::containing_address(target_address));
+            return Err(err);
+        }
+        Ok(new_page_table)
     }
 
     /// Returns the current page table.
@@ -59,12 +118,14 @@ impl GeneralPageTable {
     }
 
     /// Creates a new page table.
-    unsafe fn new(frame_allocator: &mut BitmapFrameAllocator) -> Self {
+    unsafe fn new(
+        frame_allocator: &mut BitmapFrameAllocator,
+    ) -> Result<Self, MapToError<Size4KiB>> {
         let page_table_address: Option<PhysFrame<Size4KiB>> =
             BitmapFrameAllocator::allocate_frame(frame_allocator);
 
         let page_table_address = page_table_address
-            .expect("Failed to allocate frame for page table!")
+            .ok_or(MapToError::FrameAllocationFailed)?
             .start_address();
 
         let new_page_table =
@@ -72,41 +133,49 @@ impl GeneralPageTable {
         let physical_memory_offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.clone());
         let page_table = OffsetPageTable::new(new_page_table, physical_memory_offset);
 
-        GeneralPageTable {
+        Ok(GeneralPageTable {
             inner: page_table,
             physical_address: page_table_address,
-        }
+        })
     }
 
-    /// Creates a new page table from the kernel page table.
-    unsafe fn new_from_recursion(
+    /// Copies the level 4 table at `source` and the tables below it into the table at `target`.
+    /// The pages themselves are shared, only the tables are copied.
+    /// Walks the tables with a list of (level, source, target) entries, so it uses little and bounded stack,
+    /// and frees the tables it allocated if it runs out of memory.
+    unsafe fn copy_tables(
         frame_allocator: &mut BitmapFrameAllocator,
-        source_page_table: &PageTable,
-        target_page_table: &mut PageTable,
-        page_table_level: u8,
-    ) {
-        for (index, entry) in source_page_table.iter().enumerate() {
-            if (page_table_level == 1)
-                || entry.is_unused()
-                || entry.flags().contains(PageTableFlags::HUGE_PAGE)
-            {
-                target_page_table[index].set_addr(entry.addr(), entry.flags());
-                continue;
+        source: PhysAddr,
+        target: PhysAddr,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let mut allocated = Vec::new();
+        let mut pending = Vec::from([(4u8, source, target)]);
+
+        while let Some((level, source, target)) = pending.pop() {
+            let source_table = &*convert_physical_to_virtual(source).as_ptr::<PageTable>();
+            let target_table = &mut *convert_physical_to_virtual(target).as_mut_ptr::<PageTable>();
+
+            for (index, entry) in source_table.iter().enumerate() {
+                if level == 1
+                    || entry.is_unused()
+                    || entry.flags().contains(PageTableFlags::HUGE_PAGE)
+                {
+                    target_table[index].set_addr(entry.addr(), entry.flags());
+                    continue;
+                }
+
+                let Some(frame) = frame_allocator.allocate_frame() else {
+                    for frame in allocated {
+                        frame_allocator.deallocate_frame(frame);
+                    }
+                    return Err(MapToError::FrameAllocationFailed);
+                };
+                allocated.push(frame);
+                target_table[index].set_addr(frame.start_address(), entry.flags());
+                pending.push((level - 1, entry.addr(), frame.start_address()));
             }
-            let mut new_page_table = Self::new(frame_allocator);
-            let new_page_table_address = new_page_table.physical_address;
-            target_page_table[index].set_addr(new_page_table_address, entry.flags());
-
-            let source_page_table_next = &*convert_physical_to_virtual(entry.addr()).as_ptr();
-            let target_page_table_next = new_page_table.inner.level_4_table_mut();
-
-            Self::new_from_recursion(
-                frame_allocator,
-                source_page_table_next,
-                target_page_table_next,
-                page_table_level - 1,
-            );
         }
+        Ok(())
     }
 
     /// Maps a frame to a page with the specified flags.
@@ -256,12 +325,104 @@ impl CleanUp for GeneralPageTable {
 }
 
 impl GeneralPageTable {
+    /// Returns the physical address and the flags the address is mapped with.
+    pub fn query(&self, address: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        match self.translate(address) {
+            TranslateResult::Mapped { frame, offset, flags } => {
+                Some((frame.start_address() + offset, flags))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns every present mapping with the flags of its leaf entry, ordered by address.
+    /// A huge page is expanded into its 4 KiB pages, their flags contain `HUGE_PAGE`.
+    pub fn iter_mappings(
+        &self,
+    ) -> impl Iterator<Item = (Page<Size4KiB>, PhysFrame, PageTableFlags)> + '_ {
+        let level_4_table = self.inner.level_4_table();
+        Mappings {
+            tables: [level_4_table; 4],
+            indices: [0; 4],
+            level: 3,
+            run: None,
+        }
+    }
+
+    /// Returns whether every page of the range is present, writable and user accessible.
+    pub fn is_user_writable(&self, address: VirtAddr, len: u64) -> bool {
+        if len == 0 {
+            return true;
+        }
+        let Some(end_address) = address.as_u64().checked_add(len - 1) else {
+            return false;
+        };
+        let Ok(end_address) = VirtAddr::try_new(end_address) else {
+            return false;
+        };
+
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::USER_ACCESSIBLE;
+        let start_page = Page::<Size4KiB>::containing_address(address);
+        let end_page = Page::<Size4KiB>::containing_address(end_address);
+
+        Page::range_inclusive(start_page, end_page).all(|page| {
+            self.effective_flags(page.start_address())
+                .is_some_and(|page_flags| page_flags.contains(flags))
+        })
+    }
+
+    /// Returns whether the address is on a present, user accessible and executable page.
+    pub fn is_user_executable(&self, address: VirtAddr) -> bool {
+        self.effective_flags(address).is_some_and(|flags| {
+            flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE)
+                && !flags.contains(PageTableFlags::NO_EXECUTE)
+        })
+    }
+
+    /// Returns the flags the address is mapped with, taking the entries of all four levels.
+    /// `USER_ACCESSIBLE` and `WRITABLE` are kept only if every level sets them,
+    /// `NO_EXECUTE` is set if any level does.
+    fn effective_flags(&self, address: VirtAddr) -> Option<PageTableFlags> {
+        let inherited = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+        let mut allowed = inherited;
+        let mut no_execute = PageTableFlags::empty();
+        let mut table = self.inner.level_4_table();
+        let indices = [
+            address.p4_index(),
+            address.p3_index(),
+            address.p2_index(),
+            address.p1_index(),
+        ];
+
+        for (depth, index) in indices.into_iter().enumerate() {
+            let entry = &table[index];
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+            allowed &= flags;
+            no_execute |= flags & PageTableFlags::NO_EXECUTE;
+            // A huge page ends the walk at the p3 or the p2 entry.
+            if depth == 3 || (depth > 0 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+                return Some((flags - inherited) | allowed | no_execute);
+            }
+            let table_address = convert_physical_to_virtual(entry.addr());
+            table = unsafe { &*table_address.as_ptr::<PageTable>() };
+        }
+        None
+    }
+
     /// Read data from the virtual address on the page table.
-    pub fn read(&self, address: VirtAddr, len: usize, buffer: &mut [u8]) -> Result<(), ()> {
+    pub fn read(&self, address: VirtAddr, len: usize, buffer: &mut [u8]) -> crate::Result<()> {
+        if len > buffer.len() {
+            return Err(Error::InvalidArgument);
+        }
         for offset in 0..len {
             let src_address = address + offset as u64;
 
-            let physical_address = self.translate_addr(src_address).ok_or(())?;
+            let physical_address = self.translate_addr(src_address).ok_or(Error::NotMapped)?;
 
             let virtual_address = convert_physical_to_virtual(physical_address);
 
@@ -273,10 +434,14 @@ impl GeneralPageTable {
     }
 
     /// Write data to the virtual address on the page table.
-    pub fn write(&self, buffer: &[u8], address: VirtAddr) -> Result<(), ()> {
+    /// A copy-on-write page is rejected, it must be resolved with `resolve_cow_range` first.
+    pub fn write(&self, buffer: &[u8], address: VirtAddr) -> crate::Result<()> {
         for (offset, &byte) in buffer.iter().enumerate() {
             let target_address = address + offset as u64;
-            let physical_address = self.translate_addr(target_address).ok_or(())?;
+            let (physical_address, flags) = self.query(target_address).ok_or(Error::NotMapped)?;
+            if flags.contains(COW_FLAG) {
+                return Err(Error::InvalidArgument);
+            }
             let virtual_address = convert_physical_to_virtual(physical_address);
             unsafe {
                 (virtual_address.as_u64() as *mut u8).write(byte);
@@ -286,13 +451,104 @@ impl GeneralPageTable {
     }
 }
 
+/// Walks the tables depth first, `tables[level]` is the table of the level `level + 1`.
+struct Mappings<'a> {
+    tables: [&'a PageTable; 4],
+    /// The index of the next entry to look at in each table.
+    indices: [usize; 4],
+    level: usize,
+    /// The 4 KiB pages of the leaf entry being yielded.
+    run: Option<MappingRun>,
+}
+
+struct MappingRun {
+    start_address: VirtAddr,
+    physical_address: PhysAddr,
+    flags: PageTableFlags,
+    pages: u64,
+    next: u64,
+}
+
+impl Mappings<'_> {
+    /// Returns the virtual address the entry of the current table at `index` maps.
+    fn entry_address(&self, index: usize) -> VirtAddr {
+        let mut address = (index as u64) << (12 + 9 * self.level);
+        for level in self.level + 1..4 {
+            address |= ((self.indices[level] - 1) as u64) << (12 + 9 * level);
+        }
+        VirtAddr::new_truncate(address)
+    }
+}
+
+impl Iterator for Mappings<'_> {
+    type Item = (Page<Size4KiB>, PhysFrame, PageTableFlags);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(run) = &mut self.run {
+                if run.next < run.pages {
+                    let offset = run.next * Size4KiB::SIZE;
+                    run.next += 1;
+                    let page = Page::containing_address(run.start_address + offset);
+                    let frame = PhysFrame::containing_address(run.physical_address + offset);
+                    return Some((page, frame, run.flags));
+                }
+                self.run = None;
+            }
+
+            let index = self.indices[self.level];
+            if index == 512 {
+                if self.level == 3 {
+                    return None;
+                }
+                self.level += 1;
+                continue;
+            }
+            self.indices[self.level] += 1;
+
+            let entry = &self.tables[self.level][index];
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            if self.level == 0 || (self.level < 3 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+                self.run = Some(MappingRun {
+                    start_address: self.entry_address(index),
+                    physical_address: entry.addr(),
+                    flags,
+                    pages: 1 << (9 * self.level),
+                    next: 0,
+                });
+                continue;
+            }
+
+            let table_address = convert_physical_to_virtual(entry.addr());
+            let table = unsafe { &*table_address.as_ptr::<PageTable>() };
+            self.level -= 1;
+            self.tables[self.level] = table;
+            self.indices[self.level] = 0;
+        }
+    }
+}
+
 /// In syscall, we don't need to worry about page tables, because we are using the user page table.
 /// Use this function instead of `write` in syscall.
-pub fn write_for_syscall<T: Clone>(addr: VirtAddr, buf: &[T]) {
+/// Returns an error without writing anything if the range isn't mapped as user writable.
+pub fn write_for_syscall<T: Clone>(addr: VirtAddr, buf: &[T]) -> crate::Result<()> {
+    let len = core::mem::size_of_val(buf) as u64;
+    let mut page_table = unsafe { GeneralPageTable::ref_from_current() };
+    interrupts::without_interrupts(|| {
+        page_table.resolve_cow_range(addr, len, &mut FRAME_ALLOCATOR.lock())
+    })?;
+    if !page_table.is_user_writable(addr, len) {
+        return Err(Error::NotMapped);
+    }
+
     let reffer: *mut T = addr.as_mut_ptr();
     for (idx, byte) in buf.iter().enumerate() {
         unsafe {
             reffer.add(idx).write(byte.clone());
         }
     }
+    Ok(())
 }
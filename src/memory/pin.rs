@@ -0,0 +1,85 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{Page, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+use super::{GeneralPageTable, FRAME_ALLOCATOR};
+use crate::Error;
+
+/// The frames whose physical address must not change, with the number of times each is pinned.
+static PINNED_FRAMES: Mutex<BTreeMap<PhysFrame, usize>> = Mutex::new(BTreeMap::new());
+
+/// Returns whether the frame is pinned, the swap clock must never pick it as a victim.
+pub fn is_pinned(frame: PhysFrame) -> bool {
+    interrupts::without_interrupts(|| PINNED_FRAMES.lock().contains_key(&frame))
+}
+
+/// Pins the frames, a frame pinned twice has to be unpinned twice.
+pub(crate) fn pin_frames(frames: impl IntoIterator<Item = PhysFrame>) {
+    interrupts::without_interrupts(|| {
+        let mut pinned_frames = PINNED_FRAMES.lock();
+        for frame in frames {
+            *pinned_frames.entry(frame).or_default() += 1;
+        }
+    });
+}
+
+pub(crate) fn unpin_frames(frames: impl IntoIterator<Item = PhysFrame>) {
+    interrupts::without_interrupts(|| {
+        let mut pinned_frames = PINNED_FRAMES.lock();
+        for frame in frames {
+            match pinned_frames.get_mut(&frame) {
+                Some(count) if *count > 1 => *count -= 1,
+                Some(_) => {
+                    pinned_frames.remove(&frame);
+                }
+                None => {
+                    log::warn!("The frame {:#x} isn't pinned", frame.start_address().as_u64());
+                }
+            }
+        }
+    });
+}
+
+/// Pins the frames a range of the current page table maps, for a driver buffer a device accesses.
+/// The copy-on-write pages are copied first, so their frames stay the same while pinned.
+/// Fails without pinning anything if a page of the range isn't mapped.
+pub fn pin_range(address: VirtAddr, len: u64) -> crate::Result<()> {
+    pin_frames(frames_of(address, len)?);
+    Ok(())
+}
+
+/// Unpins the frames `pin_range` pinned for the range.
+pub fn unpin_range(address: VirtAddr, len: u64) -> crate::Result<()> {
+    unpin_frames(frames_of(address, len)?);
+    Ok(())
+}
+
+fn frames_of(address: VirtAddr, len: u64) -> crate::Result<Vec<PhysFrame>> {
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    let end_address = address
+        .as_u64()
+        .checked_add(len - 1)
+        .and_then(|end_address| VirtAddr::try_new(end_address).ok())
+        .ok_or(Error::InvalidArgument)?;
+
+    let mut page_table = unsafe { GeneralPageTable::ref_from_current() };
+    interrupts::without_interrupts(|| {
+        page_table.resolve_cow_range(address, len, &mut FRAME_ALLOCATOR.lock())
+    })?;
+
+    let start_page = Page::<Size4KiB>::containing_address(address);
+    let end_page = Page::<Size4KiB>::containing_address(end_address);
+    Page::range_inclusive(start_page, end_page)
+        .map(|page| {
+            page_table
+                .query(page.start_address())
+                .map(|(physical_address, _)| PhysFrame::containing_address(physical_address))
+                .ok_or(Error::NotMapped)
+        })
+        .collect()
+}
@@ -4,12 +4,14 @@
 * @author  :   zzjcarrot
 */
 
-use crate::{memory::FRAME_ALLOCATOR, ref_to_mut, task::Process};
-use alloc::sync::Weak;
+use crate::memory::{GeneralPageTable, FRAME_ALLOCATOR, KERNEL_HEAP_RANGE, ZERO_FRAME};
+use crate::task::stack::{USER_STACK_END, USER_STACK_SIZE};
+use crate::Error;
+use alloc::collections::BTreeMap;
+use core::ops::Range;
 use core::{alloc::{Allocator, Layout}, ptr::NonNull};
-use spin::RwLock;
 use x86_64::{
-    structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags},
+    structures::paging::{Mapper, Page, PageTableFlags},
     VirtAddr,
 };
 use talc::*;
@@ -21,144 +23,242 @@ pub enum HeapType {
 
 pub const HEAP_START: u64 = 20 * 1024 * 1024 * 1024 * 1024; // 20TB(用户程序空间18TB~20TB)
 pub const USER_HEAP_INIT_SIZE: usize = 128 * 1024; // 128KB
+pub const USER_HEAP_MAX_SIZE: usize = 1024 * 1024 * 1024 * 1024; // 1TB
+
+/// The addresses user memory may be mapped at, from above the null page to the end of the
+/// lower half. The kernel heap lies inside it and is checked separately.
+pub const USER_WINDOW: Range<u64> = 0x1000..0x0000_8000_0000_0000;
+
+/// Where the heap of a process lives and how far it may grow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapConfig {
+    pub base: u64,
+    pub init_size: usize,
+    pub max_size: usize,
+}
+
+impl Default for HeapConfig {
+    fn default() -> Self {
+        Self {
+            base: HEAP_START,
+            init_size: USER_HEAP_INIT_SIZE,
+            max_size: USER_HEAP_MAX_SIZE,
+        }
+    }
+}
+
+impl HeapConfig {
+    /// Returns whether the base and sizes are page aligned, the initial size fits in the maximum
+    /// and the heap stays in the user window, clear of the kernel heap and the user stack.
+    pub fn is_valid(&self) -> bool {
+        let aligned = self.base % 4096 == 0
+            && self.init_size % 4096 == 0
+            && self.init_size <= self.max_size;
+        if !aligned || self.base.checked_add(self.max_size as u64).is_none() {
+            return false;
+        }
+
+        let range = self.range();
+        let stack = (USER_STACK_END - USER_STACK_SIZE) as u64..USER_STACK_END as u64;
+        let overlaps = |other: &Range<u64>| range.start < other.end && other.start < range.end;
+        range.start >= USER_WINDOW.start
+            && range.end <= USER_WINDOW.end
+            && !overlaps(&KERNEL_HEAP_RANGE)
+            && !overlaps(&stack)
+    }
+
+    /// Returns the address range the heap may grow into.
+    pub fn range(&self) -> Range<u64> {
+        self.base..self.base + self.max_size as u64
+    }
+}
 
 pub struct ProcessHeap {
     heap_type: HeapType,
+    config: HeapConfig,
     size: usize,
     usable_size: usize,
     allocator: Talck<spin::Mutex<()>, ClaimOnOom>,
-    process: Option<Weak<RwLock<Process>>>,
+    /// The live allocations by address, with their size, to reject invalid and double frees.
+    allocations: BTreeMap<u64, usize>,
+    /// Whether the heap grows by zero pages, see `disable_cow`.
+    cow: bool,
 }
 
 impl ProcessHeap {
-    pub fn new(heap_type: HeapType) -> Self {
+    pub fn new(heap_type: HeapType, config: HeapConfig) -> Self {
         let size = match heap_type {
             HeapType::Kernel => 0,
-            HeapType::User => USER_HEAP_INIT_SIZE,
+            HeapType::User => config.init_size,
         };
         let allocator = Talck::new(Talc::new(unsafe {
-            ClaimOnOom::new(Span::from_base_size(HEAP_START as *mut u8, size))
+            ClaimOnOom::new(Span::from_base_size(config.base as *mut u8, size))
         }));
 
         Self {
             heap_type,
+            config,
             size,
             usable_size: size,
             allocator,
-            process: None,
+            allocations: BTreeMap::new(),
+            cow: true,
         }
     }
 
-    pub fn init(&self, process: Weak<RwLock<Process>>) {
+    /// Makes the heap grow by private zeroed frames instead of zero pages,
+    /// once the process has several threads, see `GeneralPageTable::map_zeroed_range`.
+    /// Returns whether it grew by zero pages until now.
+    pub fn disable_cow(&mut self) -> bool {
+        core::mem::replace(&mut self.cow, false)
+    }
+
+    /// Maps the initial heap pages into the page table of the process.
+    /// The page table is passed in by the process that owns both, so nothing is aliased.
+    /// The pages are copy-on-write zero pages, they take memory once they're written.
+    /// Fails if there isn't enough memory for the page tables.
+    pub fn init(&mut self, page_table: &mut GeneralPageTable) -> crate::Result<()> {
         match self.heap_type {
             HeapType::User => {
-                ref_to_mut(self).process = Some(process.clone());
                 let mut frame_allocator = FRAME_ALLOCATOR.lock();
-                for page in 0..USER_HEAP_INIT_SIZE / 4096 {
-                    let frame = frame_allocator.allocate_frame().unwrap();
-                    let page =
-                        Page::containing_address(VirtAddr::new(HEAP_START + page as u64 * 4096));
-                    let flags = PageTableFlags::PRESENT
-                        | PageTableFlags::WRITABLE
-                        | PageTableFlags::USER_ACCESSIBLE;
-                    unsafe {
-                        ref_to_mut(&*process.upgrade().unwrap().read())
-                            .page_table
-                            .map_to(page, frame, flags, &mut *frame_allocator)
-                            .unwrap()
-                            .flush();
-                    }
-                }
-                
+                let flags = PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::USER_ACCESSIBLE;
+                page_table
+                    .map_zero_range(
+                        VirtAddr::new(self.config.base),
+                        self.size as u64,
+                        flags,
+                        &mut frame_allocator,
+                    )
+                    .map_err(|_| Error::OutOfMemory)?;
             }
 
             _ => {}
         }
+        Ok(())
     }
 
-    fn sbrk(&mut self, size: usize) {
+    /// Grows the heap by at least `size` bytes.
+    /// Fails if that would exceed the maximum size or there isn't memory for the page tables.
+    fn sbrk(&mut self, size: usize, page_table: &mut GeneralPageTable) -> Result<(), ()> {
         let page_cnt = (size + 4095) / 4096;
+        if self.size + page_cnt * 4096 > self.config.max_size {
+            return Err(());
+        }
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::USER_ACCESSIBLE;
+        let start_address = VirtAddr::new(self.config.base + self.size as u64);
+        let length = page_cnt as u64 * 4096;
+        let result = if self.cow {
+            page_table.map_zero_range(start_address, length, flags, &mut frame_allocator)
+        } else {
+            page_table.map_zeroed_range(start_address, length, flags, &mut frame_allocator)
+        };
+        // Talc writes its metadata at the new top, which faults on the zero page,
+        // and the copy-on-write fault takes the allocator.
+        drop(frame_allocator);
+        result.map_err(|_| ())?;
+        // The span grows after the mapping, by whole pages to stay in step with `self.size`.
         unsafe {
-            let old = Span::from_base_size(HEAP_START as *mut u8, self.size);
-            let new = old.extend(0, size);
+            let old = Span::from_base_size(self.config.base as *mut u8, self.size);
+            let new = old.extend(0, page_cnt * 4096);
             self.allocator.lock().extend(old, new);
         };
-        let mut frame_allocator = FRAME_ALLOCATOR.lock();
-        let process = self.process.as_ref().unwrap().upgrade().unwrap();
-        let process = process.read();
-
-        let process = ref_to_mut(&*process);
-        for _ in 0..page_cnt {
-            let frame = frame_allocator.allocate_frame().unwrap();
-            let page = Page::containing_address(VirtAddr::new(HEAP_START + self.size as u64));
-            let flags = PageTableFlags::PRESENT
-                | PageTableFlags::WRITABLE
-                | PageTableFlags::USER_ACCESSIBLE;
-            unsafe {
-                process
-                    .page_table
-                    .map_to(page, frame, flags, &mut *frame_allocator)
-                    .unwrap()
-                    .flush();
-            }
 
-            /*KERNEL_PAGE_TABLE
-            .try_get()
-            .unwrap()
-            .lock()
-            .unmap(page)
-            .unwrap()
-            .1
-            .flush();*/
-
-            self.size += 4096;
-            self.usable_size += 4096;
-        }
+        self.size += page_cnt * 4096;
+        self.usable_size += page_cnt * 4096;
+        Ok(())
     }
 
-    pub fn allocate(&mut self, layout: Layout) -> Option<u64> {
+    /// Allocates memory on the heap, growing it in the page table if it is full.
+    /// Returns None if the heap would grow past its maximum size.
+    pub fn allocate(&mut self, layout: Layout, page_table: &mut GeneralPageTable) -> Option<u64> {
         match self.heap_type {
             HeapType::Kernel => {
                 panic!("Don't use process heaps in kernel mode! Use kernel heap instead!")
             }
             _ => {}
         }
-        if let Ok(ptr) = self.allocator.allocate(layout) {
-            self.usable_size -= layout.size();
-            Some(ptr.addr().get() as u64)
-        } else {
-            self.sbrk(layout.size() * 2);
-            let ptr = self.allocator.allocate(layout).unwrap();
-            self.usable_size -= layout.size();
-            Some(ptr.addr().get() as u64)
-        }
+        let ptr = match self.allocator.allocate(layout) {
+            Ok(ptr) => ptr,
+            Err(_) => {
+                self.sbrk(layout.size() * 2, page_table).ok()?;
+                self.allocator.allocate(layout).ok()?
+            }
+        };
+        let ptr = ptr.addr().get() as u64;
+        self.usable_size -= layout.size();
+        self.allocations.insert(ptr, layout.size());
+        Some(ptr)
     }
 
-    pub fn deallocate(&mut self, ptr: u64, layout: Layout) {
+    /// Frees memory returned by `allocate`.
+    /// A pointer outside the heap, not allocated, already freed or with another size is rejected.
+    pub fn deallocate(&mut self, ptr: u64, layout: Layout) -> Result<(), &'static str> {
         match self.heap_type {
             HeapType::Kernel => panic!("Don't use process heaps in kernel mode!"),
             _ => {}
         }
+
+        let end = ptr.checked_add(layout.size() as u64);
+        let heap_end = self.config.base + self.size as u64;
+        if ptr < self.config.base || end.map_or(true, |end| end > heap_end) {
+            log::warn!("Invalid free of {:#x} outside the process heap", ptr);
+            return Err("The pointer is outside the heap");
+        }
+        match self.allocations.get(&ptr) {
+            Some(&size) if size == layout.size() => {}
+            Some(_) => {
+                log::warn!("Invalid free of {:#x} with the wrong size", ptr);
+                return Err("The layout doesn't match the allocation");
+            }
+            None => {
+                log::warn!("Invalid or double free of {:#x}", ptr);
+                return Err("The pointer is not allocated");
+            }
+        }
+
+        self.allocations.remove(&ptr);
         unsafe {
             self.allocator.deallocate(NonNull::new(ptr as *mut u8).unwrap(), layout);
         }
         self.usable_size += layout.size();
+        Ok(())
     }
 
-    pub fn clear(&mut self) {
+    /// Returns where the heap lives and how far it may grow.
+    pub fn config(&self) -> HeapConfig {
+        self.config
+    }
+
+    /// Returns the size of the heap mapped so far.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the bytes of the heap not handed out by `allocate`.
+    pub fn usable_size(&self) -> usize {
+        self.usable_size
+    }
+
+    /// Returns the bytes handed out by `allocate` and not deallocated yet.
+    pub fn allocated(&self) -> usize {
+        self.size - self.usable_size
+    }
+
+    /// Unmaps and frees all heap pages from the page table.
+    pub fn clear(&mut self, page_table: &mut GeneralPageTable) {
         let page_cnt = (self.size + 4095) / 4096;
         let mut frame_allocator = FRAME_ALLOCATOR.lock();
-        let process = self.process.as_ref().unwrap().upgrade().unwrap();
-        unsafe {
-            process.force_write_unlock();
-        }
-        let mut process = process.write();
 
         for page in 0..page_cnt {
-            let page = Page::containing_address(VirtAddr::new(HEAP_START + page as u64 * 4096));
+            let page =
+                Page::containing_address(VirtAddr::new(self.config.base + page as u64 * 4096));
             let frame = {
-                let (frame, mapper_flush) = process
-                    .page_table
+                let (frame, mapper_flush) = page_table
                     .unmap(page)
                     .unwrap();
 
@@ -166,6 +266,10 @@ impl ProcessHeap {
 
                 frame
             };
+            // A page which was never written still maps the shared zero frame.
+            if frame == *ZERO_FRAME {
+                continue;
+            }
             use x86_64::structures::paging::FrameDeallocator;
             unsafe {
                 frame_allocator.deallocate_frame(frame);
@@ -173,5 +277,6 @@ impl ProcessHeap {
         }
         self.size = 0;
         self.usable_size = 0;
+        self.allocations.clear();
     }
 }
@@ -0,0 +1,128 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::arch::smp::{cpu_stats, CpuStats, BSP_LAPIC_ID};
+use crate::task::process::ProcessId;
+use crate::task::{get_process, process_ids};
+
+/// Where the kernel's VFS should mount the files, the paths below are relative to it.
+pub const MOUNT_POINT: &str = "/proc";
+
+/// The files at the root of the filesystem, besides a directory per process.
+const ROOT_FILES: [&str; 3] = ["meminfo", "cpuinfo", "interrupts"];
+
+/// Reads one of the counters of a CPU, for a row of `/proc/interrupts`.
+type Counter = fn(&CpuStats) -> u64;
+
+/// Returns the content of the file at `path`, generated from the current state of the kernel.
+/// Returns `None` if there is no such file, the files are read-only.
+pub fn read(path: &str) -> Option<String> {
+    match components(path).as_slice() {
+        ["meminfo"] => Some(meminfo()),
+        ["cpuinfo"] => Some(cpuinfo()),
+        ["interrupts"] => Some(interrupts()),
+        [pid, "status"] => status(parse_pid(pid)?),
+        _ => None,
+    }
+}
+
+/// Returns the names of the entries in the directory at `path`, or `None` if it isn't one.
+pub fn read_dir(path: &str) -> Option<Vec<String>> {
+    match components(path).as_slice() {
+        [] => {
+            let files = ROOT_FILES.iter().map(|name| name.to_string());
+            let pids = process_ids().into_iter().map(|pid| pid.0.to_string());
+            Some(files.chain(pids).collect())
+        }
+        [pid] => {
+            get_process(parse_pid(pid)?)?.upgrade()?;
+            Some(Vec::from(["status".to_string()]))
+        }
+        _ => None,
+    }
+}
+
+/// Returns whether `path` is a directory, for the directory checker of the VFS.
+pub fn is_dir(path: &str) -> bool {
+    read_dir(path).is_some()
+}
+
+fn components(path: &str) -> Vec<&str> {
+    path.split('/')
+        .filter(|component| !component.is_empty())
+        .collect()
+}
+
+fn parse_pid(pid: &str) -> Option<ProcessId> {
+    pid.parse().ok().map(ProcessId)
+}
+
+fn meminfo() -> String {
+    let stats = crate::memory::stats();
+    format!(
+        "MemTotal: {:>12} kB\nMemFree:  {:>12} kB\nMemUsed:  {:>12} kB\n",
+        stats.total / 1024,
+        stats.free / 1024,
+        stats.total.saturating_sub(stats.free) / 1024
+    )
+}
+
+fn cpuinfo() -> String {
+    let mut content = String::new();
+    for (processor, lapic_id) in cpu_stats().into_keys().enumerate() {
+        let bsp = if lapic_id == *BSP_LAPIC_ID { "yes" } else { "no" };
+        let _ = write!(
+            content,
+            "processor\t: {}\napicid\t\t: {}\nbsp\t\t: {}\n\n",
+            processor, lapic_id, bsp
+        );
+    }
+    content
+}
+
+fn interrupts() -> String {
+    let stats = cpu_stats();
+    let mut content = String::from("    ");
+    for lapic_id in stats.keys() {
+        let _ = write!(content, " {:>12}", format!("CPU{}", lapic_id));
+    }
+
+    let rows: [(&str, Counter, &str); 4] = [
+        ("LOC", |stats| stats.ticks, "Local timer interrupts"),
+        ("ALL", |stats| stats.interrupts, "All interrupts"),
+        ("CSW", |stats| stats.context_switches, "Context switches"),
+        ("IDL", |stats| stats.idle_ticks, "Idle timer ticks"),
+    ];
+    for (name, counter, description) in rows {
+        let _ = write!(content, "\n{}:", name);
+        for cpu_stats in stats.values() {
+            let _ = write!(content, " {:>12}", counter(cpu_stats));
+        }
+        let _ = write!(content, "   {}", description);
+    }
+    content.push('\n');
+    content
+}
+
+fn status(pid: ProcessId) -> Option<String> {
+    let process = get_process(pid)?.upgrade()?;
+    let process = process.read();
+    let (heap_used, heap_size) = process.heap_usage();
+
+    let mut content = String::new();
+    let _ = writeln!(content, "Name:\t{}", process.name());
+    let _ = writeln!(content, "Pid:\t{}", process.id.0);
+    let parent = process.father.as_ref().and_then(|father| father.upgrade());
+    let _ = writeln!(content, "PPid:\t{}", parent.map_or(0, |father| father.read().id.0));
+    let _ = writeln!(content, "Cwd:\t{}", process.cwd());
+    let _ = writeln!(content, "HeapUsed:\t{} kB", heap_used / 1024);
+    let _ = writeln!(content, "HeapSize:\t{} kB", heap_size / 1024);
+    let threads = process.threads_snapshot();
+    let _ = writeln!(content, "Threads:\t{}", threads.len());
+    for (id, state) in threads {
+        let _ = writeln!(content, "Thread:\t{} {:?}", id.0, state);
+    }
+    Some(content)
+}
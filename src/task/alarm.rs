@@ -0,0 +1,71 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::process::WeakSharedProcess;
+use super::scheduler::Scheduler;
+use super::signal::{deliver_signal, well_known, Signal};
+use crate::drivers::hpet::uptime_ns;
+
+/// A pending alarm of a process.
+struct Alarm {
+    deadline: u64,
+    process: WeakSharedProcess,
+}
+
+/// The pending alarms, at most one per process.
+static ALARMS: Mutex<Vec<Alarm>> = Mutex::new(Vec::new());
+
+/// Sends an `ALARM` signal to the current process after `ms` milliseconds.
+/// Setting an alarm cancels the previous one of the process, 0 only cancels it.
+///
+/// The deadline is checked against the HPET counter on every scheduler tick,
+/// so the signal arrives up to one tick late. A deadline too far away never fires.
+pub fn set_alarm(ms: u64) {
+    let Some(thread) = super::current_thread().upgrade() else {
+        return;
+    };
+    let process = thread.read().process.clone();
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut alarms = ALARMS.lock();
+        alarms.retain(|alarm| !alarm.process.ptr_eq(&process));
+        if ms != 0 {
+            alarms.push(Alarm {
+                deadline: uptime_ns().saturating_add(ms.saturating_mul(1_000_000)),
+                process,
+            });
+        }
+    });
+}
+
+/// Delivers the expired alarms, called by the scheduler on each tick.
+pub(super) fn fire_alarms(scheduler: &mut Scheduler) {
+    // Another CPU is already on it or the interrupted code is setting an alarm.
+    let Some(mut alarms) = ALARMS.try_lock() else {
+        return;
+    };
+    if alarms.is_empty() {
+        return;
+    }
+
+    let now = uptime_ns();
+    alarms.retain(|alarm| {
+        if alarm.deadline > now {
+            return true;
+        }
+        let Some(process) = alarm.process.upgrade() else {
+            return false;
+        };
+        // Retry on the next tick if the process is locked by the interrupted code.
+        let Some(mut process) = process.try_write() else {
+            return true;
+        };
+
+        let signal = Signal {
+            ty: well_known::ALARM,
+            data: [0; 8],
+        };
+        deliver_signal(&mut process, signal, scheduler);
+        false
+    });
+}
@@ -0,0 +1,105 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Waker};
+use spin::{Lazy, Mutex, Once};
+use x86_64::instructions::interrupts;
+
+use super::thread::WeakSharedThread;
+use super::{block_current, unblock, Thread};
+
+type Task = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// The executor of `spawn`, its thread is created on the first spawn.
+static EXECUTOR: Lazy<Executor> = Lazy::new(Executor::new);
+
+/// Runs `future` on the kernel executor thread, alongside the other spawned futures.
+///
+/// The futures are polled one at a time on that thread, when their waker is woken,
+/// so they must not block it: an I/O-bound driver `.await`s instead, e.g. a `KeyStream`.
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+    EXECUTOR.start();
+    EXECUTOR.spawn(future);
+}
+
+/// A cooperative executor for the futures of the kernel, `spawn` uses a global one.
+/// Its thread sleeps with `block_current` while no future was woken.
+pub struct Executor {
+    tasks: Mutex<BTreeMap<u64, Task>>,
+    /// The ids of the woken tasks, also pushed by interrupt handlers.
+    ready: Mutex<VecDeque<u64>>,
+    thread: Once<WeakSharedThread>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Self {
+            tasks: Mutex::new(BTreeMap::new()),
+            ready: Mutex::new(VecDeque::new()),
+            thread: Once::new(),
+        }
+    }
+
+    /// Creates the kernel thread which polls the futures, if it isn't running yet.
+    pub fn start(&'static self) {
+        self.thread.call_once(|| Thread::spawn_kernel(move || self.run()));
+    }
+
+    /// Adds a future, it is polled once the executor is started.
+    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        self.tasks.lock().insert(id, Box::pin(future));
+        self.wake(id);
+    }
+
+    fn wake(&self, id: u64) {
+        interrupts::without_interrupts(|| self.ready.lock().push_back(id));
+        if let Some(thread) = self.thread.get() {
+            unblock(thread);
+        }
+    }
+
+    fn run(&'static self) -> ! {
+        loop {
+            let Some(id) = interrupts::without_interrupts(|| self.ready.lock().pop_front()) else {
+                block_current(|| self.ready.lock().is_empty());
+                continue;
+            };
+            // A task may be woken again after it completed.
+            let Some(mut task) = self.tasks.lock().remove(&id) else {
+                continue;
+            };
+
+            let waker = Waker::from(Arc::new(TaskWaker { id, executor: self }));
+            if task.as_mut().poll(&mut Context::from_waker(&waker)).is_pending() {
+                self.tasks.lock().insert(id, task);
+            }
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct TaskWaker {
+    id: u64,
+    executor: &'static Executor,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.executor.wake(self.id);
+    }
+}
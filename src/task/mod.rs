@@ -1,13 +1,47 @@
+mod alarm;
 pub mod context;
+pub mod executor;
 pub mod process;
 pub mod scheduler;
 pub mod signal;
 pub mod stack;
+pub mod sync;
 pub mod thread;
 
-pub use process::Process;
+use alloc::sync::Weak;
+use core::mem::ManuallyDrop;
+use spin::RwLock;
+
+use crate::arch::smp::current_thread_ptr;
+use process::WeakSharedProcess;
+use scheduler::SCHEDULER;
+use thread::{ThreadState, WeakSharedThread};
+
+pub use alarm::set_alarm;
+pub use process::{get_process, process_ids, Process};
 pub use scheduler::init;
-pub use thread::Thread;
+pub use thread::{find_thread, Thread};
+
+/// Returns the thread running on the current CPU, read from the GS base.
+/// The returned thread can't be upgraded before the scheduler is initialized.
+pub fn current_thread() -> WeakSharedThread {
+    let thread = current_thread_ptr() as *const RwLock<Thread>;
+    if thread.is_null() {
+        return Weak::new();
+    }
+    // The scheduler keeps a `Weak` of the current thread, so the pointer stays valid.
+    let thread = ManuallyDrop::new(unsafe { Weak::from_raw(thread) });
+    Weak::clone(&thread)
+}
+
+/// Returns the process of the thread running on the current CPU.
+/// Drivers can keep it with `current_thread` to find who to wake when an I/O completes.
+pub fn current_process() -> WeakSharedProcess {
+    match current_thread().upgrade() {
+        Some(thread) => thread.read().process.clone(),
+        None => Weak::new(),
+    }
+}
 
 /// Schedules the next task.
 /// It uses a interrupt.
@@ -17,3 +51,45 @@ pub fn schedule() {
         core::arch::asm!("int 0x20");
     }
 }
+
+/// Terminates the current kernel thread, the scheduler frees it once another thread runs.
+pub fn exit_current_thread() -> ! {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if let Some(thread) = current_thread().upgrade() {
+            thread.write().state = ThreadState::Terminated;
+        }
+    });
+    loop {
+        schedule();
+    }
+}
+
+/// Blocks the current thread until `unblock` is called on it, if `should_block` returns true.
+///
+/// The thread is marked waiting before `should_block` runs, with the interrupts disabled,
+/// so an `unblock` coming after the condition was checked under the caller's lock isn't lost.
+pub fn block_current(should_block: impl FnOnce() -> bool) {
+    let Some(thread) = current_thread().upgrade() else {
+        return;
+    };
+    let block = x86_64::instructions::interrupts::without_interrupts(|| {
+        thread.write().state = ThreadState::Waiting;
+        let block = should_block();
+        if !block {
+            let mut thread = thread.write();
+            // An `unblock` in between already made it ready.
+            if thread.state == ThreadState::Waiting {
+                thread.state = ThreadState::Ready;
+            }
+        }
+        block
+    });
+    if block {
+        schedule();
+    }
+}
+
+/// Makes a thread blocked by `block_current` ready again, nothing happens if it isn't waiting.
+pub fn unblock(thread: &WeakSharedThread) {
+    x86_64::instructions::interrupts::without_interrupts(|| SCHEDULER.lock().wake(thread.clone()));
+}
@@ -1,39 +1,145 @@
-use alloc::collections::VecDeque;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
 use alloc::string::String;
 use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::alloc::Layout;
 use core::fmt::Debug;
-use core::sync::atomic::{AtomicU64, Ordering};
-use object::{File, Object, ObjectSegment};
-use spin::{Lazy, RwLock};
+use core::ops::Range;
+use core::sync::atomic::AtomicU64;
+use object::elf::{PF_W, PF_X};
+use object::read::elf::FileHeader;
+use object::{File, Object, ObjectSegment, SegmentFlags};
+use spin::{Lazy, Mutex, RwLock};
 use x86_64::instructions::interrupts;
 use x86_64::structures::paging::mapper::CleanUp;
-use x86_64::structures::paging::PageTableFlags;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
 use x86_64::VirtAddr;
 
-use super::signal::SignalManager;
-use super::thread::{SharedThread, Thread};
+use super::scheduler::SCHEDULER;
+use super::signal::{well_known, Signal, SignalManager, SIGNAL_TYPE_NUM};
+use super::stack::{auxv, USER_STACK_END, USER_STACK_SIZE};
+use super::thread::{next_id, SharedThread, Thread, ThreadId, ThreadState};
 use crate::memory::MemoryManager;
-use crate::memory::{create_page_table_from_kernel, HeapType, ProcessHeap};
-use crate::memory::{GeneralPageTable, FRAME_ALLOCATOR};
+use crate::memory::{convert_physical_to_virtual, GeneralPageTable, FRAME_ALLOCATOR};
+use crate::memory::{create_page_table_from_kernel, HeapConfig, HeapType, ProcessHeap};
+use crate::memory::{FileBacking, FileMapping, MapSharing, KERNEL_HEAP_RANGE, USER_WINDOW};
 
 pub(super) type SharedProcess = Arc<RwLock<Process>>;
-pub(super) type WeakSharedProcess = Weak<RwLock<Process>>;
+pub type WeakSharedProcess = Weak<RwLock<Process>>;
 
-static PROCESSES: RwLock<VecDeque<SharedProcess>> = RwLock::new(VecDeque::new());
+/// Owns the user processes which haven't exited, by id.
+static PROCESSES: RwLock<BTreeMap<ProcessId, SharedProcess>> = RwLock::new(BTreeMap::new());
+/// Every process which isn't freed yet, by id, for `get_process`.
+static PROCESS_TABLE: RwLock<BTreeMap<ProcessId, WeakSharedProcess>> = RwLock::new(BTreeMap::new());
 pub static KERNEL_PROCESS: Lazy<SharedProcess> = Lazy::new(|| Process::new_kernel_process());
 
+/// Killed processes whose threads may still be running on a CPU, the scheduler frees them later.
+pub(super) static DEAD_PROCESSES: Mutex<Vec<SharedProcess>> = Mutex::new(Vec::new());
+
 const KERNEL_PROCESS_NAME: &str = "kernel";
 
+/// Checks whether an absolute path is a directory, for `Process::chdir`.
+pub type DirectoryCheckerFn = fn(path: &str) -> bool;
+
+/// Accepts every path until the kernel registers its VFS with `regist_directory_checker`.
+static DIRECTORY_CHECKER: Mutex<DirectoryCheckerFn> = Mutex::new(|_| true);
+
+/// Sets the function `Process::chdir` uses to check that the new working directory exists.
+pub fn regist_directory_checker(checker: DirectoryCheckerFn) {
+    *DIRECTORY_CHECKER.lock() = checker;
+}
+
+/// Resolves `path` against the absolute directory `base`.
+/// The result is absolute, without `.`, `..`, repeated or trailing slashes, and `..` stops at the root.
+pub fn resolve_path(base: &str, path: &str) -> String {
+    let base = if path.starts_with('/') { "" } else { base };
+    let mut components = Vec::new();
+    for component in base.split('/').chain(path.split('/')) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+
+    let mut resolved = String::new();
+    for component in components {
+        resolved.push('/');
+        resolved.push_str(component);
+    }
+    if resolved.is_empty() {
+        resolved.push('/');
+    }
+    resolved
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ProcessId(pub u64);
 
 impl ProcessId {
+    /// Returns a new id, the ids are never reused.
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
-        ProcessId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+        ProcessId(next_id(&NEXT_ID))
     }
 }
 
+/// Returns the process with the id, the kernel process included, as long as it isn't freed.
+/// The lookup takes O(log n) and doesn't lock any process.
+pub fn get_process(id: ProcessId) -> Option<WeakSharedProcess> {
+    interrupts::without_interrupts(|| PROCESS_TABLE.read().get(&id).cloned())
+}
+
+/// Returns the ids of every process which isn't freed yet, the kernel process included.
+pub fn process_ids() -> Vec<ProcessId> {
+    interrupts::without_interrupts(|| PROCESS_TABLE.read().keys().copied().collect())
+}
+
+/// Reads the page of a file mapping of the current process which `address` falls in.
+/// Returns whether the page is mapped now, so the faulting access can be retried.
+/// Called by the page fault handler of a user thread with the interrupts enabled,
+/// the file is read without holding the lock of the process.
+pub fn handle_file_fault(address: VirtAddr) -> bool {
+    let Some(process) = super::current_process().upgrade() else {
+        return false;
+    };
+    let mapping = interrupts::without_interrupts(|| {
+        let process = process.read();
+        process
+            .file_mappings
+            .iter()
+            .find(|mapping| mapping.contains(address))
+            .cloned()
+    });
+    let Some(mapping) = mapping else {
+        return false;
+    };
+
+    let frame = match mapping.read_frame(address) {
+        Ok(frame) => frame,
+        Err(err) => {
+            log::warn!("Failed to read the mapped file page at {:#x}: {}", address, err);
+            return false;
+        }
+    };
+    interrupts::without_interrupts(|| {
+        let mut process = process.write();
+        // The mapping may have been unmapped while the file was read.
+        let still_mapped = process
+            .file_mappings
+            .iter()
+            .any(|other| other.start() == mapping.start());
+        if !still_mapped {
+            drop(process);
+            mapping.release_frame(frame);
+            return false;
+        }
+        mapping.map_frame(address, frame, &mut process.page_table).is_ok()
+    })
+}
+
 #[allow(dead_code)]
 pub struct Process {
     pub id: ProcessId,
@@ -43,64 +149,359 @@ pub struct Process {
     pub heap: ProcessHeap,
     pub signal_manager: SignalManager,
     pub father: Option<WeakSharedProcess>,
+    cwd: String,
+    /// The ranges mapped from files, their pages are read on the first access.
+    file_mappings: Vec<FileMapping>,
 }
 
 impl Process {
     /// Creates a new process.
     /// Don't use this function directly, use `new_user_process` instead.
-    pub fn new(name: &str, heap_type: HeapType) -> Self {
-        let page_table = create_page_table_from_kernel();
+    /// Fails if there isn't enough memory for the page table.
+    pub fn new(
+        name: &str,
+        heap_type: HeapType,
+        heap_config: HeapConfig,
+    ) -> Result<Self, &'static str> {
+        let page_table = create_page_table_from_kernel()
+            .map_err(|_| "Failed to allocate the page table of the process")?;
         let pid = ProcessId::new();
         let process = Process {
             id: pid,
             name: String::from(name),
             page_table,
             threads: Default::default(),
-            heap: ProcessHeap::new(heap_type),
-            signal_manager: SignalManager::new(64),
+            heap: ProcessHeap::new(heap_type, heap_config),
+            signal_manager: SignalManager::new(SIGNAL_TYPE_NUM),
             father: None,
+            cwd: String::from("/"),
+            file_mappings: Vec::new(),
         };
 
+        Ok(process)
+    }
+
+    /// Shares the process and registers it for `get_process`.
+    fn into_shared(self) -> SharedProcess {
+        let id = self.id;
+        let process = Arc::new(RwLock::new(self));
+        interrupts::without_interrupts(|| PROCESS_TABLE.write().insert(id, Arc::downgrade(&process)));
         process
     }
 
     /// Creates a new kernel process.
     /// Dont't use this function. There should be only one kernel process.
     pub fn new_kernel_process() -> SharedProcess {
-        let process = Arc::new(RwLock::new(Self::new(
-            KERNEL_PROCESS_NAME,
-            HeapType::Kernel,
-        )));
-        process.read().heap.init(Arc::downgrade(&process));
+        let process = Self::new(KERNEL_PROCESS_NAME, HeapType::Kernel, HeapConfig::default())
+            .expect("Failed to create the kernel process!")
+            .into_shared();
         process
+            .write()
+            .init_heap()
+            .expect("Failed to create the kernel process!");
+        process
+    }
+
+    /// Creates a new user process from the ELF data.
+    /// The segments are copied into the process, so the data can be freed afterwards,
+    /// e.g. a buffer a file was read into.
+    /// Fails if the ELF can't be parsed, one of its segments lies outside the user window
+    /// or overlaps the heap or stack, or its entry point isn't in an executable segment.
+    pub fn new_user_process(name: &str, elf_data: &[u8]) -> Result<SharedProcess, &'static str> {
+        Self::new_user_process_with_heap(name, elf_data, HeapConfig::default())
     }
 
-    /// Creates a new user process.
-    pub fn new_user_process(name: &str, elf_data: &'static [u8]) -> SharedProcess {
-        let binary = ProcessBinary::parse(elf_data);
-        let process = Arc::new(RwLock::new(Self::new(name, HeapType::User)));
-        process.read().heap.init(Arc::downgrade(&process));
-        ProcessBinary::map_segments(&binary, &mut process.write().page_table);
+    /// The same as `new_user_process`, with the heap placed and sized by `heap_config`.
+    pub fn new_user_process_with_heap(
+        name: &str,
+        elf_data: &[u8],
+        heap_config: HeapConfig,
+    ) -> Result<SharedProcess, &'static str> {
+        Self::load_user_process(name, elf_data, heap_config, None)
+    }
+
+    fn load_user_process(
+        name: &str,
+        elf_data: &[u8],
+        heap_config: HeapConfig,
+        father: Option<&SharedProcess>,
+    ) -> Result<SharedProcess, &'static str> {
+        if !heap_config.is_valid() {
+            return Err("Invalid heap config");
+        }
+        let binary = ProcessBinary::parse(elf_data)?;
+        ProcessBinary::check_segments(&binary, heap_config.range())?;
+        let mut process = Self::new(name, HeapType::User, heap_config)?;
+        if let Some(father) = father {
+            process.cwd = father.read().cwd.clone();
+            process.father = Some(Arc::downgrade(father));
+        }
+        let process = process.into_shared();
+        process.write().init_heap()?;
+        let aux = ProcessBinary::aux_vector(&binary)?;
+        ProcessBinary::map_segments(&binary, &mut process.write().page_table)?;
         //log::info!("User Entry Point: {:x} ID: {:?}", binary.entry(),process.read().id);
-        Thread::new_user_thread(Arc::downgrade(&process), binary.entry() as usize);
-        PROCESSES.write().push_back(process.clone());
-        process
+        Thread::new_main_thread(
+            Arc::downgrade(&process),
+            binary.entry() as usize,
+            &[name],
+            &aux,
+        )?;
+        PROCESSES.write().insert(process.read().id, process.clone());
+        Ok(process)
     }
 
-    pub fn exit_process(&self) {
-        let mut processes = PROCESSES.write();
-        if let Some(index) = processes
+    /// Creates a user process like `new_user_process`, as a child of `father`.
+    /// The child inherits the working directory of its father.
+    pub fn new_child_process(
+        father: &SharedProcess,
+        name: &str,
+        elf_data: &[u8],
+    ) -> Result<SharedProcess, &'static str> {
+        Self::load_user_process(name, elf_data, HeapConfig::default(), Some(father))
+    }
+
+    /// Returns the id of the process.
+    pub fn pid(&self) -> ProcessId {
+        self.id
+    }
+
+    /// Returns the name of the process.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the absolute working directory of the process.
+    pub fn cwd(&self) -> &str {
+        &self.cwd
+    }
+
+    /// Changes the working directory, `path` may be relative to the current one.
+    /// Fails if the directory checker set with `regist_directory_checker` rejects the resolved path.
+    pub fn chdir(&mut self, path: &str) -> Result<(), &'static str> {
+        let path = resolve_path(&self.cwd, path);
+        if !DIRECTORY_CHECKER.lock()(&path) {
+            return Err("Not a directory");
+        }
+        self.cwd = path;
+        Ok(())
+    }
+
+    /// Resolves a path given by the process against its working directory, for the VFS to open.
+    pub fn resolve_path(&self, path: &str) -> String {
+        resolve_path(&self.cwd, path)
+    }
+
+    /// Returns the id and state of each thread of the process.
+    /// Only takes the read locks of the threads, so it can be called while they run.
+    pub fn threads_snapshot(&self) -> Vec<(ThreadId, ThreadState)> {
+        self.threads
             .iter()
-            .position(|process| process.read().id == self.id)
-        {
-            processes.remove(index);
+            .map(|thread| {
+                let thread = thread.read();
+                (thread.id, thread.state)
+            })
+            .collect()
+    }
+
+    /// Returns the allocated bytes and the total size of the heap.
+    pub fn heap_usage(&self) -> (usize, usize) {
+        (self.heap.allocated(), self.heap.size())
+    }
+
+    fn init_heap(&mut self) -> Result<(), &'static str> {
+        self.heap
+            .init(&mut self.page_table)
+            .map_err(|_| "Out of memory for the heap of the process")
+    }
+
+    /// Allocates memory on the heap of the process.
+    pub fn heap_allocate(&mut self, layout: Layout) -> Option<u64> {
+        self.heap.allocate(layout, &mut self.page_table)
+    }
+
+    /// Deallocates memory on the heap of the process, fails on an invalid or double free.
+    pub fn heap_deallocate(&mut self, ptr: u64, layout: Layout) -> Result<(), &'static str> {
+        self.heap.deallocate(ptr, layout)
+    }
+
+    /// Frees the whole heap of the process.
+    pub fn heap_clear(&mut self) {
+        self.heap.clear(&mut self.page_table);
+    }
+
+    /// Drops the copy-on-write pages of the process before it gets a second thread.
+    /// The zero pages get private frames and the heap grows by such frames from now on,
+    /// see `GeneralPageTable::map_zeroed_range`.
+    pub fn disable_cow(&mut self) -> Result<(), &'static str> {
+        if !self.heap.disable_cow() {
+            return Ok(());
+        }
+        interrupts::without_interrupts(|| {
+            self.page_table
+                .resolve_all_cow(&mut FRAME_ALLOCATOR.lock())
+                .map_err(|_| "Out of memory for the copy-on-write pages")
+        })
+    }
+
+    /// Maps `len` bytes of `file` from `offset` at `start`, for the mmap system call.
+    /// The pages are read from the file when they're first accessed.
+    /// `start` and `offset` must be page aligned, and the range must be free user memory.
+    pub fn map_file(
+        &mut self,
+        start: VirtAddr,
+        len: u64,
+        file: Arc<dyn FileBacking>,
+        offset: u64,
+        flags: PageTableFlags,
+        sharing: MapSharing,
+    ) -> Result<(), &'static str> {
+        let flags = flags | PageTableFlags::USER_ACCESSIBLE;
+        let mapping = FileMapping::new(start, len, file, offset, flags, sharing)
+            .map_err(|_| "The file mapping isn't page aligned!")?;
+        let range = mapping.range();
+        if range.start < USER_WINDOW.start || range.end > USER_WINDOW.end {
+            return Err("The file mapping is outside the user address space!");
+        }
+        let overlaps = |other: &Range<u64>| range.start < other.end && other.start < range.end;
+        if self.file_mappings.iter().any(|other| overlaps(&other.range())) {
+            return Err("The file mapping overlaps another one!");
+        }
+        // Only the present tables are walked, a large range isn't probed page by page.
+        let used = self
+            .page_table
+            .iter_mappings()
+            .map(|(page, ..)| page.start_address().as_u64())
+            .find(|&address| address >= range.start)
+            .is_some_and(|address| address < range.end);
+        if used || overlaps(&self.heap.config().range()) {
+            return Err("The file mapping overlaps mapped memory!");
+        }
+
+        self.file_mappings.push(mapping);
+        Ok(())
+    }
+
+    /// Writes the dirty pages of the shared file mapping at `start` back to the file.
+    /// The pages are copied with the process locked and written after it's unlocked,
+    /// so a fault on another CPU doesn't wait for the file.
+    pub fn sync_file(process: &SharedProcess, start: VirtAddr) -> Result<(), &'static str> {
+        let (mapping, dirty) = interrupts::without_interrupts(|| {
+            let mut process = process.write();
+            let process = &mut *process;
+            let mapping = process
+                .file_mappings
+                .iter()
+                .find(|mapping| mapping.start() == start)
+                .cloned()
+                .ok_or("No file is mapped there!")?;
+            let dirty = mapping
+                .take_dirty(&mut process.page_table)
+                .map_err(|_| "Failed to copy the file mapping!")?;
+            Ok((mapping, dirty))
+        })?;
+
+        if mapping.write_back(&dirty).is_err() {
+            interrupts::without_interrupts(|| {
+                let mut process = process.write();
+                let still_mapped = process
+                    .file_mappings
+                    .iter()
+                    .any(|other| other.start() == start);
+                if still_mapped {
+                    mapping.redirty(&dirty, &mut process.page_table);
+                }
+            });
+            return Err("Failed to write the file mapping back!");
+        }
+        Ok(())
+    }
+
+    /// Writes the file mapping at `start` back if it is shared, then unmaps it.
+    /// The mapping stays if it can't be written back.
+    pub fn unmap_file(process: &SharedProcess, start: VirtAddr) -> Result<(), &'static str> {
+        loop {
+            Self::sync_file(process, start)?;
+            let unmapped = interrupts::without_interrupts(|| {
+                let mut process = process.write();
+                let process = &mut *process;
+                let index = process
+                    .file_mappings
+                    .iter()
+                    .position(|mapping| mapping.start() == start)
+                    .ok_or("No file is mapped there!")?;
+                // A thread may have written a page again while it was unlocked.
+                if process.file_mappings[index].is_dirty(&process.page_table) {
+                    return Ok(false);
+                }
+                let mapping = process.file_mappings.remove(index);
+                mapping.release(&mut process.page_table);
+                Ok(true)
+            })?;
+            if unmapped {
+                return Ok(());
+            }
         }
     }
+
+    /// Removes the process and sends `CHILD_EXIT` to its father.
+    pub fn exit_process(&self) {
+        PROCESSES.write().remove(&self.id);
+
+        if let Some(father) = self.father.as_ref().and_then(|father| father.upgrade()) {
+            let mut data = [0; 8];
+            data[0] = self.id.0;
+            let signal = Signal {
+                ty: well_known::CHILD_EXIT,
+                data,
+            };
+            interrupts::without_interrupts(|| {
+                let mut father = father.write();
+                if father
+                    .signal_manager
+                    .register_signal(well_known::CHILD_EXIT, signal)
+                {
+                    let mut scheduler = SCHEDULER.lock();
+                    for thread in father.threads.iter() {
+                        scheduler.wake(Arc::downgrade(thread));
+                    }
+                }
+            });
+        }
+    }
+
+    /// Returns the id of a child which exited, or starts to wait for `CHILD_EXIT` if there is none.
+    pub fn wait_child(&mut self) -> Option<ProcessId> {
+        match self.signal_manager.take_signal(well_known::CHILD_EXIT) {
+            Some(signal) => Some(ProcessId(signal.data[0])),
+            None => {
+                self.signal_manager
+                    .register_wait_for(well_known::CHILD_EXIT);
+                None
+            }
+        }
+    }
+
+    /// Terminates all threads of the process and removes it.
+    /// The process is freed by the scheduler once none of its threads is running.
+    pub fn kill(process: SharedProcess) {
+        for thread in process.read().threads.iter() {
+            thread.write().state = ThreadState::Terminated;
+        }
+        process.read().exit_process();
+        DEAD_PROCESSES.lock().push(process);
+    }
 }
 
 impl Drop for Process {
     /// drop the data of the process.
     fn drop(&mut self) {
+        // The scheduler frees the dead processes in the timer interrupt.
+        interrupts::without_interrupts(|| PROCESS_TABLE.write().remove(&self.id));
+        // The shared mappings should be unmapped before the exit, nothing is written back here.
+        for mapping in core::mem::take(&mut self.file_mappings) {
+            mapping.release(&mut self.page_table);
+        }
         unsafe { self.page_table.clean_up(&mut *FRAME_ALLOCATOR.lock()) };
     }
 }
@@ -108,26 +509,174 @@ impl Drop for Process {
 struct ProcessBinary;
 
 impl ProcessBinary {
-    fn parse(bin: &'static [u8]) -> File<'static> {
-        File::parse(bin).expect("Failed to parse ELF binary!")
+    fn parse(bin: &[u8]) -> Result<File<'_>, &'static str> {
+        File::parse(bin).map_err(|_| "Failed to parse ELF binary!")
     }
 
-    fn map_segments(elf_file: &File, page_table: &mut GeneralPageTable) {
+    /// Checks that every segment lies in the user window and doesn't overlap the kernel heap,
+    /// the user heap, the stack or another segment.
+    /// Segments may share a page as long as their bytes don't overlap.
+    fn check_segments(elf_file: &File, heap: Range<u64>) -> Result<(), &'static str> {
+        let stack = (USER_STACK_END - USER_STACK_SIZE) as u64..USER_STACK_END as u64;
+        let overlaps = |a: &Range<u64>, b: &Range<u64>| a.start < b.end && b.start < a.end;
+        let mut ranges = Vec::new();
+
+        for segment in elf_file.segments() {
+            let start = segment.address();
+            let end = start
+                .checked_add(segment.size())
+                .ok_or("ELF segment wraps around the address space")?;
+            let range = start..end;
+
+            if start < USER_WINDOW.start || end > USER_WINDOW.end {
+                return Err("ELF segment outside of the user address space");
+            }
+            if overlaps(&range, &KERNEL_HEAP_RANGE) {
+                return Err("ELF segment overlaps the kernel heap");
+            }
+            if overlaps(&range, &heap) {
+                return Err("ELF segment overlaps the user heap");
+            }
+            if overlaps(&range, &stack) {
+                return Err("ELF segment overlaps the user stack");
+            }
+            if !range.is_empty() {
+                ranges.push(range);
+            }
+        }
+
+        ranges.sort_unstable_by_key(|range| range.start);
+        if ranges.windows(2).any(|pair| overlaps(&pair[0], &pair[1])) {
+            return Err("ELF segments overlap each other");
+        }
+        Ok(())
+    }
+
+    /// Converts the ELF segment flags to page table flags, segments are always readable.
+    fn segment_flags(segment_flags: SegmentFlags) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if let SegmentFlags::Elf { p_flags } = segment_flags {
+            if p_flags & PF_W != 0 {
+                flags |= PageTableFlags::WRITABLE;
+            }
+            if p_flags & PF_X == 0 {
+                flags |= PageTableFlags::NO_EXECUTE;
+            }
+        }
+        flags
+    }
+
+    /// Returns the auxv entries describing the binary, `AT_RANDOM` is added with the stack.
+    /// `AT_PHDR` is only given if the program headers are in a loaded segment.
+    fn aux_vector(elf_file: &File) -> Result<Vec<(u64, u64)>, &'static str> {
+        let File::Elf64(elf) = elf_file else {
+            return Err("Only 64-bit ELF binaries are supported!");
+        };
+        let header = elf.elf_header();
+        let endian = elf.endian();
+        let phoff = header.e_phoff(endian);
+
+        let mut aux = Vec::from([
+            (auxv::AT_PHENT, header.e_phentsize(endian) as u64),
+            (auxv::AT_PHNUM, elf.elf_program_headers().len() as u64),
+            (auxv::AT_PAGESZ, 4096),
+            (auxv::AT_ENTRY, elf_file.entry()),
+        ]);
+        let phdr = elf_file.segments().find_map(|segment| {
+            let (offset, size) = segment.file_range();
+            (offset..offset + size)
+                .contains(&phoff)
+                .then(|| segment.address() + (phoff - offset))
+        });
+        if let Some(phdr) = phdr {
+            aux.push((auxv::AT_PHDR, phdr));
+        }
+        Ok(aux)
+    }
+
+    /// Maps and copies the segments, which must have passed `check_segments`.
+    /// A page shared by two segments is mapped once, with the union of their flags.
+    /// Any other page mapped before the binary is refused.
+    fn map_segments(
+        elf_file: &File,
+        page_table: &mut GeneralPageTable,
+    ) -> Result<(), &'static str> {
+        // The pages mapped by the earlier segments, only those may be shared.
+        let mut mapped_pages = BTreeSet::new();
         interrupts::without_interrupts(|| {
             for segment in elf_file.segments() {
+                if segment.size() == 0 {
+                    continue;
+                }
                 let segment_address = VirtAddr::new(segment.address() as u64);
 
-                let flags = PageTableFlags::PRESENT
-                    | PageTableFlags::WRITABLE
-                    | PageTableFlags::USER_ACCESSIBLE;
+                let flags = Self::segment_flags(segment.flags());
 
-                <MemoryManager>::alloc_range(segment_address, segment.size(), flags, page_table)
-                    .expect("Failed to allocate memory for ELF segment!");
+                let start_page = Page::<Size4KiB>::containing_address(segment_address);
+                let end_page = Page::containing_address(segment_address + (segment.size() - 1));
+                // The pages past the data from the file are .bss, they can share the zero frame.
+                let (_, file_size) = segment.file_range();
+                let zero_start = (segment_address + file_size).align_up(4096u64);
+                let mut frame_allocator = FRAME_ALLOCATOR.lock();
+
+                for page in Page::range_inclusive(start_page, end_page) {
+                    let present = page_table.query(page.start_address()).is_some();
+                    if present && !mapped_pages.contains(&page) {
+                        return Err("ELF segment overlaps memory mapped before the binary");
+                    }
+                    // A zero page of the previous segment may hold data of this one.
+                    page_table
+                        .resolve_cow(page, &mut frame_allocator)
+                        .map_err(|_| "Failed to allocate memory for ELF segment!")?;
+                    match page_table.query(page.start_address()) {
+                        Some((_, old_flags)) => unsafe {
+                            // The page is executable if either segment is.
+                            let merged = ((old_flags | flags) - PageTableFlags::NO_EXECUTE)
+                                | (old_flags & flags & PageTableFlags::NO_EXECUTE);
+                            page_table
+                                .update_flags(page, merged)
+                                .map_err(|_| "Failed to update flags of ELF segment!")?
+                                .flush();
+                        },
+                        None if page.start_address() >= zero_start
+                            && flags.contains(PageTableFlags::WRITABLE) =>
+                        {
+                            let address = page.start_address();
+                            page_table
+                                .map_zero_range(address, 4096, flags, &mut frame_allocator)
+                                .map_err(|_| "Failed to map ELF segment!")?;
+                        }
+                        None => {
+                            let frame = frame_allocator
+                                .allocate_frame()
+                                .ok_or("Failed to allocate memory for ELF segment!")?;
+                            // Zeroed, so the part of the segment not in the file (.bss) reads as 0.
+                            unsafe {
+                                let frame_address =
+                                    convert_physical_to_virtual(frame.start_address());
+                                core::ptr::write_bytes(frame_address.as_mut_ptr::<u8>(), 0, 4096);
+                            }
+                            <MemoryManager>::map_frame_to_page(
+                                frame,
+                                page,
+                                flags,
+                                page_table,
+                                &mut frame_allocator,
+                            )
+                            .map_err(|_| "Failed to map ELF segment!")?;
+                        }
+                    }
+                    mapped_pages.insert(page);
+                }
+                drop(frame_allocator);
 
                 if let Ok(data) = segment.data() {
-                    page_table.write(data, segment_address).unwrap();
+                    page_table
+                        .write(data, segment_address)
+                        .map_err(|_| "Failed to copy ELF segment!")?;
                 }
             }
-        });
+            Ok(())
+        })
     }
 }
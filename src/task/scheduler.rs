@@ -2,15 +2,20 @@ use core::sync::atomic::{AtomicBool, Ordering};
 
 use alloc::collections::vec_deque::VecDeque;
 use alloc::collections::BTreeMap;
-use alloc::sync::Arc;
+use alloc::sync::{Arc, Weak};
 use spin::{Lazy, Mutex};
+use x86_64::registers::model_specific::{FsBase, KernelGsBase};
 use x86_64::VirtAddr;
 
+use super::alarm;
+use super::signal;
 use super::context::Context;
-use super::thread::{ThreadState, WeakSharedThread};
+use super::process::{DEAD_PROCESSES, KERNEL_PROCESS};
+use super::thread::{SharedThread, ThreadState, WeakSharedThread};
 use super::Thread;
-use crate::arch::apic::get_lapic_id;
-use crate::arch::smp::CPUS;
+use crate::arch::idle;
+use crate::arch::smp::{current_lapic_id, set_current_thread_ptr, CPUS};
+use crate::memory::{is_heap_locked, FRAME_ALLOCATOR};
 
 pub static SCHEDULER_INIT: AtomicBool = AtomicBool::new(false);
 pub static SCHEDULER: Lazy<Mutex<Scheduler>> = Lazy::new(|| Mutex::new(Scheduler::new()));
@@ -22,25 +27,42 @@ pub fn init() {
 pub struct Scheduler {
     current_threads: BTreeMap<u32, WeakSharedThread>,
     ready_threads: VecDeque<WeakSharedThread>,
+    /// The thread each CPU falls back to when no thread is ready and its own can't go on.
+    idle_threads: BTreeMap<u32, SharedThread>,
 }
 
 impl Scheduler {
     pub fn new() -> Self {
-        let current_threads = CPUS
+        let current_threads: BTreeMap<u32, WeakSharedThread> = CPUS
             .read()
             .iter_id()
             .map(|lapic_id| (*lapic_id, Thread::get_init_thread()))
             .collect();
 
+        let mut cpus = CPUS.write();
+        for (lapic_id, thread) in current_threads.iter() {
+            cpus.get_mut(*lapic_id)
+                .per_cpu
+                .set_current_thread(thread.as_ptr() as *const ());
+        }
+        drop(cpus);
+
+        let idle_threads = current_threads
+            .keys()
+            .map(|lapic_id| (*lapic_id, Thread::new_idle_thread()))
+            .collect();
+
         Self {
             current_threads,
             ready_threads: VecDeque::new(),
+            idle_threads,
         }
     }
 
     #[inline]
     pub fn add(&mut self, thread: WeakSharedThread) {
         self.ready_threads.push_front(thread);
+        idle::wake_idle_cpus();
     }
 
     #[inline]
@@ -51,37 +73,174 @@ impl Scheduler {
         });
     }
 
+    /// Makes a waiting thread ready again.
+    /// A thread which is still running on a CPU is requeued by `schedule` itself.
+    pub fn wake(&mut self, thread: WeakSharedThread) {
+        let Some(shared) = thread.upgrade() else {
+            return;
+        };
+        let mut shared = shared.write();
+        if shared.state != ThreadState::Waiting {
+            return;
+        }
+        shared.state = ThreadState::Ready;
+
+        let running = self
+            .current_threads
+            .values()
+            .any(|current| current.ptr_eq(&thread));
+        if !running {
+            self.ready_threads.push_back(thread.clone());
+            idle::wake_idle_cpus();
+        }
+    }
+
+    fn is_idle_thread(&self, thread: &SharedThread) -> bool {
+        self.idle_threads
+            .values()
+            .any(|idle_thread| Arc::ptr_eq(idle_thread, thread))
+    }
+
     #[inline]
     pub fn current_thread(&self) -> WeakSharedThread {
-        let lapic_id = get_lapic_id();
-        self.current_threads[&lapic_id].clone()
+        super::current_thread()
+    }
+
+    /// Frees the killed processes which have no thread running on any CPU.
+    fn free_dead_processes(&self) {
+        // Freeing the page table needs the frame allocator and the process goes back to the heap,
+        // don't wait for either in the timer interrupt.
+        if FRAME_ALLOCATOR.is_locked() || is_heap_locked() {
+            return;
+        }
+
+        DEAD_PROCESSES.lock().retain(|process| {
+            let process = Arc::downgrade(process);
+            self.current_threads.values().any(|thread| {
+                thread
+                    .upgrade()
+                    .is_some_and(|thread| thread.read().process.ptr_eq(&process))
+            })
+        });
+    }
+
+    /// Frees the kernel threads which terminated, once they aren't running on any CPU.
+    fn free_terminated_threads(&self) {
+        // Their kernel stacks go back to the heap, which the interrupted code may hold.
+        if is_heap_locked() {
+            return;
+        }
+        let Some(mut kernel_process) = KERNEL_PROCESS.try_write() else {
+            return;
+        };
+        kernel_process.threads.retain(|thread| {
+            let running = self
+                .current_threads
+                .values()
+                .any(|current| current.as_ptr() == Arc::as_ptr(thread));
+            running
+                || thread
+                    .try_read()
+                    .map_or(true, |thread| thread.state != ThreadState::Terminated)
+        });
+    }
+
+    /// Pops the next thread which can run, dropping the terminated ones
+    /// and those whose process is gone.
+    /// A thread locked by the interrupted code is left for a later tick.
+    fn pop_ready_thread(&mut self) -> Option<(WeakSharedThread, SharedThread)> {
+        for _ in 0..self.ready_threads.len() {
+            let thread = self.ready_threads.pop_front()?;
+            let Some(shared) = thread.upgrade() else {
+                continue;
+            };
+            let Some(guard) = shared.try_read() else {
+                self.ready_threads.push_back(thread);
+                continue;
+            };
+            let runnable =
+                guard.state != ThreadState::Terminated && guard.process.strong_count() != 0;
+            drop(guard);
+            if runnable {
+                return Some((thread, shared));
+            }
+        }
+        None
     }
 
     pub fn schedule(&mut self, context: VirtAddr) -> VirtAddr {
-        let lapic_id = get_lapic_id();
-
-        let last_thread = self.current_threads[&lapic_id]
-            .upgrade()
-            .and_then(|thread| {
-                thread.write().context = Context::from_address(context);
-                Some(self.current_threads[&lapic_id].clone())
-            });
-
-        if let Some(next_thread) = self.ready_threads.pop_front() {
-            self.current_threads.insert(lapic_id, next_thread);
-            if let Some(last_thread) = last_thread {
-                match last_thread.upgrade().unwrap().read().state {
-                    ThreadState::Blocked | ThreadState::Terminated | ThreadState::Waiting => {}
-                    _ => self.ready_threads.push_back(last_thread),
+        let lapic_id = current_lapic_id();
+
+        self.free_dead_processes();
+        self.free_terminated_threads();
+        alarm::fire_alarms(self);
+        signal::deliver_pending_signals(self);
+
+        // Both threads are held until the switch is done, so neither is freed in the middle of it.
+        let last_thread = self.current_threads.get(&lapic_id).and_then(Weak::upgrade);
+        let last_state = match &last_thread {
+            Some(last_thread) => {
+                // The interrupted code holds the lock of its own thread,
+                // it keeps running until the next tick.
+                let Some(mut thread) = last_thread.try_write() else {
+                    return context;
+                };
+                thread.context = Context::from_address(context);
+                thread.fs_base = FsBase::read().as_u64();
+                thread.gs_base = KernelGsBase::read().as_u64();
+                // A soft-float kernel target like `x86_64-unknown-none` never touches the registers,
+                // so they still hold the values of the interrupted thread.
+                thread.fpu_context.save();
+                // A user thread can't go on once the page table of its process is freed.
+                if thread.process.strong_count() == 0 {
+                    thread.state = ThreadState::Terminated;
                 }
+                Some(thread.state)
+            }
+            None => None,
+        };
+
+        let (next_handle, next_thread) = match self.pop_ready_thread() {
+            Some(next) => next,
+            None if last_state.is_some_and(|state| state.is_active()) => return context,
+            // The last thread is waiting or gone, the CPU idles until a thread is ready.
+            None => match self.idle_threads.get(&lapic_id) {
+                Some(idle_thread) => (Arc::downgrade(idle_thread), idle_thread.clone()),
+                None => {
+                    log::error!("CPU {} has no idle thread to fall back to", lapic_id);
+                    return context;
+                }
+            },
+        };
+
+        let switched = last_thread
+            .as_ref()
+            .is_none_or(|last_thread| !Arc::ptr_eq(last_thread, &next_thread));
+        self.current_threads.insert(lapic_id, next_handle.clone());
+        if let (Some(last_thread), Some(last_state)) = (&last_thread, last_state) {
+            match last_state {
+                ThreadState::Blocked | ThreadState::Terminated | ThreadState::Waiting => {}
+                _ if self.is_idle_thread(last_thread) => {}
+                _ => self.ready_threads.push_back(Arc::downgrade(last_thread)),
             }
         }
 
-        let next_thread = self.current_threads[&lapic_id].upgrade().unwrap();
+        set_current_thread_ptr(next_handle.as_ptr() as *const ());
         let next_thread = next_thread.read();
 
         let kernel_address = next_thread.kernel_stack.end_address();
-        CPUS.write().get_mut(lapic_id).set_ring0_rsp(kernel_address);
+        let mut cpus = CPUS.write();
+        let cpu_info = cpus.get_mut(lapic_id);
+        cpu_info.set_ring0_rsp(kernel_address);
+        if switched {
+            cpu_info.counters.count_context_switch();
+            // A tick which switches away from the idle loop doesn't return to it.
+            cpu_info.counters.set_idle(false);
+        }
+        drop(cpus);
+        FsBase::write(VirtAddr::new_truncate(next_thread.fs_base));
+        KernelGsBase::write(VirtAddr::new_truncate(next_thread.gs_base));
+        next_thread.fpu_context.restore();
 
         next_thread.context.address()
     }
@@ -1,7 +1,67 @@
+use alloc::sync::Arc;
 use alloc::{vec::Vec, vec};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
 
+use super::process::{Process, WeakSharedProcess};
+use super::scheduler::Scheduler;
+use super::thread::ThreadState;
 use crate::data::bitmap::Bitmap;
 
+/// How many signals `send_signal` can hold until the next tick delivers them.
+const PENDING_SIGNALS_SIZE: usize = 64;
+
+/// The signals sent by `send_signal`, a fixed array so an interrupt handler never allocates.
+/// Always locked with the interrupts disabled, so a handler never waits for the code it interrupted.
+static PENDING_SIGNALS: Mutex<[Option<PendingSignal>; PENDING_SIGNALS_SIZE]> =
+    Mutex::new([const { None }; PENDING_SIGNALS_SIZE]);
+
+struct PendingSignal {
+    process: WeakSharedProcess,
+    signal: Signal,
+}
+
+/// The signal types the framework sends itself, the kernel can use the others freely.
+pub mod well_known {
+    /// Sent to the father when a process exits, `data[0]` is the id of the child.
+    pub const CHILD_EXIT: usize = 1;
+    /// Asks the process to terminate.
+    pub const KILL: usize = 2;
+    /// Sent when a timer of the process expires.
+    pub const ALARM: usize = 3;
+
+    /// The first signal type not used by the framework.
+    pub const FIRST_CUSTOM: usize = 4;
+    /// How many signal types are left for the kernel.
+    pub const CUSTOM_COUNT: usize = 60;
+}
+
+/// The names of the well known signal types.
+pub const WELL_KNOWN_SIGNALS: [(&str, usize); 3] = [
+    ("CHILD_EXIT", well_known::CHILD_EXIT),
+    ("KILL", well_known::KILL),
+    ("ALARM", well_known::ALARM),
+];
+
+/// The number of signal types of a process, type 0 is never used.
+pub const SIGNAL_TYPE_NUM: usize = well_known::FIRST_CUSTOM + well_known::CUSTOM_COUNT;
+
+/// Returns the name of a well known signal type.
+pub fn signal_name(signal_type: usize) -> Option<&'static str> {
+    WELL_KNOWN_SIGNALS
+        .iter()
+        .find(|(_, ty)| *ty == signal_type)
+        .map(|(name, _)| *name)
+}
+
+/// Returns the signal type with the well known name.
+pub fn signal_type(name: &str) -> Option<usize> {
+    WELL_KNOWN_SIGNALS
+        .iter()
+        .find(|(signal_name, _)| *signal_name == name)
+        .map(|(_, ty)| *ty)
+}
+
 /// the signal structure.
 #[derive(Debug, Clone, Copy)]
 pub struct Signal {
@@ -15,14 +75,17 @@ pub struct SignalManager {
     signal_bitmap: Bitmap, 
     signals: Vec<Signal>,
     waiting_for: usize,
+    signal_type_num: usize,
 }
 
 impl SignalManager {
+    /// Creates a manager for the signal types below `signal_type_num`, usually `SIGNAL_TYPE_NUM`.
     pub fn new(signal_type_num: usize) -> Self {
         Self {
-            signal_bitmap: Bitmap::new(vec![0;signal_type_num].leak()),
+            signal_bitmap: Bitmap::new(vec![0;signal_type_num.div_ceil(8)].leak()),
             signals: Vec::new(),
             waiting_for: 0,
+            signal_type_num,
         }
     }
 
@@ -34,6 +97,7 @@ impl SignalManager {
     /// Registers a new signal and wakes up the process if it is waiting for the signal.
     pub fn register_signal(&mut self, signal_type: usize, signal: Signal) -> bool {
         assert_ne!(signal_type, 0);
+        assert!(signal_type < self.signal_type_num, "Invalid signal type {}", signal_type);
         self.signal_bitmap.set(signal_type, true);
         self.signals.push(signal);
 
@@ -65,16 +129,95 @@ impl SignalManager {
         }
     }
 
-    /// Deletes a signal of the specified type. Nothing happens if the signal type is not registered.
+    /// Removes and returns the oldest signal of the specified type.
+    pub fn take_signal(&mut self, signal_type: usize) -> Option<Signal> {
+        let idx = self.signals.iter().position(|signal| signal.ty == signal_type)?;
+        let signal = self.signals.remove(idx);
+        if !self.signals.iter().any(|signal| signal.ty == signal_type) {
+            self.signal_bitmap.set(signal_type, false);
+        }
+        Some(signal)
+    }
+
+    /// Deletes all signals of the specified type. Nothing happens if the signal type is not registered.
     pub fn delete_signal(&mut self, signal_type: usize) {
         if self.signal_bitmap.get(signal_type) {
             self.signal_bitmap.set(signal_type, false);
-            for idx in 0..self.signals.len() {
-                if self.signals[idx].ty == signal_type {
-                    self.signals.remove(idx);
-                }
+            self.signals.retain(|signal| signal.ty != signal_type);
+        }
+    }
+}
+
+/// Sends the signal to the process, it may be called from an interrupt handler.
+/// Nothing of the process is locked here, the scheduler registers the signal and wakes the process
+/// on a later tick, once the process isn't locked.
+/// Returns false if the signal type is invalid or too many signals are pending.
+pub fn send_signal(process: WeakSharedProcess, signal: Signal) -> bool {
+    if signal.ty == 0 || signal.ty >= SIGNAL_TYPE_NUM {
+        return false;
+    }
+    interrupts::without_interrupts(|| {
+        let mut pending_signals = PENDING_SIGNALS.lock();
+        match pending_signals.iter_mut().find(|pending| pending.is_none()) {
+            Some(slot) => {
+                *slot = Some(PendingSignal { process, signal });
+                true
             }
+            None => false,
+        }
+    })
+}
+
+/// Registers the signal and wakes the threads of the process if it is waiting for it.
+pub(super) fn deliver_signal(process: &mut Process, signal: Signal, scheduler: &mut Scheduler) {
+    if process.signal_manager.register_signal(signal.ty, signal) {
+        for thread in process.threads.iter() {
+            scheduler.wake(Arc::downgrade(thread));
         }
     }
 }
 
+/// Delivers the signals sent by `send_signal`, called by the scheduler on each tick.
+/// A signal to a process the interrupted code holds the lock of waits for a later tick.
+pub(super) fn deliver_pending_signals(scheduler: &mut Scheduler) {
+    // Another CPU is already on it.
+    let Some(mut pending_signals) = PENDING_SIGNALS.try_lock() else {
+        return;
+    };
+    for slot in pending_signals.iter_mut() {
+        let Some(pending) = slot else {
+            continue;
+        };
+        let Some(process) = pending.process.upgrade() else {
+            *slot = None;
+            continue;
+        };
+        let Some(mut process) = process.try_write() else {
+            continue;
+        };
+        deliver_signal(&mut process, pending.signal, scheduler);
+        *slot = None;
+    }
+}
+
+/// Blocks the current thread until its process gets a signal of the type, then takes it.
+pub fn wait_for_signal(signal_type: usize) -> Signal {
+    let thread = super::current_thread().upgrade().unwrap();
+    let process = thread.read().process.upgrade().unwrap();
+    loop {
+        // The process lock is held until the thread waits, so that the signal can't slip in between.
+        let signal = x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut process = process.write();
+            let signal = process.signal_manager.take_signal(signal_type);
+            if signal.is_none() {
+                process.signal_manager.register_wait_for(signal_type);
+                thread.write().state = ThreadState::Waiting;
+            }
+            signal
+        });
+        match signal {
+            Some(signal) => return signal,
+            None => super::schedule(),
+        }
+    }
+}
@@ -1,18 +1,36 @@
 use alloc::boxed::Box;
+use alloc::vec::Vec;
+use x86_64::structures::paging::mapper::MapToError;
 use x86_64::{structures::paging::PageTableFlags, VirtAddr};
 
 use crate::memory::{GeneralPageTable, MemoryManager};
 
 const KERNEL_STACK_SIZE: usize = 16 * 1024;
-const USER_STACK_END: usize = 0x0000_7fff_feff_f000;
-const USER_STACK_SIZE: usize = 64 * 1024;
+pub(crate) const USER_STACK_END: usize = 0x0000_7fff_feff_f000;
+pub(crate) const USER_STACK_SIZE: usize = 64 * 1024;
+
+/// The auxiliary vector entry types put on the initial stack of a user process.
+pub mod auxv {
+    pub const AT_NULL: u64 = 0;
+    pub const AT_PHDR: u64 = 3;
+    pub const AT_PHENT: u64 = 4;
+    pub const AT_PHNUM: u64 = 5;
+    pub const AT_PAGESZ: u64 = 6;
+    pub const AT_ENTRY: u64 = 9;
+    pub const AT_RANDOM: u64 = 25;
+}
 
 /// You don't have to use this struct.
 pub struct KernelStack(Box<[u8]>);
 
 impl KernelStack {
     pub fn new() -> Self {
-        Self(Box::from(alloc::vec![0; KERNEL_STACK_SIZE]))
+        Self::with_size(KERNEL_STACK_SIZE)
+    }
+
+    /// Creates a kernel stack of at least `size` bytes, rounded up to 16 bytes.
+    pub fn with_size(size: usize) -> Self {
+        Self(Box::from(alloc::vec![0; size.next_multiple_of(16).max(16)]))
     }
 
     pub fn end_address(&self) -> VirtAddr {
@@ -32,7 +50,8 @@ pub struct UserStack {
 }
 
 impl UserStack {
-    pub fn new(page_table: &mut GeneralPageTable) -> Self {
+    /// Maps the stack of a user thread, fails if there isn't enough memory for it.
+    pub fn new(page_table: &mut GeneralPageTable) -> Result<Self, &'static str> {
         let user_stack_end = VirtAddr::new(USER_STACK_END as u64);
         let user_stack_start = user_stack_end - USER_STACK_SIZE as u64;
 
@@ -42,11 +61,85 @@ impl UserStack {
             | PageTableFlags::NO_EXECUTE;
 
         <MemoryManager>::alloc_range(user_stack_start, USER_STACK_SIZE as u64, flags, page_table)
-            .unwrap();
+            .map_err(|err| match err {
+                MapToError::FrameAllocationFailed => "Out of memory for the user stack",
+                _ => "The user stack is already mapped",
+            })?;
 
-        Self {
+        Ok(Self {
             start_address: user_stack_start,
             end_address: user_stack_end,
+        })
+    }
+
+    /// Unmaps the stack and frees its frames, for a thread which couldn't be created.
+    pub fn free(self, page_table: &mut GeneralPageTable) {
+        let size = self.end_address - self.start_address;
+        <MemoryManager>::dealloc_range(self.start_address, size, page_table);
+    }
+
+    /// Writes the System V initial process stack and returns the stack pointer for `_start`.
+    ///
+    /// From the stack pointer up: argc, the argv pointers, a null, the envp pointers, a null,
+    /// the auxv pairs with an `AT_RANDOM` one appended, `AT_NULL`, then the 16 random bytes and the strings.
+    /// The stack pointer is 16-byte aligned and points at argc, as `_start` expects.
+    pub fn push_start_frame(
+        &self,
+        page_table: &mut GeneralPageTable,
+        argv: &[&str],
+        envp: &[&str],
+        aux: &[(u64, u64)],
+    ) -> Result<VirtAddr, &'static str> {
+        let end = self.end_address.as_u64();
+        let strings_size: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+        let strings_start = end - (strings_size + 16) as u64;
+        let random_address = strings_start;
+
+        let mut strings = Vec::with_capacity(strings_size + 16);
+        let seed = unsafe { core::arch::x86_64::_rdtsc() };
+        strings.extend_from_slice(&seed.to_le_bytes());
+        strings.extend_from_slice(
+            &seed
+                .rotate_left(32)
+                .wrapping_mul(0x9e37_79b9_7f4a_7c15)
+                .to_le_bytes(),
+        );
+
+        let string_pointers = |strs: &[&str], strings: &mut Vec<u8>| {
+            strs.iter()
+                .map(|s| {
+                    let address = strings_start + strings.len() as u64;
+                    strings.extend_from_slice(s.as_bytes());
+                    strings.push(0);
+                    address
+                })
+                .collect::<Vec<_>>()
+        };
+        let argv_pointers = string_pointers(argv, &mut strings);
+        let envp_pointers = string_pointers(envp, &mut strings);
+
+        let mut words = Vec::new();
+        words.push(argv.len() as u64);
+        words.extend(argv_pointers);
+        words.push(0);
+        words.extend(envp_pointers);
+        words.push(0);
+        for &(key, value) in aux.iter().chain(&[(auxv::AT_RANDOM, random_address)]) {
+            words.extend([key, value]);
         }
+        words.extend([auxv::AT_NULL, 0]);
+
+        let stack_pointer = ((strings_start & !0xf) - words.len() as u64 * 8) & !0xf;
+        if stack_pointer < self.start_address.as_u64() {
+            return Err("The arguments don't fit on the user stack");
+        }
+
+        let words: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        page_table
+            .write(&words, VirtAddr::new(stack_pointer))
+            .and_then(|_| page_table.write(&strings, VirtAddr::new(strings_start)))
+            .map_err(|_| "Failed to write the initial user stack")?;
+
+        Ok(VirtAddr::new(stack_pointer))
     }
 }
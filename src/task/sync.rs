@@ -0,0 +1,220 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::thread::WeakSharedThread;
+use super::{block_current, current_thread, unblock};
+
+/// A reader-writer lock whose contended `read` and `write` block the thread instead of spinning.
+///
+/// The waiters are served in order: a writer gets the lock once the readers before it are done,
+/// and a new reader queues behind a waiting writer, so writers don't starve.
+/// When a writer releases it, the next writer or every reader queued before it get the lock.
+/// It isn't reentrant, a reader which reads again while a writer waits deadlocks.
+///
+/// Before the scheduler runs there is no thread to block, the waiters spin instead.
+pub struct BlockingRwLock<T: ?Sized> {
+    state: Mutex<LockState>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for BlockingRwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for BlockingRwLock<T> {}
+
+struct LockState {
+    readers: usize,
+    writer: bool,
+    waiters: VecDeque<Waiter>,
+}
+
+struct Waiter {
+    thread: WeakSharedThread,
+    write: bool,
+    /// Set by the thread which released the lock, the lock is then already held for the waiter.
+    granted: Arc<AtomicBool>,
+}
+
+pub struct BlockingRwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a BlockingRwLock<T>,
+}
+
+pub struct BlockingRwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a BlockingRwLock<T>,
+}
+
+impl<T> BlockingRwLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            state: Mutex::new(LockState {
+                readers: 0,
+                writer: false,
+                waiters: VecDeque::new(),
+            }),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> BlockingRwLock<T> {
+    /// Locks for reading, blocks while a writer holds or waits for the lock.
+    pub fn read(&self) -> BlockingRwLockReadGuard<'_, T> {
+        self.lock(false);
+        BlockingRwLockReadGuard { lock: self }
+    }
+
+    /// Locks for writing, blocks while any reader or writer holds or waits for the lock.
+    pub fn write(&self) -> BlockingRwLockWriteGuard<'_, T> {
+        self.lock(true);
+        BlockingRwLockWriteGuard { lock: self }
+    }
+
+    /// Locks for reading if it can be done without blocking.
+    pub fn try_read(&self) -> Option<BlockingRwLockReadGuard<'_, T>> {
+        self.with_state(|state| {
+            let free = !state.writer && state.waiters.is_empty();
+            if free {
+                state.readers += 1;
+            }
+            free
+        })
+        .then(|| BlockingRwLockReadGuard { lock: self })
+    }
+
+    /// Locks for writing if it can be done without blocking.
+    pub fn try_write(&self) -> Option<BlockingRwLockWriteGuard<'_, T>> {
+        self.with_state(|state| {
+            let free = !state.writer && state.readers == 0 && state.waiters.is_empty();
+            if free {
+                state.writer = true;
+            }
+            free
+        })
+        .then(|| BlockingRwLockWriteGuard { lock: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// The state is only locked with the interrupts disabled, so it isn't held across a preemption.
+    fn with_state<R>(&self, f: impl FnOnce(&mut LockState) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.state.lock()))
+    }
+
+    fn lock(&self, write: bool) {
+        let granted = self.with_state(|state| {
+            let free = if write {
+                !state.writer && state.readers == 0 && state.waiters.is_empty()
+            } else {
+                !state.writer && state.waiters.is_empty()
+            };
+            if free {
+                if write {
+                    state.writer = true;
+                } else {
+                    state.readers += 1;
+                }
+                return None;
+            }
+
+            let granted = Arc::new(AtomicBool::new(false));
+            state.waiters.push_back(Waiter {
+                thread: current_thread(),
+                write,
+                granted: granted.clone(),
+            });
+            Some(granted)
+        });
+
+        if let Some(granted) = granted {
+            while !granted.load(Ordering::Acquire) {
+                block_current(|| !granted.load(Ordering::Acquire));
+            }
+        }
+    }
+
+    fn unlock(&self, write: bool) {
+        self.with_state(|state| {
+            if write {
+                state.writer = false;
+            } else {
+                state.readers -= 1;
+            }
+            Self::grant(state);
+        });
+    }
+
+    /// Hands the lock to the next writer, or to the readers at the front of the queue.
+    fn grant(state: &mut LockState) {
+        if state.writer {
+            return;
+        }
+        match state.waiters.front() {
+            Some(waiter) if waiter.write => {
+                if state.readers == 0 {
+                    state.writer = true;
+                    Self::wake(state.waiters.pop_front().unwrap());
+                }
+            }
+            _ => {
+                while state.waiters.front().is_some_and(|waiter| !waiter.write) {
+                    state.readers += 1;
+                    Self::wake(state.waiters.pop_front().unwrap());
+                }
+            }
+        }
+    }
+
+    fn wake(waiter: Waiter) {
+        waiter.granted.store(true, Ordering::Release);
+        unblock(&waiter.thread);
+    }
+}
+
+impl<T: Default> Default for BlockingRwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized> Deref for BlockingRwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for BlockingRwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock(false);
+    }
+}
+
+impl<T: ?Sized> Deref for BlockingRwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for BlockingRwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for BlockingRwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock(true);
+    }
+}
@@ -1,8 +1,11 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use alloc::sync::Weak;
 use core::fmt::Debug;
 use core::sync::atomic::{AtomicU64, Ordering};
 use spin::RwLock;
+use x86_64::instructions::interrupts;
 
 use super::context::Context;
 use super::process::WeakSharedProcess;
@@ -10,22 +13,42 @@ use super::process::KERNEL_PROCESS;
 use super::scheduler::SCHEDULER;
 use super::stack::{KernelStack, UserStack};
 use crate::arch::gdt::Selectors;
+use crate::arch::idle;
+use crate::arch::smp::runs_on_other_cpu;
 use crate::drivers::fpu::FpState;
-use crate::memory::KERNEL_PAGE_TABLE;
+use crate::memory::{GeneralPageTable, KERNEL_PAGE_TABLE};
+use x86_64::VirtAddr;
 
 pub(super) type SharedThread = Arc<RwLock<Thread>>;
-pub(super) type WeakSharedThread = Weak<RwLock<Thread>>;
+pub type WeakSharedThread = Weak<RwLock<Thread>>;
+
+/// Every thread alive, by id, for `find_thread`.
+static THREADS: RwLock<BTreeMap<ThreadId, WeakSharedThread>> = RwLock::new(BTreeMap::new());
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ThreadId(pub u64);
 
 impl ThreadId {
+    /// Returns a new id, the ids are never reused.
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
-        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+        ThreadId(next_id(&NEXT_ID))
     }
 }
 
+/// Takes the next id of a counter, the ids are never reused so a stale id can't name a new object.
+/// 64 bits last for centuries even at an id per nanosecond, so running out panics instead of wrapping.
+pub(super) fn next_id(counter: &AtomicU64) -> u64 {
+    counter
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |id| id.checked_add(1))
+        .expect("Ran out of ids!")
+}
+
+/// Returns the thread with the id, if it is still alive. The lookup takes O(log n).
+pub fn find_thread(id: ThreadId) -> Option<WeakSharedThread> {
+    interrupts::without_interrupts(|| THREADS.read().get(&id).cloned())
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ThreadState {
     Running,
@@ -52,38 +75,80 @@ pub struct Thread {
     pub context: Context,
     pub process: WeakSharedProcess,
     pub fpu_context: FpState,
+    /// The FS base of the thread, saved and restored by the scheduler for the TLS of user programs.
+    pub fs_base: u64,
+    /// The user GS base of the thread, kept in the kernel GS base while it runs in the kernel.
+    pub gs_base: u64,
 }
 
 impl Thread {
     /// Creates a new thread.
     /// Don't call this function directly, use `Thread::new_init_thread`,`Thread::new_user_thread` or `Thread::new_kernel_thread` instead.
     pub fn new(process: WeakSharedProcess) -> Self {
+        Self::with_kernel_stack(process, KernelStack::new())
+    }
+
+    fn with_kernel_stack(process: WeakSharedProcess, kernel_stack: KernelStack) -> Self {
         let thread = Thread {
             id: ThreadId::new(),
             state: ThreadState::Ready,
             context: Context::default(),
-            kernel_stack: KernelStack::new(),
+            kernel_stack,
             process,
-            fpu_context: FpState::default(),
+            fpu_context: FpState::new(),
+            fs_base: 0,
+            gs_base: 0,
         };
 
         thread
     }
 
+    /// Shares the thread and registers it for `find_thread`.
+    fn into_shared(self) -> SharedThread {
+        let id = self.id;
+        let thread = Arc::new(RwLock::new(self));
+        interrupts::without_interrupts(|| THREADS.write().insert(id, Arc::downgrade(&thread)));
+        thread
+    }
+
     /// Creates a new initial thread.
     pub fn get_init_thread() -> WeakSharedThread {
         let thread = Self::new(Arc::downgrade(&KERNEL_PROCESS));
-        let thread = Arc::new(RwLock::new(thread));
+        let thread = thread.into_shared();
         KERNEL_PROCESS.write().threads.push_back(thread.clone());
         //SCHEDULER.lock().add(Arc::downgrade(&thread));
         Arc::downgrade(&thread)
     }
 
 
-    /// Creates a new kernel thread.
-    pub fn new_kernel_thread(function: fn()) {
+    /// Creates the idle thread of a CPU, which runs the idle loop whenever nothing else can run.
+    /// It is owned by the scheduler alone, it is never queued nor terminated.
+    pub(super) fn new_idle_thread() -> SharedThread {
         let mut thread = Self::new(Arc::downgrade(&KERNEL_PROCESS));
 
+        thread.context.init(
+            idle::idle_loop as *const () as usize,
+            thread.kernel_stack.end_address() - 8u64,
+            KERNEL_PAGE_TABLE.lock().physical_address,
+            Selectors::get_kernel_segments(),
+        );
+
+        thread.into_shared()
+    }
+
+    /// Creates a new kernel thread and returns a handle to it.
+    pub fn new_kernel_thread(function: fn()) -> WeakSharedThread {
+        Self::new_kernel_thread_with_stack(function, KernelStack::new())
+    }
+
+    /// Creates a new kernel thread with a stack of at least `stack_size` bytes, for stack-hungry work.
+    pub fn new_kernel_thread_sized(function: fn(), stack_size: usize) -> WeakSharedThread {
+        Self::new_kernel_thread_with_stack(function, KernelStack::with_size(stack_size))
+    }
+
+    fn new_kernel_thread_with_stack(function: fn(), kernel_stack: KernelStack) -> WeakSharedThread {
+        let mut thread = Self::with_kernel_stack(Arc::downgrade(&KERNEL_PROCESS), kernel_stack);
+
         thread.context.init(
             function as usize,
             thread.kernel_stack.end_address(),
@@ -93,33 +158,132 @@ impl Thread {
 
         
 
-        let thread = Arc::new(RwLock::new(thread));
-        
-        
-        SCHEDULER.lock().add(Arc::downgrade(&thread));
+        let thread = thread.into_shared();
+        let handle = Arc::downgrade(&thread);
+
+        interrupts::without_interrupts(|| SCHEDULER.lock().add(handle.clone()));
         KERNEL_PROCESS.write().threads.push_back(thread);
-        
+        handle
     }
 
-    /// Creates a new user thread.
-    pub fn new_user_thread(process: WeakSharedProcess, entry_point: usize) {
-        let mut thread = Self::new(process.clone());
+    /// Creates a new kernel thread running the closure.
+    /// The closure is freed once it returns, the thread then terminates and is freed by the scheduler.
+    pub fn spawn_kernel<F: FnOnce() + Send + 'static>(f: F) -> WeakSharedThread {
+        let closure: Box<Box<dyn FnOnce() + Send>> = Box::new(Box::new(f));
+        let mut thread = Self::new(Arc::downgrade(&KERNEL_PROCESS));
+
+        thread.context.init(
+            kernel_thread_entry as *const () as usize,
+            thread.kernel_stack.end_address() - 8u64,
+            KERNEL_PAGE_TABLE.lock().physical_address,
+            Selectors::get_kernel_segments(),
+        );
+        thread.context.rdi = Box::into_raw(closure) as usize;
+
+        let thread = thread.into_shared();
+        let handle = Arc::downgrade(&thread);
+
+        interrupts::without_interrupts(|| SCHEDULER.lock().add(handle.clone()));
+        KERNEL_PROCESS.write().threads.push_back(thread);
+        handle
+    }
+
+    /// Creates a new user thread and returns a handle to it.
+    /// Fails if the entry point isn't on an executable user page of the process.
+    pub fn new_user_thread(
+        process: WeakSharedProcess,
+        entry_point: usize,
+    ) -> Result<WeakSharedThread, &'static str> {
+        Self::new_user_thread_with_stack(process, entry_point, |user_stack, _| {
+            Ok(user_stack.end_address)
+        })
+    }
+
+    /// Creates the first thread of a user process, starting at `_start` with argc, argv, envp and auxv on its stack.
+    pub(super) fn new_main_thread(
+        process: WeakSharedProcess,
+        entry_point: usize,
+        argv: &[&str],
+        aux: &[(u64, u64)],
+    ) -> Result<WeakSharedThread, &'static str> {
+        Self::new_user_thread_with_stack(process, entry_point, |user_stack, page_table| {
+            user_stack.push_start_frame(page_table, argv, &[], aux)
+        })
+    }
+
+    /// Creates a new user thread, `init_stack` fills the new stack and returns the initial stack pointer.
+    fn new_user_thread_with_stack(
+        process: WeakSharedProcess,
+        entry_point: usize,
+        init_stack: impl FnOnce(&UserStack, &mut GeneralPageTable) -> Result<VirtAddr, &'static str>,
+    ) -> Result<WeakSharedThread, &'static str> {
+        let process_ref = process.upgrade().ok_or("The process no longer exists")?;
+        let mut process_guard = process_ref.write();
+
+        let entry_address = VirtAddr::try_new(entry_point as u64)
+            .map_err(|_| "The entry point is not canonical")?;
+        if !process_guard.page_table.is_user_executable(entry_address) {
+            return Err("The entry point is not on an executable user page");
+        }
+
+        if !process_guard.threads.is_empty() {
+            // The zero pages are resolved with only the TLB of this CPU flushed.
+            let running_elsewhere = process_guard
+                .threads
+                .iter()
+                .any(|thread| runs_on_other_cpu(Arc::as_ptr(thread) as *const ()));
+            if running_elsewhere {
+                return Err("The process is running on another CPU");
+            }
+            process_guard.disable_cow()?;
+        }
+
+        let mut thread = Self::new(process);
         //log::info!("New : {}", thread.id.0);
-        let process = process.upgrade().unwrap();
-        let mut process = process.write();
-        let user_stack = UserStack::new(&mut process.page_table);
+        let process = &mut *process_guard;
+        let user_stack = UserStack::new(&mut process.page_table)?;
+        let stack_pointer = init_stack(&user_stack, &mut process.page_table).and_then(|pointer| {
+            if pointer.is_aligned(16u64) {
+                Ok(pointer)
+            } else {
+                Err("The user stack is not 16-byte aligned")
+            }
+        });
+        let stack_pointer = match stack_pointer {
+            Ok(stack_pointer) => stack_pointer,
+            Err(err) => {
+                user_stack.free(&mut process.page_table);
+                return Err(err);
+            }
+        };
 
         thread.context.init(
             entry_point,
-            user_stack.end_address,
+            stack_pointer,
             process.page_table.physical_address,
             Selectors::get_user_segments(),
         );
 
-        let thread = Arc::new(RwLock::new(thread));
-        SCHEDULER.lock().add(Arc::downgrade(&thread));
-        process.threads.push_back(thread.clone());
+        let thread = thread.into_shared();
+        let handle = Arc::downgrade(&thread);
+
+        interrupts::without_interrupts(|| SCHEDULER.lock().add(handle.clone()));
+        process.threads.push_back(thread);
+        Ok(handle)
     }
 }
 
+impl Drop for Thread {
+    fn drop(&mut self) {
+        // The scheduler may free it in the timer interrupt,
+        // so the lock is never held with the interrupts on.
+        interrupts::without_interrupts(|| THREADS.write().remove(&self.id));
+    }
+}
 
+/// The entry of the threads created by `Thread::spawn_kernel`, the stack is aligned as after a call.
+extern "C" fn kernel_thread_entry(closure: *mut Box<dyn FnOnce() + Send>) -> ! {
+    let closure = unsafe { Box::from_raw(closure) };
+    closure();
+    super::exit_current_thread()
+}
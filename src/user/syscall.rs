@@ -33,6 +33,8 @@ pub fn init() {
 extern "C" fn syscall_handler() {
     unsafe {
         asm!(
+            // A syscall always comes from ring 3, the user GS base goes back before `sysretq`.
+            "swapgs",
             "push rcx",
             "push r11",
             "push rbp",
@@ -54,6 +56,7 @@ extern "C" fn syscall_handler() {
             "pop rbp",
             "pop r11",
             "pop rcx",
+            "swapgs",
             "sysretq",
             syscall_handle_fn = sym syscall_handle_fn,
             options(noreturn)